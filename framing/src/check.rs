@@ -0,0 +1,307 @@
+
+//! Integrity checks carried inside a frame, after the payload. COBS and length prefixes only
+//! find where a frame ends: a byte corrupted on a UART can still leave a valid frame, and
+//! often a valid message. TCP already checks everything, so it doesn't need these.
+
+use crc::{ Crc, Digest, CRC_16_IBM_SDLC, CRC_32_ISO_HDLC };
+
+/// The longest trailer any check adds.
+pub const MAX_TRAILER: usize = 8;
+
+/// The bytes a check adds to the end of a payload.
+#[derive(Default)]
+pub struct Trailer {
+    bytes: [u8; MAX_TRAILER],
+    len: usize,
+}
+
+impl Trailer {
+    fn extend(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// A frame that passed its check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verified {
+    /// The length of the payload, without the trailer.
+    pub len: usize,
+    /// How many frames went missing before this one, going by the sequence number.
+    pub dropped: u32,
+}
+
+/// A frame that failed its check, and should be dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckFailed;
+
+/// Checks frames. An encoder and a decoder each have their own.
+pub trait Check {
+    /// Bytes added to each payload.
+    const TRAILER: usize;
+
+    /// Forget the sequence, for example when the other end has reconnected.
+    fn reset(&mut self) {}
+
+    /// Start encoding a payload, dropping any partial one.
+    fn start(&mut self) {}
+
+    /// Add a byte of a payload that's being encoded.
+    fn update(&mut self, byte: u8);
+
+    /// End a payload that's being encoded. Returns the bytes to send after it.
+    fn trailer(&mut self) -> Trailer;
+
+    /// Check a frame that's been decoded, trailer and all.
+    fn verify(&mut self, frame: &[u8]) -> Result<Verified, CheckFailed>;
+}
+
+/// No check at all. This is the default.
+#[derive(Default)]
+pub struct Unchecked;
+
+impl Check for Unchecked {
+    const TRAILER: usize = 0;
+
+    fn update(&mut self, _byte: u8) {}
+
+    fn trailer(&mut self) -> Trailer {
+        Trailer::default()
+    }
+
+    fn verify(&mut self, frame: &[u8]) -> Result<Verified, CheckFailed> {
+        Ok(Verified { len: frame.len(), dropped: 0 })
+    }
+}
+
+/// CRC-16/X.25, the HDLC frame check. Sent little endian.
+static CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
+
+/// CRC-32 as used by ethernet and zlib. Sent little endian.
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+pub struct Crc16 {
+    digest: Digest<'static, u16>,
+}
+
+impl Crc16 {
+    pub const fn new() -> Self {
+        Crc16 { digest: CRC16.digest() }
+    }
+}
+
+impl Check for Crc16 {
+    const TRAILER: usize = 2;
+
+    fn start(&mut self) {
+        self.digest = CRC16.digest();
+    }
+
+    fn update(&mut self, byte: u8) {
+        self.digest.update(&[byte]);
+    }
+
+    fn trailer(&mut self) -> Trailer {
+        let digest = core::mem::replace(&mut self.digest, CRC16.digest());
+        let mut trailer = Trailer::default();
+        trailer.extend(&digest.finalize().to_le_bytes());
+        trailer
+    }
+
+    fn verify(&mut self, frame: &[u8]) -> Result<Verified, CheckFailed> {
+        let len = frame.len().checked_sub(Self::TRAILER).ok_or(CheckFailed)?;
+        match CRC16.checksum(&frame[..len]).to_le_bytes() == frame[len..] {
+            true => Ok(Verified { len, dropped: 0 }),
+            false => Err(CheckFailed),
+        }
+    }
+}
+
+impl Default for Crc16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Crc32 {
+    digest: Digest<'static, u32>,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32 { digest: CRC32.digest() }
+    }
+}
+
+impl Check for Crc32 {
+    const TRAILER: usize = 4;
+
+    fn start(&mut self) {
+        self.digest = CRC32.digest();
+    }
+
+    fn update(&mut self, byte: u8) {
+        self.digest.update(&[byte]);
+    }
+
+    fn trailer(&mut self) -> Trailer {
+        let digest = core::mem::replace(&mut self.digest, CRC32.digest());
+        let mut trailer = Trailer::default();
+        trailer.extend(&digest.finalize().to_le_bytes());
+        trailer
+    }
+
+    fn verify(&mut self, frame: &[u8]) -> Result<Verified, CheckFailed> {
+        let len = frame.len().checked_sub(Self::TRAILER).ok_or(CheckFailed)?;
+        match CRC32.checksum(&frame[..len]).to_le_bytes() == frame[len..] {
+            true => Ok(Verified { len, dropped: 0 }),
+            false => Err(CheckFailed),
+        }
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Adds a one byte sequence number after the payload, covered by check `C`, so the decoder
+/// can tell when frames have gone missing. The decoder syncs to whatever the first frame
+/// after a reset carries.
+#[derive(Default)]
+pub struct Sequenced<C> {
+    check: C,
+    sequence: u8,
+    synced: bool,
+}
+
+impl <C: Check> Sequenced<C> {
+    pub const fn new(check: C) -> Self {
+        Sequenced { check, sequence: 0, synced: false }
+    }
+}
+
+impl <C: Check> Check for Sequenced<C> {
+    const TRAILER: usize = C::TRAILER + 1;
+
+    fn reset(&mut self) {
+        self.synced = false;
+        self.check.reset();
+    }
+
+    fn start(&mut self) {
+        self.check.start();
+    }
+
+    fn update(&mut self, byte: u8) {
+        self.check.update(byte);
+    }
+
+    fn trailer(&mut self) -> Trailer {
+        let sequence = self.sequence;
+        self.sequence = sequence.wrapping_add(1);
+        self.check.update(sequence);
+        let mut trailer = Trailer::default();
+        trailer.extend(&[sequence]);
+        trailer.extend(self.check.trailer().as_slice());
+        trailer
+    }
+
+    fn verify(&mut self, frame: &[u8]) -> Result<Verified, CheckFailed> {
+        let verified = self.check.verify(frame)?;
+        let len = verified.len.checked_sub(1).ok_or(CheckFailed)?;
+        let sequence = frame[len];
+        let dropped = match self.synced {
+            true => sequence.wrapping_sub(self.sequence) as u32,
+            false => 0,
+        };
+        self.sequence = sequence.wrapping_add(1);
+        self.synced = true;
+        Ok(Verified { len, dropped: verified.dropped + dropped })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECK: &[u8] = b"123456789";
+
+    /// Encode `payload` with `check`, trailer and all.
+    fn seal<C: Check>(check: &mut C, payload: &[u8]) -> ([u8; 32], usize) {
+        let mut frame = [0; 32];
+        check.start();
+        for byte in payload {
+            check.update(*byte);
+        }
+        frame[..payload.len()].copy_from_slice(payload);
+        let trailer = check.trailer();
+        frame[payload.len()..payload.len() + trailer.as_slice().len()].copy_from_slice(trailer.as_slice());
+        (frame, payload.len() + trailer.as_slice().len())
+    }
+
+    #[test]
+    fn crc16_known_answer() {
+        let (frame, len) = seal(&mut Crc16::new(), CHECK);
+        assert_eq!(frame[CHECK.len()..len], 0x906E_u16.to_le_bytes());
+        assert_eq!(Crc16::new().verify(&frame[..len]), Ok(Verified { len: CHECK.len(), dropped: 0 }));
+    }
+
+    #[test]
+    fn crc32_known_answer() {
+        let (frame, len) = seal(&mut Crc32::new(), CHECK);
+        assert_eq!(frame[CHECK.len()..len], 0xCBF43926_u32.to_le_bytes());
+        assert_eq!(Crc32::new().verify(&frame[..len]), Ok(Verified { len: CHECK.len(), dropped: 0 }));
+    }
+
+    #[test]
+    fn flipped_bits_are_rejected() {
+        let (frame, len) = seal(&mut Crc16::new(), CHECK);
+        for bit in 0..len * 8 {
+            let mut corrupt = frame;
+            corrupt[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(Crc16::new().verify(&corrupt[..len]), Err(CheckFailed), "bit {}", bit);
+        }
+        let (frame, len) = seal(&mut Crc32::new(), CHECK);
+        for bit in 0..len * 8 {
+            let mut corrupt = frame;
+            corrupt[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(Crc32::new().verify(&corrupt[..len]), Err(CheckFailed), "bit {}", bit);
+        }
+        assert_eq!(Crc16::new().verify(&[0x6E]), Err(CheckFailed));
+    }
+
+    #[test]
+    fn sequence_gaps_and_repeats() {
+        let mut encoder = Sequenced::new(Crc16::new());
+        let frames: [_; 5] = core::array::from_fn(|_| seal(&mut encoder, CHECK));
+        let mut decoder = Sequenced::new(Crc16::new());
+        let mut verify = |index: usize| {
+            let (frame, len) = &frames[index];
+            decoder.verify(&frame[..*len]).map(|verified| verified.dropped)
+        };
+
+        // Whatever comes first is in sequence
+        assert_eq!(verify(1), Ok(0));
+        assert_eq!(verify(2), Ok(0));
+        assert_eq!(verify(4), Ok(1));
+        // A repeat looks like everything but one frame of the sequence went missing
+        assert_eq!(verify(4), Ok(255));
+    }
+
+    #[test]
+    fn reset_resyncs_the_sequence() {
+        let mut encoder = Sequenced::new(Crc32::new());
+        let frames: [_; 4] = core::array::from_fn(|_| seal(&mut encoder, CHECK));
+        let mut decoder = Sequenced::new(Crc32::new());
+        assert_eq!(decoder.verify(&frames[3].0[..frames[3].1]).map(|verified| verified.dropped), Ok(0));
+        decoder.reset();
+        assert_eq!(decoder.verify(&frames[0].0[..frames[0].1]).map(|verified| verified.dropped), Ok(0));
+        assert_eq!(decoder.verify(&frames[1].0[..frames[1].1]), Ok(Verified { len: CHECK.len(), dropped: 0 }));
+    }
+}
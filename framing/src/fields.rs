@@ -0,0 +1,297 @@
+
+//! Reads the fields of an encoded message in place. `bytes` and `string` fields, and nested
+//! messages, borrow from the payload rather than being copied into a container, so a message
+//! carrying a large blob needs no more memory than the frame it arrived in.
+
+use core::convert::Infallible;
+
+/// Why a payload couldn't be read, in micropb's terms.
+pub type FieldError = micropb::DecodeError<Infallible>;
+
+/// The value of a field, by wire type. The accessors return None if the field doesn't have
+/// the wire type of the protobuf type asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value<'a> {
+    Varint(u64),
+    I64(u64),
+    Len(&'a [u8]),
+    I32(u32),
+}
+
+impl <'a> Value<'a> {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Varint(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        self.as_u64().map(|value| value as u32)
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        self.as_u64().map(|value| value as i64)
+    }
+
+    pub fn as_i32(&self) -> Option<i32> {
+        self.as_u64().map(|value| value as i32)
+    }
+
+    pub fn as_sint64(&self) -> Option<i64> {
+        self.as_u64().map(|value| ((value >> 1) as i64) ^ -((value & 1) as i64))
+    }
+
+    pub fn as_sint32(&self) -> Option<i32> {
+        self.as_sint64().map(|value| value as i32)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        self.as_u64().map(|value| value != 0)
+    }
+
+    pub fn as_fixed64(&self) -> Option<u64> {
+        match *self {
+            Value::I64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_fixed32(&self) -> Option<u32> {
+        match *self {
+            Value::I32(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        self.as_fixed64().map(f64::from_bits)
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        self.as_fixed32().map(f32::from_bits)
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            Value::Len(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// None if the field isn't length delimited, or isn't UTF-8.
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes().and_then(|bytes| core::str::from_utf8(bytes).ok())
+    }
+
+    /// The fields of a nested message.
+    pub fn as_fields(&self) -> Option<Fields<'a>> {
+        self.as_bytes().map(Fields::new)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field<'a> {
+    pub number: u32,
+    pub value: Value<'a>,
+}
+
+/// The fields of an encoded message, in the order they were encoded. Stops after an error.
+#[derive(Clone)]
+pub struct Fields<'a> {
+    payload: &'a [u8],
+}
+
+impl <'a> Fields<'a> {
+    pub const fn new(payload: &'a [u8]) -> Self {
+        Fields { payload }
+    }
+
+    fn varint(&mut self) -> Result<u64, FieldError> {
+        let mut value = 0;
+        for (index, byte) in self.payload.iter().enumerate().take(10) {
+            value |= ((byte & 0x7f) as u64) << (7 * index);
+            if byte & 0x80 == 0 {
+                self.payload = &self.payload[index + 1..];
+                return Ok(value);
+            }
+        }
+        match self.payload.len() < 10 {
+            true => Err(FieldError::UnexpectedEof),
+            false => Err(FieldError::VarIntLimit),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FieldError> {
+        if len > self.payload.len() {
+            return Err(FieldError::UnexpectedEof);
+        }
+        let (bytes, rest) = self.payload.split_at(len);
+        self.payload = rest;
+        Ok(bytes)
+    }
+
+    fn field(&mut self) -> Result<Field<'a>, FieldError> {
+        let tag = self.varint()?;
+        let number = (tag >> 3) as u32;
+        if number == 0 {
+            return Err(FieldError::ZeroField);
+        }
+        let value = match tag & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => Value::I64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            2 => {
+                let len = self.varint()? as usize;
+                Value::Len(self.take(len)?)
+            },
+            5 => Value::I32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            3 | 4 => return Err(FieldError::Deprecation),
+            _ => return Err(FieldError::UnknownWireType),
+        };
+        Ok(Field { number, value })
+    }
+}
+
+impl <'a> Iterator for Fields<'a> {
+    type Item = Result<Field<'a>, FieldError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.payload.is_empty() {
+            return None;
+        }
+        let field = self.field();
+        if field.is_err() {
+            self.payload = &[];
+        }
+        Some(field)
+    }
+}
+
+/// A message that borrows its `bytes` and `string` fields from the payload it's decoded from,
+/// usually written as a loop over `Fields`:
+///
+/// ```
+/// use pbstream_framing::{ BorrowedMessage, FieldError, Fields };
+///
+/// struct Chunk<'a> {
+///     offset: u32,
+///     data: &'a [u8],
+/// }
+///
+/// impl <'a> BorrowedMessage<'a> for Chunk<'a> {
+///     fn decode(payload: &'a [u8]) -> Result<Self, FieldError> {
+///         let mut chunk = Chunk { offset: 0, data: &[] };
+///         for field in Fields::new(payload) {
+///             let field = field?;
+///             match field.number {
+///                 1 => chunk.offset = field.value.as_u32().ok_or(FieldError::UnknownWireType)?,
+///                 2 => chunk.data = field.value.as_bytes().ok_or(FieldError::UnknownWireType)?,
+///                 _ => {},
+///             }
+///         }
+///         Ok(chunk)
+///     }
+/// }
+/// ```
+pub trait BorrowedMessage<'a>: Sized {
+    fn decode(payload: &'a [u8]) -> Result<Self, FieldError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(number: u32, value: Value<'_>) -> Option<Result<Field<'_>, FieldError>> {
+        Some(Ok(Field { number, value }))
+    }
+
+    #[test]
+    fn each_wire_type() {
+        let payload = [
+            0x08, 0x96, 0x01,
+            0x11, 1, 2, 3, 4, 5, 6, 7, 8,
+            0x1a, 3, b'a', b'b', b'c',
+            0x25, 1, 2, 3, 4,
+        ];
+        let mut fields = Fields::new(&payload);
+        assert_eq!(fields.next(), field(1, Value::Varint(150)));
+        assert_eq!(fields.next(), field(2, Value::I64(0x0807060504030201)));
+        assert_eq!(fields.next(), field(3, Value::Len(b"abc")));
+        assert_eq!(fields.next(), field(4, Value::I32(0x04030201)));
+        assert_eq!(fields.next(), None);
+    }
+
+    #[test]
+    fn accessors_check_the_wire_type() {
+        assert_eq!(Value::Varint(150).as_u32(), Some(150));
+        assert_eq!(Value::Varint(150).as_fixed32(), None);
+        assert_eq!(Value::I32(1.5f32.to_bits()).as_f32(), Some(1.5));
+        assert_eq!(Value::I64((-2.25f64).to_bits()).as_f64(), Some(-2.25));
+        assert_eq!(Value::I64(7).as_u64(), None);
+        assert_eq!(Value::Len(b"abc").as_str(), Some("abc"));
+        assert_eq!(Value::Len(&[0xff]).as_str(), None);
+        assert_eq!(Value::Varint(1).as_bytes(), None);
+        // Negative int32s are sign extended to ten bytes
+        assert_eq!(Value::Varint(u64::MAX).as_i32(), Some(-1));
+
+        let nested = Value::Len(&[0x08, 0x01]);
+        assert_eq!(nested.as_fields().unwrap().next(), field(1, Value::Varint(1)));
+    }
+
+    #[test]
+    fn zigzag() {
+        let pairs = [(0, 0), (1, -1), (2, 1), (3, -2), (4294967294, 2147483647), (4294967295, -2147483648)];
+        for (encoded, decoded) in pairs {
+            assert_eq!(Value::Varint(encoded).as_sint64(), Some(decoded));
+            assert_eq!(Value::Varint(encoded).as_sint32(), Some(decoded as i32));
+        }
+        assert_eq!(Value::Varint(u64::MAX).as_sint64(), Some(i64::MIN));
+        assert_eq!(Value::Varint(u64::MAX - 1).as_sint64(), Some(i64::MAX));
+    }
+
+    #[test]
+    fn truncated() {
+        // A varint that runs off the end, in a tag and in a value
+        let mut fields = Fields::new(&[0x80]);
+        assert_eq!(fields.next(), Some(Err(FieldError::UnexpectedEof)));
+        assert_eq!(fields.next(), None);
+        let mut fields = Fields::new(&[0x08, 0x96]);
+        assert_eq!(fields.next(), Some(Err(FieldError::UnexpectedEof)));
+
+        // Fewer bytes than the length, or than a fixed width value
+        let mut fields = Fields::new(&[0x08, 0x01, 0x12, 4, b'a', b'b', b'c']);
+        assert_eq!(fields.next(), field(1, Value::Varint(1)));
+        assert_eq!(fields.next(), Some(Err(FieldError::UnexpectedEof)));
+        assert_eq!(fields.next(), None);
+        assert_eq!(Fields::new(&[0x0d, 1, 2, 3]).next(), Some(Err(FieldError::UnexpectedEof)));
+        assert_eq!(Fields::new(&[0x09, 1, 2, 3, 4, 5, 6, 7]).next(), Some(Err(FieldError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn varint_limit() {
+        // Ten bytes is as long as a varint gets
+        let mut payload = [0xff; 12];
+        payload[0] = 0x08;
+        payload[10] = 0x01;
+        assert_eq!(Fields::new(&payload[..11]).next(), field(1, Value::Varint(u64::MAX)));
+
+        payload[10] = 0x81;
+        let mut fields = Fields::new(&payload);
+        assert_eq!(fields.next(), Some(Err(FieldError::VarIntLimit)));
+        assert_eq!(fields.next(), None);
+    }
+
+    #[test]
+    fn groups_and_unknown_wire_types() {
+        // Start and end group
+        assert_eq!(Fields::new(&[0x0b]).next(), Some(Err(FieldError::Deprecation)));
+        assert_eq!(Fields::new(&[0x0c]).next(), Some(Err(FieldError::Deprecation)));
+        let mut fields = Fields::new(&[0x0b, 0x08, 0x01, 0x0c]);
+        assert_eq!(fields.next(), Some(Err(FieldError::Deprecation)));
+        assert_eq!(fields.next(), None);
+
+        assert_eq!(Fields::new(&[0x0e]).next(), Some(Err(FieldError::UnknownWireType)));
+        assert_eq!(Fields::new(&[0x0f]).next(), Some(Err(FieldError::UnknownWireType)));
+        assert_eq!(Fields::new(&[0x00, 0x01]).next(), Some(Err(FieldError::ZeroField)));
+    }
+}
@@ -0,0 +1,158 @@
+
+//! Length prefixed framing: each payload follows its length, as a protobuf varint.
+
+use crate::{ DecodeError, FrameDecode, FrameEncode };
+
+/// The most bytes in the varint of a 32 bit length.
+const MAX_LENGTH_BYTES: u32 = 5;
+
+/// Bytes handed back by the encoder in one go.
+const BLOCK: usize = 256;
+
+enum State {
+    Length { value: u32, count: u32 },
+    Payload { size: usize },
+    /// Throwing away a payload that's too big for the buffer.
+    Skip { remaining: usize },
+}
+
+/// Decodes length prefixed frames a byte at a time, into a buffer the caller owns.
+pub struct LengthDecoder {
+    state: State,
+    len: usize,
+}
+
+impl LengthDecoder {
+    pub const fn new() -> Self {
+        LengthDecoder { state: State::Length { value: 0, count: 0 }, len: 0 }
+    }
+}
+
+impl FrameDecode for LengthDecoder {
+    fn reset(&mut self) {
+        self.state = State::Length { value: 0, count: 0 };
+        self.len = 0;
+    }
+
+    /// A payload that doesn't fit in the buffer is an error as soon as its length has been
+    /// read, and the payload is skipped. A length that isn't a valid varint can't be skipped,
+    /// so decoding starts again with the next byte, which probably won't help.
+    fn feed(&mut self, buffer: &mut [u8], byte: u8) -> Result<Option<usize>, DecodeError> {
+        match self.state {
+            State::Length { value, count } => {
+                let value = value | (((byte & 0x7f) as u32) << (7 * count));
+                let count = count + 1;
+                if byte & 0x80 != 0 {
+                    if count == MAX_LENGTH_BYTES {
+                        self.reset();
+                        return Err(DecodeError::InvalidFrame { decoded_bytes: 0 });
+                    }
+                    self.state = State::Length { value, count };
+                    return Ok(None);
+                }
+                let size = value as usize;
+                self.len = 0;
+                if size == 0 {
+                    self.reset();
+                    Ok(Some(0))
+                } else if size > buffer.len() {
+                    self.state = State::Skip { remaining: size };
+                    Err(DecodeError::TargetBufTooSmall)
+                } else {
+                    self.state = State::Payload { size };
+                    Ok(None)
+                }
+            },
+            State::Payload { size } => match buffer.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = byte;
+                    self.len += 1;
+                    if self.len == size {
+                        self.reset();
+                        Ok(Some(size))
+                    } else {
+                        Ok(None)
+                    }
+                },
+                None => {
+                    // The buffer shrank: skip the rest of the payload, after this byte
+                    match size - self.len - 1 {
+                        0 => self.reset(),
+                        remaining => self.state = State::Skip { remaining },
+                    }
+                    Err(DecodeError::TargetBufTooSmall)
+                },
+            },
+            State::Skip { remaining } => {
+                match remaining {
+                    1 => self.reset(),
+                    _ => self.state = State::Skip { remaining: remaining - 1 },
+                }
+                Ok(None)
+            },
+        }
+    }
+}
+
+impl Default for LengthDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Length prefixes payloads a byte at a time. The length has to be known up front.
+pub struct LengthEncoder {
+    block: [u8; BLOCK],
+    len: usize,
+}
+
+impl LengthEncoder {
+    pub const fn new() -> Self {
+        LengthEncoder { block: [0; BLOCK], len: 0 }
+    }
+}
+
+impl FrameEncode for LengthEncoder {
+    fn start(&mut self, len: usize) {
+        self.len = 0;
+        let mut value = len;
+        loop {
+            match value < 0x80 {
+                true => {
+                    self.block[self.len] = value as u8;
+                    self.len += 1;
+                    return;
+                },
+                false => {
+                    self.block[self.len] = (value as u8 & 0x7f) | 0x80;
+                    self.len += 1;
+                    value >>= 7;
+                },
+            }
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        self.block[self.len] = byte;
+        self.len += 1;
+        match self.len == BLOCK {
+            true => {
+                self.len = 0;
+                Some(&self.block)
+            },
+            false => None,
+        }
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        let len = self.len;
+        self.len = 0;
+        &self.block[..len]
+    }
+}
+
+impl Default for LengthEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,300 @@
+#![no_std]
+
+//! The wire format of a pbstream: each protocol buffer message is COBS encoded, and followed
+//! by a zero byte. COBS removes every zero from the message, so the zero marks the end of
+//! the frame, and a reader that starts mid-stream (or loses a byte) resyncs at the next one.
+//!
+//! Streams can also use `LengthPrefixed` framing instead, where each message follows its
+//! length as a varint. That's what protobuf's `writeDelimitedTo` and `parseDelimitedFrom`
+//! speak, but a reader can't resync if it loses a byte.
+//!
+//! Either way, a frame can carry a `Check` after its payload: a CRC, and optionally a
+//! sequence number.
+
+mod check;
+mod fields;
+mod length;
+
+use core::convert::Infallible;
+
+use cobs::{ CobsEncoder, DecodeResult, DecoderState };
+use micropb::{ MessageDecode, MessageEncode, PbDecoder, PbEncoder, PbWrite };
+
+pub use cobs::{ DecodeError, DestBufTooSmallError };
+pub use check::{ Check, CheckFailed, Crc16, Crc32, Sequenced, Trailer, Unchecked, Verified, MAX_TRAILER };
+pub use fields::{ BorrowedMessage, Field, FieldError, Fields, Value };
+pub use length::{ LengthDecoder, LengthEncoder };
+
+/// Splits a byte stream into frames, a byte at a time.
+pub trait FrameDecode {
+    /// Drop any partial frame.
+    fn reset(&mut self);
+
+    /// Feed one byte of the stream. When it completes a frame, returns the length of the
+    /// payload, which is at the start of `buffer`. After an error the partial frame has been
+    /// dropped.
+    fn feed(&mut self, buffer: &mut [u8], byte: u8) -> Result<Option<usize>, DecodeError>;
+}
+
+/// Frames payloads a byte at a time, handing back pieces to write out as they're ready.
+pub trait FrameEncode {
+    /// Start a frame, with a payload of `len` bytes. Drops any partial frame.
+    fn start(&mut self, len: usize);
+
+    /// Add a byte of the payload. Returns bytes to write out, if there are enough yet.
+    fn push(&mut self, byte: u8) -> Option<&[u8]>;
+
+    /// End the frame. Returns whatever's left to write out.
+    fn finish(&mut self) -> &[u8];
+}
+
+/// A way of framing messages in a stream, for choosing the encoder and decoder together.
+pub trait Framing {
+    type Decoder: FrameDecode + Default;
+    type Encoder: FrameEncode + Default;
+}
+
+/// Zero delimited COBS frames. This is the default.
+pub struct Cobs;
+
+impl Framing for Cobs {
+    type Decoder = FrameDecoder;
+    type Encoder = StreamEncoder;
+}
+
+/// Each payload follows its length, as a varint.
+pub struct LengthPrefixed;
+
+impl Framing for LengthPrefixed {
+    type Decoder = LengthDecoder;
+    type Encoder = LengthEncoder;
+}
+
+pub const DELIMITER: u8 = 0;
+
+/// The largest frame a payload of `size` bytes can need, including the delimiter.
+pub const fn max_frame_size(size: usize) -> usize {
+    cobs::max_encoding_length(size) + 1
+}
+
+struct PbCobsEncoder<'a>(CobsEncoder<'a>);
+
+impl PbWrite for PbCobsEncoder<'_> {
+    type Error = DestBufTooSmallError;
+
+    fn pb_write(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.0.push(data)
+    }
+}
+
+/// Encode a message straight into a frame in `buffer`. Returns the length of the frame.
+pub fn encode<M: MessageEncode>(message: &M, buffer: &mut [u8]) -> Result<usize, DestBufTooSmallError> {
+    let last = buffer.len().checked_sub(1).ok_or(DestBufTooSmallError)?;
+    let mut cobs = PbCobsEncoder(CobsEncoder::new(&mut buffer[..last]));
+    message.encode(&mut PbEncoder::new(&mut cobs))?;
+    let size = cobs.0.finalize();
+    buffer[size] = DELIMITER;
+    Ok(size + 1)
+}
+
+struct Window<'a> {
+    skip: usize,
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+struct WindowFull;
+
+impl PbWrite for Window<'_> {
+    type Error = WindowFull;
+
+    fn pb_write(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        let data = &data[skipped..];
+        let count = data.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + count].copy_from_slice(&data[..count]);
+        self.len += count;
+        match count < data.len() {
+            true => Err(WindowFull),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Encode the part of a message's encoding that starts at `offset`, into `buffer`, without
+/// framing. Returns the number of bytes written, which is less than the length of the buffer
+/// only at the end of the message. Everything before the offset is encoded again and thrown
+/// away, so a large message can be encoded a piece at a time with a small buffer.
+///
+/// That makes encoding a message of `S` bytes a window of `B` at a time cost about
+/// `S * S / (2 * B)` bytes of encoding, rather than `S`. micropb's encoders run to completion,
+/// so there's no state to resume from: keep `B` close to the size of most messages, and only
+/// the occasional large one pays for it.
+pub fn encode_window<M: MessageEncode>(message: &M, offset: usize, buffer: &mut [u8]) -> usize {
+    let mut window = Window { skip: offset, buffer, len: 0 };
+    // Stopping early when the window is full is the point, not an error
+    message.encode(&mut PbEncoder::new(&mut window)).ok();
+    window.len
+}
+
+/// Frame an already encoded payload. Returns the length of the frame.
+pub fn frame(payload: &[u8], buffer: &mut [u8]) -> Result<usize, DestBufTooSmallError> {
+    let last = buffer.len().checked_sub(1).ok_or(DestBufTooSmallError)?;
+    let size = cobs::try_encode(payload, &mut buffer[..last])?;
+    buffer[size] = DELIMITER;
+    Ok(size + 1)
+}
+
+/// Decode the payload of a frame. See `BorrowedMessage` for messages that borrow from it.
+pub fn decode<M: MessageDecode + Default>(payload: &[u8]) -> Result<M, micropb::DecodeError<Infallible>> {
+    let mut message = M::default();
+    message.decode(&mut PbDecoder::new(payload), payload.len())?;
+    Ok(message)
+}
+
+/// The most data bytes in one COBS block.
+const COBS_BLOCK: usize = 254;
+
+/// COBS encodes a frame a byte at a time. Each block is handed back as soon as it's
+/// complete, so a frame never has to be held in memory whole.
+pub struct StreamEncoder {
+    block: [u8; COBS_BLOCK + 2],
+    len: usize,
+}
+
+impl StreamEncoder {
+    pub const fn new() -> Self {
+        StreamEncoder { block: [0; COBS_BLOCK + 2], len: 0 }
+    }
+
+    /// Drop any partial frame.
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+
+    /// Add a byte of the payload. Returns a block to write out, if this byte completed one.
+    pub fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        if byte == DELIMITER {
+            return Some(self.block());
+        }
+        self.len += 1;
+        self.block[self.len] = byte;
+        match self.len == COBS_BLOCK {
+            true => Some(self.block()),
+            false => None,
+        }
+    }
+
+    /// End the frame. Returns the last block, and the delimiter.
+    pub fn finish(&mut self) -> &[u8] {
+        let len = self.len;
+        self.block[len + 1] = DELIMITER;
+        self.block[0] = (len + 1) as u8;
+        self.len = 0;
+        &self.block[..len + 2]
+    }
+
+    fn block(&mut self) -> &[u8] {
+        let len = self.len;
+        self.block[0] = (len + 1) as u8;
+        self.len = 0;
+        &self.block[..len + 1]
+    }
+}
+
+impl FrameEncode for StreamEncoder {
+    fn start(&mut self, _len: usize) {
+        self.reset();
+    }
+
+    fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        StreamEncoder::push(self, byte)
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        StreamEncoder::finish(self)
+    }
+}
+
+impl Default for StreamEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes frames a byte at a time, into a buffer the caller owns, so the same decoder works
+/// with a fixed array on a gadget and a `Vec` on a host. The buffer sets the largest frame.
+pub struct FrameDecoder {
+    state: DecoderState,
+    len: usize,
+    /// Throwing away the rest of a frame that didn't fit in the buffer.
+    discarding: bool,
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        FrameDecoder { state: DecoderState::Idle, len: 0, discarding: false }
+    }
+
+    /// Drop any partial frame.
+    pub fn reset(&mut self) {
+        self.state = DecoderState::Idle;
+        self.len = 0;
+        self.discarding = false;
+    }
+
+    /// Feed one byte of the stream. When it completes a frame, returns the length of the
+    /// payload, which is at the start of `buffer`. After an error the partial frame has been
+    /// dropped. A frame that's too big for the buffer is an error as soon as it overflows,
+    /// and the rest of it is skipped, up to the delimiter: decoding what's left as a frame of
+    /// its own could turn up a message that was never sent. After any other error, decoding
+    /// starts again with the next byte, since COBS errors only happen at a delimiter.
+    pub fn feed(&mut self, buffer: &mut [u8], byte: u8) -> Result<Option<usize>, DecodeError> {
+        if self.discarding {
+            self.discarding = byte != DELIMITER;
+            return Ok(None);
+        }
+        match self.state.feed(byte) {
+            Ok(DecodeResult::NoData) => Ok(None),
+            Ok(DecodeResult::DataStart) => {
+                self.len = 0;
+                Ok(None)
+            },
+            Ok(DecodeResult::DataContinue(data)) => match buffer.get_mut(self.len) {
+                Some(slot) => {
+                    *slot = data;
+                    self.len += 1;
+                    Ok(None)
+                },
+                None => {
+                    self.reset();
+                    self.discarding = true;
+                    Err(DecodeError::TargetBufTooSmall)
+                },
+            },
+            Ok(DecodeResult::DataComplete) => Ok(Some(self.len)),
+            Err(_) => {
+                let decoded_bytes = self.len;
+                self.reset();
+                Err(DecodeError::InvalidFrame { decoded_bytes })
+            },
+        }
+    }
+}
+
+impl FrameDecode for FrameDecoder {
+    fn reset(&mut self) {
+        FrameDecoder::reset(self)
+    }
+
+    fn feed(&mut self, buffer: &mut [u8], byte: u8) -> Result<Option<usize>, DecodeError> {
+        FrameDecoder::feed(self, buffer, byte)
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,42 @@
+
+//! Send a firmware image to a gadget's `update` channel.
+
+use std::{ path::PathBuf, process::ExitCode };
+
+use clap::Parser;
+use pbstream_host::{ update::Updater, Connection };
+
+#[derive(Parser)]
+#[command(version, about = "Send a firmware image to a gadget's update channel")]
+struct Args {
+    /// The gadget's update channel, as host:port
+    address: String,
+    /// The image to write, as a raw binary
+    image: PathBuf,
+    /// Write and verify the image, but don't swap it in
+    #[arg(long)]
+    no_commit: bool,
+}
+
+async fn run(args: Args) -> std::io::Result<()> {
+    let image = std::fs::read(&args.image)?;
+    let mut updater = Updater::new(Connection::connect(&args.address).await?);
+    updater.update(&image, |written| eprint!("\r{}/{} bytes", written, image.len())).await?;
+    eprintln!(", verified");
+    if !args.no_commit {
+        updater.commit().await?;
+        eprintln!("committed: the gadget will boot it next time it restarts");
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("\n{}", e);
+            ExitCode::FAILURE
+        },
+    }
+}
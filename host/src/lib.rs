@@ -0,0 +1,149 @@
+
+//! The host side of a gadget's channels: typed messages over a TCP connection, framed the
+//! same way as `rtic2_usb_gadget::codec::Encoder` and `Decoder`, using the same framing code.
+//! That's COBS by default, or `LengthPrefixed` to match a gadget codec that uses it.
+
+#[cfg(feature = "update")]
+pub mod update;
+
+use std::io;
+
+use micropb::{ MessageDecode, MessageEncode, PbEncoder };
+use pbstream_framing::{ Cobs, FrameDecode, FrameEncode, Framing };
+pub use pbstream_framing::LengthPrefixed;
+use tokio::{
+    io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt },
+    net::{ TcpStream, ToSocketAddrs },
+};
+
+/// The default limit on the size of a message from the gadget. It only needs to be as big as
+/// the gadget's `Encoder` buffer.
+pub const DEFAULT_MAX_FRAME: usize = 2048;
+
+const READ_CHUNK: usize = 512;
+
+/// A connection to one of a gadget's channels.
+pub struct Connection<S, F: Framing = Cobs> {
+    stream: S,
+    frames: F::Decoder,
+    encoder: F::Encoder,
+    frame: Vec<u8>,
+    pending: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl Connection<TcpStream> {
+    pub async fn connect<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        Self::connect_framed(address).await
+    }
+}
+
+impl <F: Framing> Connection<TcpStream, F> {
+    /// Connect to a channel that uses framing `F`.
+    pub async fn connect_framed<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(address).await?;
+        // Messages are small, and usually answered, so don't hold them back
+        stream.set_nodelay(true)?;
+        Ok(Self::framed(stream))
+    }
+}
+
+impl <S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Self {
+        Self::framed(stream)
+    }
+}
+
+impl <S: AsyncRead + AsyncWrite + Unpin, F: Framing> Connection<S, F> {
+    pub fn framed(stream: S) -> Self {
+        Self::with_max_frame(stream, DEFAULT_MAX_FRAME)
+    }
+
+    /// Accept messages from the gadget of up to `size` bytes, before framing.
+    pub fn with_max_frame(stream: S, size: usize) -> Self {
+        Connection {
+            stream,
+            frames: F::Decoder::default(),
+            encoder: F::Encoder::default(),
+            frame: vec![0; size],
+            pending: vec![0; READ_CHUNK],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    pub async fn send<M: MessageEncode>(&mut self, message: &M) -> io::Result<()> {
+        let mut payload = Vec::with_capacity(message.compute_size());
+        let Ok(()) = message.encode(&mut PbEncoder::new(&mut payload));
+        self.send_frame(&payload).await
+    }
+
+    /// The next message, or None once the gadget has closed the connection. A frame that
+    /// doesn't decode is an `InvalidData` error, but the connection can still be used.
+    pub async fn recv<M: MessageDecode + Default>(&mut self) -> io::Result<Option<M>> {
+        match self.recv_frame().await? {
+            Some(payload) => pbstream_framing::decode(payload)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))),
+            None => Ok(None),
+        }
+    }
+
+    /// Send a message generated by prost rather than micropb.
+    #[cfg(feature = "prost")]
+    pub async fn send_prost<M: prost::Message>(&mut self, message: &M) -> io::Result<()> {
+        self.send_frame(&message.encode_to_vec()).await
+    }
+
+    /// Frame an already encoded payload, and send it.
+    pub async fn send_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 16);
+        self.encoder.start(payload.len());
+        for byte in payload {
+            if let Some(piece) = self.encoder.push(*byte) {
+                frame.extend_from_slice(piece);
+            }
+        }
+        frame.extend_from_slice(self.encoder.finish());
+        self.stream.write_all(&frame).await
+    }
+
+    /// Receive a message generated by prost rather than micropb.
+    #[cfg(feature = "prost")]
+    pub async fn recv_prost<M: prost::Message + Default>(&mut self) -> io::Result<Option<M>> {
+        match self.recv_frame().await? {
+            Some(payload) => M::decode(payload)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+
+    /// The payload of the next frame, or None once the gadget has closed the connection.
+    pub async fn recv_frame(&mut self) -> io::Result<Option<&[u8]>> {
+        loop {
+            if self.start == self.end {
+                self.start = 0;
+                self.end = self.stream.read(&mut self.pending).await?;
+                if self.end == 0 {
+                    return Ok(None);
+                }
+            }
+
+            while self.start < self.end {
+                let byte = self.pending[self.start];
+                self.start += 1;
+                match self.frames.feed(&mut self.frame, byte) {
+                    Ok(None) => {},
+                    Ok(Some(size)) => return Ok(Some(&self.frame[..size])),
+                    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                }
+            }
+        }
+    }
+}
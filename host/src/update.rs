@@ -0,0 +1,149 @@
+
+//! The host side of `rtic2_usb_gadget::update`: the messages in `proto/update.proto`, as prost
+//! would generate them, and an `Updater` that sends an image to a gadget.
+
+use std::io;
+
+use sha2::{ Digest, Sha256 };
+use tokio::io::{ AsyncRead, AsyncWrite };
+
+use crate::Connection;
+
+/// The most image bytes in one chunk, as on the gadget.
+pub const UPDATE_CHUNK: usize = 256;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Begin {
+    #[prost(uint32, tag = "1")]
+    pub size: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Chunk {
+    #[prost(uint32, tag = "1")]
+    pub offset: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Verify {
+    #[prost(bytes = "vec", tag = "1")]
+    pub sha256: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Commit {}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Body {
+    #[prost(message, tag = "2")]
+    Begin(Begin),
+    #[prost(message, tag = "3")]
+    Chunk(Chunk),
+    #[prost(message, tag = "4")]
+    Verify(Verify),
+    #[prost(message, tag = "5")]
+    Commit(Commit),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UpdateRequest {
+    #[prost(uint32, tag = "1")]
+    pub id: u32,
+    #[prost(oneof = "Body", tags = "2, 3, 4, 5")]
+    pub body: Option<Body>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Status {
+    Ok = 0,
+    BadState = 1,
+    TooLarge = 2,
+    BadOffset = 3,
+    BadLength = 4,
+    FlashError = 5,
+    BadDigest = 6,
+    SwapFailed = 7,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UpdateResponse {
+    #[prost(uint32, tag = "1")]
+    pub id: u32,
+    #[prost(enumeration = "Status", tag = "2")]
+    pub status: i32,
+    #[prost(uint32, tag = "3")]
+    pub written: u32,
+}
+
+/// Sends firmware images over a connection to a gadget's update service.
+pub struct Updater<S> {
+    connection: Connection<S>,
+    id: u32,
+}
+
+impl <S: AsyncRead + AsyncWrite + Unpin> Updater<S> {
+    pub fn new(connection: Connection<S>) -> Self {
+        Updater { connection, id: 0 }
+    }
+
+    pub fn into_inner(self) -> Connection<S> {
+        self.connection
+    }
+
+    async fn call(&mut self, body: Body) -> io::Result<UpdateResponse> {
+        self.id = self.id.wrapping_add(1);
+        self.connection.send_prost(&UpdateRequest { id: self.id, body: Some(body) }).await?;
+        loop {
+            match self.connection.recv_prost::<UpdateResponse>().await? {
+                Some(response) if response.id == self.id => return Ok(response),
+                // An answer to an earlier request that was given up on
+                Some(_) => {},
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+
+    /// Write `image` to the gadget's flash and verify it, calling `progress` with how much has
+    /// been written after each chunk. It isn't swapped in until `commit`.
+    pub async fn update(&mut self, image: &[u8], mut progress: impl FnMut(usize)) -> io::Result<()> {
+        let size = u32::try_from(image.len()).map_err(|_| failed(Status::TooLarge, 0))?;
+        expect_ok(self.call(Body::Begin(Begin { size })).await?)?;
+
+        let mut offset = 0;
+        while offset < image.len() {
+            let end = (offset + UPDATE_CHUNK).min(image.len());
+            let data = image[offset..end].to_vec();
+            let response = self.call(Body::Chunk(Chunk { offset: offset as u32, data })).await?;
+            let written = response.written as usize;
+            match response.status() {
+                Status::Ok => offset = end,
+                // Carry on from wherever the gadget has got to, unless that's where this was
+                Status::BadOffset if written != offset && written <= image.len() => offset = written,
+                _ => return Err(failed(response.status(), response.written)),
+            }
+            progress(offset);
+        }
+
+        let sha256 = Sha256::digest(image).to_vec();
+        expect_ok(self.call(Body::Verify(Verify { sha256 })).await?)
+    }
+
+    /// Swap in the image that's just been verified, when the gadget next reboots.
+    pub async fn commit(&mut self) -> io::Result<()> {
+        expect_ok(self.call(Body::Commit(Commit {})).await?)
+    }
+}
+
+fn failed(status: Status, written: u32) -> io::Error {
+    io::Error::other(format!("update failed: {:?}, with {} bytes written", status, written))
+}
+
+fn expect_ok(response: UpdateResponse) -> io::Result<()> {
+    match response.status() {
+        Status::Ok => Ok(()),
+        status => Err(failed(status, response.written)),
+    }
+}
@@ -0,0 +1,58 @@
+
+//! Fixtures shared by the tests, and the gadget's byte streams over tokio's TCP halves.
+
+#![allow(dead_code)]
+
+#[cfg(feature = "update")]
+pub mod flash;
+mod logger;
+pub mod ping;
+
+use std::io;
+
+use rtic2_usb_gadget::stream::{ ByteSink, ByteStream, Sink, Stream };
+use tokio::{
+    io::{ AsyncReadExt, AsyncWriteExt },
+    net::{ tcp::{ OwnedReadHalf, OwnedWriteHalf }, TcpListener },
+};
+
+defmt::timestamp!("");
+
+pub struct ReadStream(pub OwnedReadHalf);
+
+impl Stream for ReadStream {
+    type Item = u8;
+
+    async fn next(&mut self) -> Option<u8> {
+        self.0.read_u8().await.ok()
+    }
+}
+
+impl ByteStream for ReadStream {
+    async fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.0.read(buf).await.unwrap_or(0)
+    }
+}
+
+pub struct WriteSink(pub OwnedWriteHalf);
+
+impl Sink for WriteSink {
+    type Item = u8;
+    type Error = io::Error;
+
+    async fn send(&mut self, item: u8) -> io::Result<()> {
+        self.0.write_all(&[item]).await
+    }
+}
+
+impl ByteSink for WriteSink {
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data).await
+    }
+}
+
+pub async fn listen() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    (listener, address)
+}
@@ -0,0 +1,56 @@
+
+//! A hand written message, for tests that don't need the generated ones.
+
+use micropb::{
+    DecodeError, MessageDecode, MessageEncode, PbDecoder, PbEncoder, PbRead, PbWrite,
+    Presence, Tag, WIRE_TYPE_LEN, WIRE_TYPE_VARINT,
+};
+
+/// What micropb-gen would make of `message Ping { uint32 seq = 1; string text = 2; }`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Ping {
+    pub seq: u32,
+    pub text: String,
+}
+
+impl MessageEncode for Ping {
+    const MAX_SIZE: Option<usize> = None;
+
+    fn encode<W: PbWrite>(&self, encoder: &mut PbEncoder<W>) -> Result<(), W::Error> {
+        if self.seq != 0 {
+            encoder.encode_tag(Tag::from_parts(1, WIRE_TYPE_VARINT))?;
+            encoder.encode_varint32(self.seq)?;
+        }
+        if !self.text.is_empty() {
+            encoder.encode_tag(Tag::from_parts(2, WIRE_TYPE_LEN))?;
+            encoder.encode_string(&self.text)?;
+        }
+        Ok(())
+    }
+
+    fn compute_size(&self) -> usize {
+        let mut size = 0;
+        if self.seq != 0 {
+            size += 1 + micropb::size::sizeof_varint32(self.seq);
+        }
+        if !self.text.is_empty() {
+            size += 1 + micropb::size::sizeof_len_record(self.text.len());
+        }
+        size
+    }
+}
+
+impl MessageDecode for Ping {
+    fn decode<R: PbRead>(&mut self, decoder: &mut PbDecoder<R>, len: usize) -> Result<(), DecodeError<R::Error>> {
+        let start = decoder.bytes_read();
+        while decoder.bytes_read() - start < len {
+            let tag = decoder.decode_tag()?;
+            match tag.field_num() {
+                1 => self.seq = decoder.decode_varint32()?,
+                2 => decoder.decode_string(&mut self.text, Presence::Implicit)?,
+                _ => decoder.skip_wire_value(tag.wire_type())?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+
+//! Both ends over a loopback socket: the gadget's `Decoder` and `Encoder` on one side, and
+//! a host `Connection` on the other.
+
+mod common;
+
+use common::{ listen, ping::Ping, ReadStream, WriteSink };
+use pbstream_framing::{ Cobs, Framing };
+use pbstream_host::{ Connection, LengthPrefixed };
+use rtic2_usb_gadget::{
+    codec::{ Decoder, Encoder },
+    stream::{ Sink, Stream },
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{ TcpListener, TcpStream },
+};
+
+/// Answers each ping with the next sequence number, and the text upper cased.
+async fn gadget(listener: TcpListener) {
+    framed_gadget::<Cobs>(listener).await
+}
+
+async fn framed_gadget<F: Framing>(listener: TcpListener) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let (read, write) = stream.into_split();
+    let mut decoder = Decoder::<_, Ping, 1024, F>::new(ReadStream(read));
+    // Much smaller than the biggest message, so that's encoded a window at a time
+    let mut encoder = Encoder::<Ping, _, 64, F>::new(WriteSink(write));
+    while let Some(ping) = decoder.next().await {
+        encoder.send(Ping { seq: ping.seq + 1, text: ping.text.to_uppercase() }).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn round_trip() {
+    let (listener, address) = listen().await;
+
+    let host = async {
+        let mut connection = Connection::connect(address).await.unwrap();
+        // A sequence number with a zero byte in its encoding, an empty message, and a text
+        // longer than one COBS block
+        let pings = [
+            Ping { seq: 255, text: "zero\0byte".into() },
+            Ping::default(),
+            Ping { seq: 7, text: "x".repeat(600) },
+        ];
+        for ping in pings.iter() {
+            connection.send(ping).await.unwrap();
+        }
+        for ping in pings.iter() {
+            let pong: Ping = connection.recv().await.unwrap().unwrap();
+            assert_eq!(pong, Ping { seq: ping.seq + 1, text: ping.text.to_uppercase() });
+        }
+    };
+
+    tokio::join!(gadget(listener), host);
+}
+
+#[tokio::test]
+async fn end_of_stream() {
+    let (listener, address) = listen().await;
+
+    let gadget = async {
+        let (stream, _) = listener.accept().await.unwrap();
+        drop(stream);
+    };
+    let host = async {
+        let mut connection = Connection::connect(address).await.unwrap();
+        assert_eq!(connection.recv::<Ping>().await.unwrap(), None);
+    };
+
+    tokio::join!(gadget, host);
+}
+
+#[tokio::test]
+async fn resyncs_after_garbage() {
+    let (listener, address) = listen().await;
+
+    let host = async {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        // A frame cut short by a delimiter, then a good one
+        stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut frame = [0; 32];
+        let size = pbstream_framing::encode(&Ping { seq: 1, text: "ok".into() }, &mut frame).unwrap();
+        stream.write_all(&frame[..size]).await.unwrap();
+
+        let mut connection = Connection::new(stream);
+        let pong: Ping = connection.recv().await.unwrap().unwrap();
+        assert_eq!(pong, Ping { seq: 2, text: "OK".into() });
+    };
+
+    tokio::join!(gadget(listener), host);
+}
+
+#[tokio::test]
+async fn length_prefixed_round_trip() {
+    let (listener, address) = listen().await;
+
+    let host = async {
+        let mut connection = Connection::<_, LengthPrefixed>::connect_framed(address).await.unwrap();
+        // An empty message has a zero length, and the long one a two byte length
+        let pings = [
+            Ping::default(),
+            Ping { seq: 3, text: "x".repeat(600) },
+            Ping { seq: 255, text: "zero\0byte".into() },
+        ];
+        for ping in pings.iter() {
+            connection.send(ping).await.unwrap();
+        }
+        for ping in pings.iter() {
+            let pong: Ping = connection.recv().await.unwrap().unwrap();
+            assert_eq!(pong, Ping { seq: ping.seq + 1, text: ping.text.to_uppercase() });
+        }
+    };
+
+    tokio::join!(framed_gadget::<LengthPrefixed>(listener), host);
+}
+
+#[cfg(feature = "prost")]
+#[derive(Clone, PartialEq, prost::Message)]
+struct ProstPing {
+    #[prost(uint32, tag = "1")]
+    seq: u32,
+    #[prost(string, tag = "2")]
+    text: String,
+}
+
+/// What a client using protobuf's `writeDelimitedTo` and `parseDelimitedFrom` sends and reads.
+#[cfg(feature = "prost")]
+#[tokio::test]
+async fn prost_length_delimited() {
+    use prost::Message;
+    use tokio::io::AsyncReadExt;
+
+    let (listener, address) = listen().await;
+
+    let host = async {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let ping = ProstPing { seq: 9, text: "y".repeat(300) };
+        stream.write_all(&ping.encode_length_delimited_to_vec()).await.unwrap();
+
+        let mut reply = vec![0; ping.encoded_len() + 16];
+        let mut len = 0;
+        let pong = loop {
+            len += stream.read(&mut reply[len..]).await.unwrap();
+            if let Ok(pong) = ProstPing::decode_length_delimited(&reply[..len]) {
+                break pong;
+            }
+        };
+        assert_eq!(pong, ProstPing { seq: 10, text: "Y".repeat(300) });
+    };
+
+    tokio::join!(framed_gadget::<LengthPrefixed>(listener), host);
+}
+
+#[cfg(feature = "prost")]
+#[tokio::test]
+async fn prost_round_trip() {
+    let (listener, address) = listen().await;
+
+    let host = async {
+        let mut connection = Connection::connect(address).await.unwrap();
+        connection.send_prost(&ProstPing { seq: 41, text: "prost".into() }).await.unwrap();
+        let pong: ProstPing = connection.recv_prost().await.unwrap().unwrap();
+        assert_eq!(pong, ProstPing { seq: 42, text: "PROST".into() });
+    };
+
+    tokio::join!(gadget(listener), host);
+}
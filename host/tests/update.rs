@@ -0,0 +1,63 @@
+
+//! An `Updater` sending images to the gadget's `FirmwareUpdate` over a loopback socket, which
+//! checks the prost and micropb messages agree.
+
+#![cfg(feature = "update")]
+
+mod common;
+
+use common::{ flash::{ MemoryFlash, Swap }, listen, ReadStream, WriteSink };
+use pbstream_host::{ update::Updater, Connection };
+use rtic2_usb_gadget::{
+    codec::{ Decoder, Encoder },
+    rpc::Dispatcher,
+    update::{ FirmwareUpdate, UpdateRequest, UpdateResponse },
+};
+use tokio::net::TcpListener;
+
+async fn gadget(listener: TcpListener, flash: MemoryFlash, swap: Swap) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let (read, write) = stream.into_split();
+    let mut requests = Decoder::<_, UpdateRequest, 512>::new(ReadStream(read));
+    let mut responses = Encoder::<UpdateResponse, _, 64>::new(WriteSink(write));
+    let dispatcher = Dispatcher::<_, 1>::new(FirmwareUpdate::new(flash, swap));
+    dispatcher.run(&mut requests, &mut responses).await.unwrap();
+}
+
+#[tokio::test]
+async fn update_and_commit() {
+    let (listener, address) = listen().await;
+    let flash = MemoryFlash::new();
+    let swap = Swap::default();
+    let image = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+    let host = async {
+        let mut updater = Updater::new(Connection::connect(address).await.unwrap());
+        let mut progress = Vec::new();
+        updater.update(&image, |written| progress.push(written)).await.unwrap();
+        updater.commit().await.unwrap();
+        assert_eq!(progress.len(), image.len().div_ceil(256));
+        assert_eq!(progress.last(), Some(&image.len()));
+    };
+
+    tokio::join!(gadget(listener, flash.clone(), swap.clone()), host);
+    assert_eq!(&flash.bytes.borrow()[..image.len()], &image[..]);
+    assert_eq!(swap.swapped.get(), Some(image.len() as u32));
+}
+
+#[tokio::test]
+async fn too_large() {
+    let (listener, address) = listen().await;
+    let flash = MemoryFlash::new();
+    let swap = Swap::default();
+
+    let host = async {
+        let mut updater = Updater::new(Connection::connect(address).await.unwrap());
+        let error = updater.update(&vec![0; MemoryFlash::CAPACITY + 1], |_| {}).await.unwrap_err();
+        assert!(error.to_string().contains("TooLarge"), "{}", error);
+        assert!(updater.commit().await.is_err());
+    };
+
+    tokio::join!(gadget(listener, flash, swap.clone()), host);
+    assert_eq!(swap.swapped.get(), None);
+}
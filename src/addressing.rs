@@ -0,0 +1,482 @@
+
+use defmt::{ debug, info, warn };
+
+use smoltcp::{
+    phy::{ Device, TxToken },
+    time::{ Duration, Instant },
+    wire::{
+        ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol,
+        EthernetRepr, Ipv4Address, Ipv4Cidr,
+    },
+};
+
+use crate::snoop::FrameObserver;
+
+/// How the gadget gets its IPv4 address.
+#[derive(Clone, Copy)]
+pub enum Addressing {
+    /// A fixed address, with an optional default gateway.
+    Static { address: Ipv4Cidr, gateway: Option<Ipv4Address> },
+    /// Wait for a DHCP server (or relay) on the host.
+    Dhcp,
+    /// Use DHCP, but if nothing is configured within `timeout` of the link coming up, claim an
+    /// RFC 3927 169.254/16 link-local address. DHCP keeps running, and replaces the link-local
+    /// address if a server turns up later.
+    DhcpWithLinkLocal { timeout: Duration },
+    /// Take `address` and run a DHCP server that leases the other end of the link to the host,
+    /// so the host needs no configuration at all. The subnet needs room for two hosts: a /30
+    /// is plenty. `Gadget::new` fails with `ConfigError::DhcpServerSubnet` if it hasn't.
+    #[cfg(feature = "dhcp-server")]
+    DhcpServer { address: Ipv4Cidr },
+}
+
+// RFC 3927 section 9
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+const PROBE_NUM: u8 = 3;
+const PROBE_MIN: Duration = Duration::from_secs(1);
+const PROBE_MAX: Duration = Duration::from_secs(2);
+const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+const ANNOUNCE_NUM: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_CONFLICTS: u8 = 10;
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+const LINK_LOCAL_PREFIX: u8 = 16;
+
+#[derive(Clone, Copy)]
+enum LinkLocalState {
+    Idle,
+    Probing { address: Ipv4Address, sent: u8, next: Instant },
+    Announcing { address: Ipv4Address, sent: u8, next: Instant, defended: Option<Instant> },
+    Bound { address: Ipv4Address, defended: Option<Instant> },
+}
+
+pub(crate) enum LinkLocalEvent {
+    Claimed(Ipv4Cidr),
+    Lost,
+}
+
+/// RFC 3927 dynamic configuration of IPv4 link-local addresses: pick a candidate in
+/// 169.254.1.0 - 169.254.254.255, probe for it with ARP, then announce it and defend it.
+pub(crate) struct LinkLocal {
+    mac_address: EthernetAddress,
+    state: LinkLocalState,
+    conflicts: u8,
+    conflict: bool,
+    random: u64,
+}
+
+impl LinkLocal {
+    pub fn new(mac_address: EthernetAddress) -> Self {
+        // The random sequence is seeded from the MAC address, as RFC 3927 suggests, so a
+        // gadget tends to get the same address each time it's plugged in.
+        let mac = mac_address.as_bytes()
+            .iter()
+            .fold(0u64, |hash, byte| (hash << 8) | *byte as u64);
+        LinkLocal {
+            mac_address,
+            state: LinkLocalState::Idle,
+            conflicts: 0,
+            conflict: false,
+            random: mac | 1,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, LinkLocalState::Idle)
+    }
+
+    pub fn start(&mut self, now: Instant) {
+        let address = self.candidate();
+        info!("link-local: probing for {}", address);
+        let next = now + self.jitter(Duration::ZERO, PROBE_WAIT);
+        self.conflict = false;
+        self.state = LinkLocalState::Probing { address, sent: 0, next };
+    }
+
+    pub fn stop(&mut self) {
+        if !self.is_idle() {
+            info!("link-local: stopped");
+        }
+        self.state = LinkLocalState::Idle;
+        self.conflicts = 0;
+        self.conflict = false;
+    }
+
+    /// When the next probe or announcement is due.
+    pub fn poll_at(&self) -> Option<Instant> {
+        match self.state {
+            LinkLocalState::Probing { next, .. } | LinkLocalState::Announcing { next, .. } => Some(next),
+            LinkLocalState::Idle | LinkLocalState::Bound { .. } => None,
+        }
+    }
+
+    pub fn poll<D: Device>(&mut self, now: Instant, device: &mut D) -> Option<LinkLocalEvent> {
+        let conflict = core::mem::take(&mut self.conflict);
+        match self.state {
+            LinkLocalState::Idle => None,
+            LinkLocalState::Probing { .. } if conflict => {
+                self.conflicts = self.conflicts.saturating_add(1);
+                let address = self.candidate();
+                let wait = if self.conflicts >= MAX_CONFLICTS {
+                    warn!("link-local: {} conflicts, rate limiting", self.conflicts);
+                    RATE_LIMIT_INTERVAL
+                } else {
+                    self.jitter(Duration::ZERO, PROBE_WAIT)
+                };
+                info!("link-local: conflict, probing for {}", address);
+                self.state = LinkLocalState::Probing { address, sent: 0, next: now + wait };
+                None
+            },
+            LinkLocalState::Probing { address, sent, next } if now >= next => {
+                if sent == PROBE_NUM {
+                    info!("link-local: claimed {}", address);
+                    self.state = LinkLocalState::Announcing { address, sent: 0, next: now, defended: None };
+                    Some(LinkLocalEvent::Claimed(Ipv4Cidr::new(address, LINK_LOCAL_PREFIX)))
+                } else if self.send(device, now, Ipv4Address::UNSPECIFIED, address) {
+                    let sent = sent + 1;
+                    let wait = if sent == PROBE_NUM {
+                        ANNOUNCE_WAIT
+                    } else {
+                        self.jitter(PROBE_MIN, PROBE_MAX)
+                    };
+                    self.state = LinkLocalState::Probing { address, sent, next: now + wait };
+                    None
+                } else {
+                    None
+                }
+            },
+            LinkLocalState::Probing { .. } => None,
+            LinkLocalState::Announcing { address, defended, .. }
+            | LinkLocalState::Bound { address, defended } if conflict => {
+                self.defend(now, device, address, defended)
+            },
+            LinkLocalState::Announcing { address, sent, next, defended } if now >= next => {
+                if self.send(device, now, address, address) {
+                    let sent = sent + 1;
+                    self.state = if sent == ANNOUNCE_NUM {
+                        debug!("link-local: announced {}", address);
+                        LinkLocalState::Bound { address, defended }
+                    } else {
+                        LinkLocalState::Announcing { address, sent, next: now + ANNOUNCE_INTERVAL, defended }
+                    };
+                }
+                None
+            },
+            LinkLocalState::Announcing { .. } | LinkLocalState::Bound { .. } => None,
+        }
+    }
+
+    fn defend<D: Device>(
+        &mut self,
+        now: Instant,
+        device: &mut D,
+        address: Ipv4Address,
+        defended: Option<Instant>) -> Option<LinkLocalEvent> {
+
+        match defended {
+            Some(at) if now < at + DEFEND_INTERVAL => {
+                warn!("link-local: lost {} to another host", address);
+                self.start(now);
+                Some(LinkLocalEvent::Lost)
+            },
+            _ => {
+                info!("link-local: defending {}", address);
+                self.send(device, now, address, address);
+                let defended = Some(now);
+                self.state = match self.state {
+                    LinkLocalState::Announcing { address, sent, next, .. } =>
+                        LinkLocalState::Announcing { address, sent, next, defended },
+                    _ => LinkLocalState::Bound { address, defended },
+                };
+                None
+            }
+        }
+    }
+
+    /// Probes have an unspecified sender address, announcements have the claimed address as
+    /// both sender and target. Both are broadcast requests.
+    fn send<D: Device>(
+        &self,
+        device: &mut D,
+        now: Instant,
+        source: Ipv4Address,
+        target: Ipv4Address) -> bool {
+
+        let arp = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: self.mac_address,
+            source_protocol_addr: source,
+            target_hardware_addr: EthernetAddress([0; 6]),
+            target_protocol_addr: target,
+        };
+        let ethernet = EthernetRepr {
+            src_addr: self.mac_address,
+            dst_addr: EthernetAddress::BROADCAST,
+            ethertype: EthernetProtocol::Arp,
+        };
+
+        match device.transmit(now) {
+            Some(token) => {
+                token.consume(ethernet.buffer_len() + arp.buffer_len(), |buffer| {
+                    let mut frame = EthernetFrame::new_unchecked(buffer);
+                    ethernet.emit(&mut frame);
+                    arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+                });
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn candidate(&mut self) -> Ipv4Address {
+        const RANGE: u64 = 254 * 256;
+        let offset = 256 + (self.next_random() % RANGE) as u16;
+        let [high, low] = offset.to_be_bytes();
+        Ipv4Address::new(169, 254, high, low)
+    }
+
+    fn jitter(&mut self, min: Duration, max: Duration) -> Duration {
+        let range = (max - min).total_millis() + 1;
+        min + Duration::from_millis(self.next_random() % range)
+    }
+
+    // xorshift64
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.random;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random = x;
+        x
+    }
+
+    fn address(&self) -> Option<Ipv4Address> {
+        match self.state {
+            LinkLocalState::Idle => None,
+            LinkLocalState::Probing { address, .. }
+            | LinkLocalState::Announcing { address, .. }
+            | LinkLocalState::Bound { address, .. } => Some(address),
+        }
+    }
+}
+
+impl FrameObserver for LinkLocal {
+    fn observe(&mut self, frame: &[u8]) {
+        let Some(address) = self.address() else { return };
+        let Ok(frame) = EthernetFrame::new_checked(frame) else { return };
+        if frame.ethertype() != EthernetProtocol::Arp {
+            return;
+        }
+        let Ok(packet) = ArpPacket::new_checked(frame.payload()) else { return };
+        let Ok(ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_protocol_addr,
+            ..
+        }) = ArpRepr::parse(&packet) else { return };
+
+        if source_hardware_addr == self.mac_address {
+            return;
+        }
+
+        // Another host using the address is always a conflict. While probing, so is another
+        // host probing for the same address.
+        let probing = matches!(self.state, LinkLocalState::Probing { .. });
+        if source_protocol_addr == address
+            || (probing
+                && operation == ArpOperation::Request
+                && source_protocol_addr.is_unspecified()
+                && target_protocol_addr == address) {
+            debug!("link-local: conflicting ARP from {}", source_hardware_addr);
+            self.conflict = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::phy::{ Loopback, Medium, RxToken };
+
+    use super::*;
+
+    const MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 1]);
+    const OTHER: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 2]);
+
+    /// What's been transmitted comes back out of the loopback device.
+    fn sent(device: &mut Loopback) -> Vec<ArpRepr> {
+        let mut sent = Vec::new();
+        while let Some((token, _)) = device.receive(Instant::ZERO) {
+            token.consume(|frame| {
+                let frame = EthernetFrame::new_checked(frame).unwrap();
+                assert_eq!(frame.dst_addr(), EthernetAddress::BROADCAST);
+                assert_eq!(frame.ethertype(), EthernetProtocol::Arp);
+                sent.push(ArpRepr::parse(&ArpPacket::new_checked(frame.payload()).unwrap()).unwrap());
+            });
+        }
+        sent
+    }
+
+    fn arp(operation: ArpOperation, source: Ipv4Address, target: Ipv4Address) -> Vec<u8> {
+        let arp = ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr: OTHER,
+            source_protocol_addr: source,
+            target_hardware_addr: EthernetAddress([0; 6]),
+            target_protocol_addr: target,
+        };
+        let ethernet = EthernetRepr { src_addr: OTHER, dst_addr: EthernetAddress::BROADCAST, ethertype: EthernetProtocol::Arp };
+        let mut buffer = vec![0; ethernet.buffer_len() + arp.buffer_len()];
+        let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+        ethernet.emit(&mut frame);
+        arp.emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+        buffer
+    }
+
+    fn probe(address: Ipv4Address) -> ArpRepr {
+        ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: MAC,
+            source_protocol_addr: Ipv4Address::UNSPECIFIED,
+            target_hardware_addr: EthernetAddress([0; 6]),
+            target_protocol_addr: address,
+        }
+    }
+
+    fn announcement(address: Ipv4Address) -> ArpRepr {
+        ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Request,
+            source_hardware_addr: MAC,
+            source_protocol_addr: address,
+            target_hardware_addr: EthernetAddress([0; 6]),
+            target_protocol_addr: address,
+        }
+    }
+
+    /// Poll every 100ms until `until`, returning the events.
+    fn run(link_local: &mut LinkLocal, device: &mut Loopback, now: &mut Instant, until: Instant) -> Vec<Ipv4Cidr> {
+        let mut claimed = Vec::new();
+        while *now < until {
+            *now += Duration::from_millis(100);
+            match link_local.poll(*now, device) {
+                Some(LinkLocalEvent::Claimed(cidr)) => claimed.push(cidr),
+                Some(LinkLocalEvent::Lost) => panic!("lost"),
+                None => {},
+            }
+        }
+        claimed
+    }
+
+    /// Probe, announce and bind, returning the address.
+    fn bound(link_local: &mut LinkLocal, device: &mut Loopback, now: &mut Instant) -> Ipv4Address {
+        link_local.start(*now);
+        let claimed = run(link_local, device, now, *now + Duration::from_secs(20));
+        assert_eq!(claimed.len(), 1);
+        sent(device);
+        claimed[0].address()
+    }
+
+    #[test]
+    fn probes_then_announces() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut link_local = LinkLocal::new(MAC);
+        let mut now = Instant::ZERO;
+        link_local.start(now);
+        let address = link_local.address().unwrap();
+        assert_eq!(address.octets()[..2], [169, 254]);
+        assert!((1..=254).contains(&address.octets()[2]));
+
+        let claimed = run(&mut link_local, &mut device, &mut now, Instant::from_secs(20));
+        assert_eq!(claimed, [Ipv4Cidr::new(address, 16)]);
+        let mut expected = vec![probe(address); 3];
+        expected.extend([announcement(address); 2]);
+        assert_eq!(sent(&mut device), expected);
+        assert_eq!(link_local.poll_at(), None);
+    }
+
+    #[test]
+    fn same_address_each_time() {
+        let mut first = LinkLocal::new(MAC);
+        let mut second = LinkLocal::new(MAC);
+        first.start(Instant::ZERO);
+        second.start(Instant::ZERO);
+        assert_eq!(first.address(), second.address());
+    }
+
+    #[test]
+    fn conflict_while_probing_picks_another_address() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut link_local = LinkLocal::new(MAC);
+        let mut now = Instant::ZERO;
+        link_local.start(now);
+        let address = link_local.address().unwrap();
+        run(&mut link_local, &mut device, &mut now, Instant::from_millis(1500));
+
+        // Another host probing for the same address
+        link_local.observe(&arp(ArpOperation::Request, Ipv4Address::UNSPECIFIED, address));
+        let claimed = run(&mut link_local, &mut device, &mut now, Instant::from_secs(20));
+        let other = link_local.address().unwrap();
+        assert_ne!(other, address);
+        assert_eq!(claimed, [Ipv4Cidr::new(other, 16)]);
+        assert!(sent(&mut device).contains(&probe(other)));
+    }
+
+    #[test]
+    fn ignores_unrelated_and_its_own_arp() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut link_local = LinkLocal::new(MAC);
+        let mut now = Instant::ZERO;
+        link_local.start(now);
+        let address = link_local.address().unwrap();
+
+        link_local.observe(&arp(ArpOperation::Request, Ipv4Address::new(169, 254, 9, 9), Ipv4Address::new(169, 254, 9, 10)));
+        // The probe coming back, as it does on a loopback
+        run(&mut link_local, &mut device, &mut now, Instant::from_secs(2));
+        while let Some((token, _)) = device.receive(now) {
+            token.consume(|frame| link_local.observe(frame));
+        }
+        let claimed = run(&mut link_local, &mut device, &mut now, Instant::from_secs(20));
+        assert_eq!(claimed, [Ipv4Cidr::new(address, 16)]);
+    }
+
+    #[test]
+    fn defends_once_then_gives_up() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut link_local = LinkLocal::new(MAC);
+        let mut now = Instant::ZERO;
+        let address = bound(&mut link_local, &mut device, &mut now);
+
+        // The first conflict is defended with an announcement
+        link_local.observe(&arp(ArpOperation::Reply, address, address));
+        now += Duration::from_millis(100);
+        assert!(link_local.poll(now, &mut device).is_none());
+        assert_eq!(sent(&mut device), [announcement(address)]);
+        assert_eq!(link_local.address(), Some(address));
+
+        // Another within DEFEND_INTERVAL loses the address
+        now += Duration::from_secs(5);
+        link_local.observe(&arp(ArpOperation::Reply, address, address));
+        now += Duration::from_millis(100);
+        assert!(matches!(link_local.poll(now, &mut device), Some(LinkLocalEvent::Lost)));
+        assert!(sent(&mut device).is_empty());
+        assert!(matches!(link_local.state, LinkLocalState::Probing { .. }));
+    }
+
+    #[test]
+    fn defends_again_after_the_interval() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut link_local = LinkLocal::new(MAC);
+        let mut now = Instant::ZERO;
+        let address = bound(&mut link_local, &mut device, &mut now);
+
+        for _ in 0..2 {
+            link_local.observe(&arp(ArpOperation::Request, address, Ipv4Address::new(169, 254, 9, 9)));
+            now += Duration::from_millis(100);
+            assert!(link_local.poll(now, &mut device).is_none());
+            assert_eq!(sent(&mut device), [announcement(address)]);
+            now += DEFEND_INTERVAL;
+        }
+    }
+}
@@ -0,0 +1,269 @@
+
+use defmt::{ debug, info, warn };
+
+use smoltcp::{
+    iface::{ Context, SocketHandle, SocketSet },
+    socket::tcp,
+    time::{ Duration, Instant },
+    wire::{ IpEndpoint, Ipv4Address },
+};
+
+/// The most client channels a gadget can have.
+pub const MAX_CLIENTS: usize = 4;
+
+/// The first retry after a failed or dropped connection. Each failure doubles it, up to
+/// `CONNECT_BACKOFF_MAX`.
+pub const CONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+pub const CONNECT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+const EPHEMERAL_PORTS: u16 = 49152;
+
+/// Where a client channel connects to.
+#[derive(Clone, Copy)]
+pub enum Remote {
+    Endpoint(IpEndpoint),
+    /// This port on the default gateway, from DHCP or `Addressing::Static`.
+    Router(u16),
+    /// This port on the first DNS server offered by DHCP.
+    DnsServer(u16),
+}
+
+impl Remote {
+    fn resolve(&self, router: Option<Ipv4Address>, dns_server: Option<Ipv4Address>) -> Option<IpEndpoint> {
+        match *self {
+            Remote::Endpoint(endpoint) => Some(endpoint),
+            Remote::Router(port) => router.map(|address| IpEndpoint::new(address.into(), port)),
+            Remote::DnsServer(port) => dns_server.map(|address| IpEndpoint::new(address.into(), port)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ConnectorState {
+    Waiting(Instant),
+    Connecting,
+    Established,
+}
+
+/// Keeps a client socket connected: connects once the gadget has an address, and reconnects
+/// with exponential backoff when the connection fails, or is closed or reset.
+pub(crate) struct Connector {
+    handle: SocketHandle,
+    remote: Remote,
+    state: ConnectorState,
+    backoff: Duration,
+}
+
+impl Connector {
+    pub fn new(handle: SocketHandle, remote: Remote) -> Self {
+        Connector { handle, remote, state: ConnectorState::Waiting(Instant::ZERO), backoff: CONNECT_BACKOFF_MIN }
+    }
+
+    /// Pick a starting point in the ephemeral port range, so a rebooted gadget doesn't reuse
+    /// the ports of connections the host may still remember.
+    pub fn first_port(seed: u64) -> u16 {
+        EPHEMERAL_PORTS + (seed % (u16::MAX - EPHEMERAL_PORTS) as u64) as u16
+    }
+
+    fn next_port(port: &mut u16) -> u16 {
+        let next = *port;
+        *port = if next == u16::MAX { EPHEMERAL_PORTS } else { next + 1 };
+        next
+    }
+
+    /// When `poll` next has something to do, other than notice the socket change.
+    pub fn poll_at(&self) -> Option<Instant> {
+        match self.state {
+            ConnectorState::Waiting(at) => Some(at),
+            _ => None,
+        }
+    }
+
+    /// Returns true if a connection attempt was started, so there's a SYN to send.
+    pub fn poll(
+        &mut self,
+        now: Instant,
+        cx: &mut Context,
+        sockets: &mut SocketSet<'_>,
+        router: Option<Ipv4Address>,
+        dns_server: Option<Ipv4Address>,
+        port: &mut u16) -> bool {
+
+        let socket = sockets.get_mut::<tcp::Socket>(self.handle);
+        match self.state {
+            ConnectorState::Connecting | ConnectorState::Established if !socket.is_open() => {
+                match self.state {
+                    ConnectorState::Established => self.backoff = CONNECT_BACKOFF_MIN,
+                    _ => self.backoff = (self.backoff * 2).min(CONNECT_BACKOFF_MAX),
+                }
+                info!("connection closed, retrying in {}s", self.backoff.secs());
+                self.state = ConnectorState::Waiting(now + self.backoff);
+                false
+            },
+            ConnectorState::Connecting if socket.state() == tcp::State::Established => {
+                if let Some(endpoint) = socket.remote_endpoint() {
+                    info!("connected to {}", endpoint);
+                }
+                self.state = ConnectorState::Established;
+                false
+            },
+            ConnectorState::Waiting(at) if now >= at => {
+                let Some(endpoint) = self.remote.resolve(router, dns_server) else {
+                    debug!("no address to connect to yet");
+                    self.state = ConnectorState::Waiting(now + self.backoff);
+                    return false;
+                };
+
+                match socket.connect(cx, endpoint, Self::next_port(port)) {
+                    Ok(()) => {
+                        debug!("connecting to {}", endpoint);
+                        self.state = ConnectorState::Connecting;
+                        true
+                    },
+                    Err(e) => {
+                        warn!("failed to connect to {}: {}", endpoint, e);
+                        self.backoff = (self.backoff * 2).min(CONNECT_BACKOFF_MAX);
+                        self.state = ConnectorState::Waiting(now + self.backoff);
+                        false
+                    }
+                }
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::{
+        iface::{ Config, Interface },
+        phy::{ Loopback, Medium },
+        wire::{ EthernetAddress, IpAddress, IpCidr },
+    };
+
+    use super::*;
+
+    const ADDRESS: Ipv4Address = Ipv4Address::new(127, 0, 0, 1);
+    const PORT: u16 = 1234;
+
+    struct Network {
+        interface: Interface,
+        device: Loopback,
+        sockets: SocketSet<'static>,
+        now: Instant,
+        connector: Connector,
+        port: u16,
+    }
+
+    impl Network {
+        fn new(remote: Remote) -> Self {
+            let mut device = Loopback::new(Medium::Ethernet);
+            let config = Config::new(EthernetAddress([0x02, 0, 0, 0, 0, 1]).into());
+            let mut interface = Interface::new(config, &mut device, Instant::ZERO);
+            interface.update_ip_addrs(|addrs| addrs.push(IpCidr::new(IpAddress::Ipv4(ADDRESS), 8)).unwrap());
+            let mut sockets = SocketSet::new(vec![]);
+            let socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 64]), tcp::SocketBuffer::new(vec![0; 64]));
+            let handle = sockets.add(socket);
+            Network { interface, device, sockets, now: Instant::ZERO, connector: Connector::new(handle, remote), port: EPHEMERAL_PORTS }
+        }
+
+        fn listen(&mut self) -> SocketHandle {
+            let mut socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 64]), tcp::SocketBuffer::new(vec![0; 64]));
+            socket.listen(PORT).unwrap();
+            self.sockets.add(socket)
+        }
+
+        /// Poll the connector, with `router` as the default gateway.
+        fn connect(&mut self, router: Option<Ipv4Address>) -> bool {
+            let cx = self.interface.context();
+            self.connector.poll(self.now, cx, &mut self.sockets, router, None, &mut self.port)
+        }
+
+        fn run(&mut self) {
+            for _ in 0..10 {
+                self.now += Duration::from_millis(10);
+                self.interface.poll(self.now, &mut self.device, &mut self.sockets);
+            }
+        }
+
+        fn socket(&mut self) -> &mut tcp::Socket<'static> {
+            self.sockets.get_mut(self.connector.handle)
+        }
+
+        /// Wait until the connector's next attempt is due, and poll it then.
+        fn retry(&mut self) -> bool {
+            let at = self.connector.poll_at().unwrap();
+            self.now = at - Duration::from_millis(1);
+            assert!(!self.connect(Some(ADDRESS)));
+            self.now = at;
+            self.connect(Some(ADDRESS))
+        }
+    }
+
+    #[test]
+    fn waits_for_an_address() {
+        let mut network = Network::new(Remote::Router(PORT));
+        assert!(!network.connect(None));
+        assert_eq!(network.connector.poll_at(), Some(Instant::ZERO + CONNECT_BACKOFF_MIN));
+        assert!(!network.socket().is_open());
+
+        network.now += CONNECT_BACKOFF_MIN;
+        assert!(network.connect(Some(ADDRESS)));
+        assert_eq!(network.connector.poll_at(), None);
+        assert_eq!(network.socket().remote_endpoint(), Some(IpEndpoint::new(ADDRESS.into(), PORT)));
+    }
+
+    #[test]
+    fn connects_and_resets_the_backoff() {
+        let mut network = Network::new(Remote::Endpoint(IpEndpoint::new(ADDRESS.into(), PORT)));
+
+        // Refused a few times, so the backoff has grown
+        assert!(network.connect(None));
+        network.run();
+        assert!(!network.connect(None));
+        assert_eq!(network.connector.backoff, CONNECT_BACKOFF_MIN * 2);
+        for _ in 0..2 {
+            assert!(network.retry());
+            network.run();
+            assert!(!network.connect(None));
+        }
+        assert_eq!(network.connector.backoff, CONNECT_BACKOFF_MIN * 8);
+
+        network.listen();
+        assert!(network.retry());
+        network.run();
+        assert!(!network.connect(None));
+        assert!(matches!(network.connector.state, ConnectorState::Established));
+
+        // Once a connection's been made, the first retry after it drops is quick
+        network.socket().abort();
+        network.run();
+        assert!(!network.connect(None));
+        assert_eq!(network.connector.poll_at(), Some(network.now + CONNECT_BACKOFF_MIN));
+    }
+
+    #[test]
+    fn backoff_stops_growing() {
+        let mut network = Network::new(Remote::Endpoint(IpEndpoint::new(ADDRESS.into(), PORT)));
+        assert!(network.connect(None));
+        for _ in 0..10 {
+            network.run();
+            assert!(!network.connect(None));
+            assert!(network.connector.backoff <= CONNECT_BACKOFF_MAX);
+            assert!(network.retry());
+        }
+        assert_eq!(network.connector.backoff, CONNECT_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn ports() {
+        for seed in [0, 1, 16383, u64::MAX] {
+            assert!(Connector::first_port(seed) >= EPHEMERAL_PORTS);
+        }
+        let mut port = u16::MAX - 1;
+        assert_eq!(Connector::next_port(&mut port), u16::MAX - 1);
+        assert_eq!(Connector::next_port(&mut port), u16::MAX);
+        assert_eq!(Connector::next_port(&mut port), EPHEMERAL_PORTS);
+        assert_eq!(port, EPHEMERAL_PORTS + 1);
+    }
+}
@@ -2,119 +2,408 @@
 
 
 use core::{
+    convert::Infallible,
     marker::PhantomData,
     option::Option,
 };
 
-use micropb::{ MessageDecode, MessageEncode, PbDecoder, PbEncoder, PbWrite };
-use defmt::{ debug, error };
-use cobs::{ CobsDecoder, CobsEncoder, DestBufTooSmallError };
+use micropb::{ heapless::Vec, MessageDecode, MessageEncode, PbDecoder, PbEncoder };
+use defmt::{ debug, error, warn };
+use pbstream_framing::{ BorrowedMessage, Check, Cobs, DecodeError, FrameDecode, FrameEncode, Framing, Unchecked, Verified };
 
 use crate::stream::{ ByteStream , ByteSink, Sink, Stream };
 
-pub struct Decoder<I, O, const BN: usize> {
+/// Why a message couldn't be decoded or encoded. `E` is the error of the sink an encoder
+/// writes to: decoders read from streams that can't fail, so theirs is `Infallible`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum CodecError<E = Infallible> {
+    /// A frame wasn't valid COBS, or its length prefix wasn't a valid varint.
+    Cobs,
+    /// A frame failed its CRC.
+    Check,
+    /// A frame wasn't a valid message.
+    Decode,
+    /// A frame didn't fit in the decoder's buffer. The rest of it is skipped.
+    FrameTooLong,
+    /// A message didn't fit in the encoder's buffer.
+    EncodeOverflow,
+    /// The sink failed.
+    Io(E),
+}
+
+/// Counts of what a codec has handled, for reporting to the host.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct CodecCounters {
+    /// Messages decoded or encoded.
+    pub messages: u32,
+    pub cobs: u32,
+    pub check: u32,
+    pub decode: u32,
+    /// Frames the decoder skipped because they were too long for its buffer.
+    pub frame_too_long: u32,
+    pub encode_overflow: u32,
+    pub io: u32,
+    /// Frames that went missing, going by their sequence numbers.
+    pub dropped: u32,
+}
+
+impl CodecCounters {
+    pub const fn new() -> Self {
+        CodecCounters {
+            messages: 0,
+            cobs: 0,
+            check: 0,
+            decode: 0,
+            frame_too_long: 0,
+            encode_overflow: 0,
+            io: 0,
+            dropped: 0,
+        }
+    }
+
+    /// The total number of errors. Dropped frames aren't errors here: they were lost on the
+    /// way, and usually counted as errors too.
+    pub fn errors(&self) -> u32 {
+        self.cobs + self.check + self.decode + self.frame_too_long + self.encode_overflow + self.io
+    }
+
+    /// The number of frames a decoder has thrown away, for whatever reason.
+    pub fn discarded(&self) -> u32 {
+        self.cobs + self.check + self.decode + self.frame_too_long
+    }
+
+    fn count<T, E>(&mut self, result: &Result<T, CodecError<E>>) {
+        let counter = match result {
+            Ok(_) => &mut self.messages,
+            Err(CodecError::Cobs) => &mut self.cobs,
+            Err(CodecError::Check) => &mut self.check,
+            Err(CodecError::Decode) => &mut self.decode,
+            Err(CodecError::FrameTooLong) => &mut self.frame_too_long,
+            Err(CodecError::EncodeOverflow) => &mut self.encode_overflow,
+            Err(CodecError::Io(_)) => &mut self.io,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+/// Bytes read from the input in one go, before they're fed through COBS.
+const DECODER_CHUNK: usize = 32;
+
+/// Decodes messages from frames of up to `BN` bytes. `F` is the framing, which is COBS unless
+/// the other end speaks `pbstream_framing::LengthPrefixed`. `C` checks each frame: on a UART
+/// use a `pbstream_framing::Crc16` or `Crc32`, maybe `Sequenced`, to match the other end.
+pub struct Decoder<I, O, const BN: usize, F: Framing = Cobs, C: Check = Unchecked> {
     input: I,
     buffer : [u8; BN],
+    frames: F::Decoder,
+    check: C,
+    pending: [u8; DECODER_CHUNK],
+    start: usize,
+    end: usize,
+    counters: CodecCounters,
     target: PhantomData<O>,
 }
 
-impl <I: ByteStream, O, const BN: usize> Decoder<I, O, BN> {
+impl <I: ByteStream, O, const BN: usize, F: Framing, C: Check + Default> Decoder<I, O, BN, F, C> {
     pub fn new(requests: I) -> Self {
-        Decoder { input: requests, buffer: [0; BN], target: PhantomData::default() }
-    }
-
-    async fn frame(&mut self) -> usize {
-        let mut cobs = CobsDecoder::new(&mut self.buffer);
-        let mut optional: Option<usize> = None;
-        while let None = optional { 
-            optional = match self.input.next().await {
-                Some(byte) => {
-                    debug!("byte received {:x}", byte);
-                    match cobs.feed(byte) {
-                        Ok(None) => None,
-                        Ok(Some(size)) => Some(size),
-                        Err(err) => {
-                            error!("cobs: {}", err); 
-                            cobs = CobsDecoder::new(&mut self.buffer);
-                            None
+        Decoder {
+            input: requests,
+            buffer: [0; BN],
+            frames: F::Decoder::default(),
+            check: C::default(),
+            pending: [0; DECODER_CHUNK],
+            start: 0,
+            end: 0,
+            counters: CodecCounters::new(),
+            target: PhantomData,
+        }
+    }
+
+    /// Forget any bytes read but not yet decoded, and any partial frame, for example when the
+    /// connection they came from has gone. It happens by itself at a session boundary in the
+    /// input, like a fenced ring.
+    pub fn reset(&mut self) {
+        self.frames.reset();
+        self.check.reset();
+        self.start = 0;
+        self.end = 0;
+    }
+
+    pub fn counters(&self) -> &CodecCounters {
+        &self.counters
+    }
+
+    /// The size of the next frame in the buffer, or None at the end of the input.
+    async fn frame(&mut self) -> Option<Result<usize, CodecError>> {
+        loop {
+            if self.start == self.end {
+                self.start = 0;
+                self.end = self.input.read(&mut self.pending).await;
+                if self.input.take_boundary() {
+                    debug!("new session, dropping any partial frame");
+                    self.frames.reset();
+                    self.check.reset();
+                }
+                if self.end == 0 {
+                    return None;
+                }
+                debug!("bytes received {:x}", self.pending[..self.end]);
+            }
+
+            while self.start < self.end {
+                let byte = self.pending[self.start];
+                self.start += 1;
+                match self.frames.feed(&mut self.buffer, byte) {
+                    Ok(None) => {},
+                    Ok(Some(size)) => { return Some(Ok(size)); },
+                    Err(DecodeError::TargetBufTooSmall) => { return Some(Err(CodecError::FrameTooLong)); },
+                    Err(_) => { return Some(Err(CodecError::Cobs)); },
+                }
+            }
+        }
+    }
+
+    /// The length of the payload of the next frame that passes its check, or why the next
+    /// frame didn't. Errors are counted here, but payloads aren't, since they aren't messages
+    /// yet.
+    async fn payload(&mut self) -> Option<Result<usize, CodecError>> {
+        let result = match self.frame().await? {
+            Ok(size) => {
+                debug!("frame received {:x}", self.buffer[0..size]);
+                match self.check.verify(&self.buffer[..size]) {
+                    Ok(Verified { len, dropped }) => {
+                        if dropped > 0 {
+                            warn!("{} frames dropped", dropped);
+                            self.counters.dropped = self.counters.dropped.wrapping_add(dropped);
                         }
-                    }
-                },
-                None => Some(0),
-            }          
+                        Ok(len)
+                    },
+                    Err(_) => Err(CodecError::Check),
+                }
+            },
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            self.counters.count(&result);
         }
+        Some(result)
+    }
+
+    /// The payload of the next frame, without decoding it. It's in the decoder's buffer, so
+    /// it's only valid until the next call.
+    pub async fn next_frame(&mut self) -> Option<Result<&[u8], CodecError>> {
+        let len = match self.payload().await? {
+            Ok(len) => len,
+            Err(err) => { return Some(Err(err)); },
+        };
+        self.counters.messages = self.counters.messages.wrapping_add(1);
+        Some(Ok(&self.buffer[..len]))
+    }
+
+    /// The next message, decoded in place: its `bytes` and `string` fields borrow from the
+    /// decoder's buffer, so they're only valid until the next call.
+    pub async fn next_borrowed<'a, M: BorrowedMessage<'a>>(&'a mut self) -> Option<Result<M, CodecError>> {
+        let len = match self.payload().await? {
+            Ok(len) => len,
+            Err(err) => { return Some(Err(err)); },
+        };
+        let result = M::decode(&self.buffer[..len]).map_err(|_| CodecError::Decode);
+        self.counters.count(&result);
+        Some(result)
+    }
+}
 
-        optional.unwrap()
+impl <I: ByteStream, O, const BN: usize, F: Framing, C: Check + Default> Decoder<I, O, BN, F, C>
+where O: MessageDecode + Default {
+    /// The next message, or why the next frame wasn't one. None at the end of the input.
+    pub async fn try_next(&mut self) -> Option<Result<O, CodecError>> {
+        let len = match self.payload().await? {
+            Ok(len) => len,
+            Err(err) => { return Some(Err(err)); },
+        };
+        let result = pbstream_framing::decode(&self.buffer[..len]).map_err(|_| CodecError::Decode);
+        self.counters.count(&result);
+        Some(result)
     }
 }
 
-impl <I: ByteStream, O, const BN: usize> Stream for Decoder<I, O, BN>
+/// Skips anything that isn't a message, after logging it.
+impl <I: ByteStream, O, const BN: usize, F: Framing, C: Check + Default> Stream for Decoder<I, O, BN, F, C>
 where O: MessageEncode + MessageDecode + Default {
     type Item = O;
 
     async fn next(&mut self) -> Option<Self::Item> {
         loop {
-            let size = self.frame().await;
-            debug!("message received {:x}", self.buffer[0..size]);
-            let mut request = Self::Item::default();
-            let mut pb = PbDecoder::new(self.buffer.as_slice());
-            match request.decode(&mut pb, size) {
-                Ok(()) => { return Some(request); },
-                Err(_) => {
-                    error!("pb decode {}", self.buffer);
+            match self.try_next().await? {
+                Ok(request) => { return Some(request); },
+                Err(err) => {
+                    error!("decoder: {}", err);
                 }
             }
         }
     }
 }
 
-pub struct Encoder<I, O, const BN: usize> {
+/// Encodes messages as frames, streaming each piece of the frame to the output as soon as it's
+/// ready. `BN` is only the size of the working buffer, not a limit on the message: a message
+/// that doesn't fit is encoded `BN` bytes at a time. `F` is the framing, and `C` the check,
+/// as for `Decoder`.
+///
+/// Each window after the first re-encodes the message from the start (see
+/// `pbstream_framing::encode_window`), so the work grows with the square of a message's size
+/// over `BN`. Make `BN` about the size of the usual message.
+pub struct Encoder<I, O, const BN: usize, F: Framing = Cobs, C: Check = Unchecked> {
     output: O,
+    buffer: [u8; BN],
+    frames: F::Encoder,
+    check: C,
+    counters: CodecCounters,
     input: PhantomData<I>,
 }
 
-struct PbCobsEncoder<'a>(CobsEncoder<'a>);
+impl <I, O, const BN: usize, F: Framing, C: Check + Default> Encoder<I, O, BN, F, C> {
+    pub fn new(output: O) -> Self {
+        Encoder {
+            output,
+            buffer: [0; BN],
+            frames: F::Encoder::default(),
+            check: C::default(),
+            counters: CodecCounters::new(),
+            input: PhantomData,
+        }
+    }
+
+    pub fn counters(&self) -> &CodecCounters {
+        &self.counters
+    }
+}
+
+impl <I, O: ByteSink, const BN: usize, F: Framing, C: Check> Sink for Encoder<I, O, BN, F, C>
+where I: MessageEncode {
+    type Item = I;
+    type Error = CodecError<O::Error>;
 
-impl <'a> PbWrite for PbCobsEncoder<'a> {
-    type Error = DestBufTooSmallError;
+    async fn send(&mut self, message: I) -> Result<(), Self::Error> {
+        let result = self.encode(message).await.map_err(CodecError::Io);
+        self.counters.count(&result);
+        result
+    }
+}
+
+impl <I: MessageEncode, O: ByteSink, const BN: usize, F: Framing, C: Check> Encoder<I, O, BN, F, C> {
+    async fn encode(&mut self, message: I) -> Result<(), O::Error> {
+        let size = message.compute_size();
+        // Anything left from a send that was cancelled part way through is lost
+        self.frames.start(size + C::TRAILER);
+        self.check.start();
 
-    fn pb_write(&mut self, data: &[u8]) -> Result<(), Self::Error> {
-        self.0.push(data)
+        let mut offset = 0;
+        while offset < size {
+            let len = pbstream_framing::encode_window(&message, offset, &mut self.buffer);
+            if len == 0 {
+                break;
+            }
+            debug!("sending response {:x}", self.buffer[..len]);
+            for byte in self.buffer[..len].iter() {
+                self.check.update(*byte);
+                if let Some(block) = self.frames.push(*byte) {
+                    self.output.write_all(block).await?;
+                }
+            }
+            offset += len;
+        }
+        for byte in self.check.trailer().as_slice() {
+            if let Some(block) = self.frames.push(*byte) {
+                self.output.write_all(block).await?;
+            }
+        }
+        self.output.write_all(self.frames.finish()).await
     }
 }
 
-impl <'a> PbCobsEncoder<'a> {
-    pub fn new(out_buf: &'a mut [u8]) -> Self {
-        PbCobsEncoder(CobsEncoder::new(out_buf))
+/// Decodes one message from each datagram. Datagrams are already framed, so there's no COBS.
+pub struct DatagramDecoder<I, O, const N: usize> {
+    input: I,
+    counters: CodecCounters,
+    target: PhantomData<O>,
+}
+
+impl <I: Stream<Item = Vec<u8, N>>, O, const N: usize> DatagramDecoder<I, O, N> {
+    pub fn new(input: I) -> Self {
+        DatagramDecoder { input, counters: CodecCounters::new(), target: PhantomData }
+    }
+
+    pub fn counters(&self) -> &CodecCounters {
+        &self.counters
     }
 }
 
-impl <I, O, const BN: usize> Encoder<I, O, BN> {
+impl <I: Stream<Item = Vec<u8, N>>, O, const N: usize> DatagramDecoder<I, O, N>
+where O: MessageDecode + Default {
+    /// The next message, or why the next datagram wasn't one. None at the end of the input.
+    pub async fn try_next(&mut self) -> Option<Result<O, CodecError>> {
+        let datagram = self.input.next().await?;
+        debug!("datagram received {:x}", datagram[..]);
+        let mut request = O::default();
+        let mut pb = PbDecoder::new(datagram.as_slice());
+        let result = request.decode(&mut pb, datagram.len())
+            .map(|()| request)
+            .map_err(|_| CodecError::Decode);
+        self.counters.count(&result);
+        Some(result)
+    }
+}
+
+/// Skips anything that isn't a message, after logging it.
+impl <I: Stream<Item = Vec<u8, N>>, O, const N: usize> Stream for DatagramDecoder<I, O, N>
+where O: MessageDecode + Default {
+    type Item = O;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.try_next().await? {
+                Ok(request) => { return Some(request); },
+                Err(err) => {
+                    error!("datagram decoder: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Encodes each message into a datagram of up to `N` bytes.
+pub struct DatagramEncoder<I, O, const N: usize> {
+    output: O,
+    counters: CodecCounters,
+    input: PhantomData<I>,
+}
+
+impl <I, O, const N: usize> DatagramEncoder<I, O, N> {
     pub fn new(output: O) -> Self {
-        Encoder { output, input: PhantomData::default() }
+        DatagramEncoder { output, counters: CodecCounters::new(), input: PhantomData }
+    }
+
+    pub fn counters(&self) -> &CodecCounters {
+        &self.counters
     }
 }
 
-impl <I, O: ByteSink, const BN: usize> Sink for Encoder<I, O, BN> 
+impl <I, O: Sink<Item = Vec<u8, N>>, const N: usize> Sink for DatagramEncoder<I, O, N>
 where I: MessageEncode {
     type Item = I;
-    type Error = O::Error;
+    type Error = CodecError<O::Error>;
 
-    async fn send(&mut self, message: I) -> Result<(), O::Error> {
-        let mut buffer: [u8;BN] = [0;BN];
-        let mut cobs = PbCobsEncoder::new(&mut buffer);
-        let mut encoder = PbEncoder::new(&mut cobs);
-        match message.encode(&mut encoder) {
+    async fn send(&mut self, message: I) -> Result<(), Self::Error> {
+        let mut datagram = Vec::new();
+        let mut encoder = PbEncoder::new(&mut datagram);
+        let result = match message.encode(&mut encoder) {
             Ok(()) => {
-                let size = cobs.0.finalize();
-                debug!("sending response {:x}", buffer[0..size]);
-                for data in buffer[0..size].iter() {
-                    self.output.send(*data).await?
-                }
-                self.output.send(0).await
+                debug!("sending datagram {:x}", datagram[..]);
+                self.output.send(datagram).await.map_err(CodecError::Io)
             },
-            Err(DestBufTooSmallError) => panic!("destination buffer too small")
-        }
+            Err(()) => Err(CodecError::EncodeOverflow),
+        };
+        self.counters.count(&result);
+        result
     }
 }
-
@@ -0,0 +1,337 @@
+
+use defmt::{ debug, info, warn };
+
+use smoltcp::{
+    iface::{ SocketHandle, SocketSet },
+    socket::udp,
+    time::Duration,
+    wire::{
+        DhcpMessageType, DhcpPacket, DhcpRepr, EthernetAddress, IpEndpoint, Ipv4Address,
+        Ipv4Cidr, DHCP_CLIENT_PORT, DHCP_SERVER_PORT,
+    },
+};
+
+use crate::usb::ConfigError;
+
+/// The largest DHCP message a client must accept (RFC 2131), so the smallest that is
+/// guaranteed to hold any request.
+pub const DHCP_MESSAGE_SIZE: usize = 576;
+
+pub const DHCP_LEASE_TIME: Duration = Duration::from_secs(3600);
+
+pub struct DhcpServerStorage {
+    rx_metadata: [udp::PacketMetadata; 1],
+    tx_metadata: [udp::PacketMetadata; 1],
+    rx_payload: [u8; DHCP_MESSAGE_SIZE],
+    tx_payload: [u8; DHCP_MESSAGE_SIZE],
+}
+
+impl DhcpServerStorage {
+    pub const fn new() -> Self {
+        Self {
+            rx_metadata: [udp::PacketMetadata::EMPTY; 1],
+            tx_metadata: [udp::PacketMetadata::EMPTY; 1],
+            rx_payload: [0; DHCP_MESSAGE_SIZE],
+            tx_payload: [0; DHCP_MESSAGE_SIZE],
+        }
+    }
+}
+
+impl Default for DhcpServerStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A minimal DHCPv4 server for the USB link. The link is point-to-point, so there is exactly
+/// one address to hand out: whoever asks for it gets it. No router or DNS servers are offered,
+/// so the host doesn't route anything but the gadget's subnet over USB.
+pub(crate) struct DhcpServer {
+    handle: SocketHandle,
+    address: Ipv4Cidr,
+    client: Ipv4Address,
+    lease: Option<EthernetAddress>,
+}
+
+impl DhcpServer {
+    pub fn new<'a>(
+        address: Ipv4Cidr,
+        sockets: &mut SocketSet<'a>,
+        storage: &'a mut DhcpServerStorage) -> Result<Self, ConfigError> {
+
+        let client = Self::client_address(address).ok_or(ConfigError::DhcpServerSubnet)?;
+        let rx_buffer = udp::PacketBuffer::new(&mut storage.rx_metadata[..], &mut storage.rx_payload[..]);
+        let tx_buffer = udp::PacketBuffer::new(&mut storage.tx_metadata[..], &mut storage.tx_payload[..]);
+        let mut socket = udp::Socket::new(rx_buffer, tx_buffer);
+        socket.bind(DHCP_SERVER_PORT).unwrap();
+        let handle = sockets.add(socket);
+
+        info!("DHCP server: {}, leasing {}", address, client);
+        Ok(DhcpServer { handle, address, client, lease: None })
+    }
+
+    /// The first host address in the subnet that isn't the gadget's, or None if the subnet
+    /// hasn't room for two hosts, or the gadget's address isn't a host address.
+    fn client_address(address: Ipv4Cidr) -> Option<Ipv4Address> {
+        if address.prefix_len() > 30 {
+            return None;
+        }
+        let network = address.network().address().to_bits();
+        let broadcast = network | !address.netmask().to_bits();
+        let gadget = address.address().to_bits();
+        if gadget == network || gadget == broadcast {
+            return None;
+        }
+        let first = network + 1;
+        Some(Ipv4Address::from_bits(if first == gadget { network + 2 } else { first }))
+    }
+
+    /// Answer any pending request. Returns true if a reply was queued.
+    pub fn poll(&mut self, sockets: &mut SocketSet<'_>) -> bool {
+        let socket = sockets.get_mut::<udp::Socket>(self.handle);
+        let mut replied = false;
+
+        while let Ok((data, _)) = socket.recv() {
+            let Ok(packet) = DhcpPacket::new_checked(data) else {
+                warn!("DHCP server: truncated packet");
+                continue;
+            };
+            let Ok(request) = DhcpRepr::parse(&packet) else {
+                warn!("DHCP server: malformed packet");
+                continue;
+            };
+
+            let Some(reply) = self.reply(&request) else { continue };
+            debug!("DHCP server: {} for {}", reply.message_type, request.client_hardware_address);
+
+            // A client without an address can't answer ARP, so it has to be broadcast to.
+            let destination = if request.client_ip.is_unspecified() {
+                Ipv4Address::BROADCAST
+            } else {
+                request.client_ip
+            };
+            let mut metadata = udp::UdpMetadata::from(IpEndpoint::new(destination.into(), DHCP_CLIENT_PORT));
+            metadata.local_address = Some(self.address.address().into());
+
+            match socket.send_with(reply.buffer_len(), metadata, |buffer| {
+                let mut packet = DhcpPacket::new_unchecked(buffer);
+                reply.emit(&mut packet).ok();
+                reply.buffer_len()
+            }) {
+                Ok(_) => replied = true,
+                Err(e) => warn!("DHCP server: failed to send reply: {}", e),
+            }
+        }
+
+        replied
+    }
+
+    /// The reply to `request`, if it needs one.
+    fn reply(&mut self, request: &DhcpRepr) -> Option<DhcpRepr<'static>> {
+        let (message_type, your_ip) = self.reply_to(request)?;
+        let lease = matches!(message_type, DhcpMessageType::Offer | DhcpMessageType::Ack)
+            && request.message_type != DhcpMessageType::Inform;
+        Some(DhcpRepr {
+            message_type,
+            transaction_id: request.transaction_id,
+            secs: 0,
+            client_hardware_address: request.client_hardware_address,
+            client_ip: request.client_ip,
+            your_ip,
+            server_ip: Ipv4Address::UNSPECIFIED,
+            router: None,
+            subnet_mask: lease.then(|| self.address.netmask()),
+            relay_agent_ip: request.relay_agent_ip,
+            broadcast: request.broadcast,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: Some(self.address.address()),
+            parameter_request_list: None,
+            dns_servers: None,
+            max_size: None,
+            lease_duration: lease.then(|| DHCP_LEASE_TIME.secs() as u32),
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        })
+    }
+
+    fn reply_to(&mut self, request: &DhcpRepr) -> Option<(DhcpMessageType, Ipv4Address)> {
+        let ours = request.server_identifier
+            .is_none_or(|server| server == self.address.address());
+
+        match request.message_type {
+            DhcpMessageType::Discover => Some((DhcpMessageType::Offer, self.client)),
+            DhcpMessageType::Request if ours => {
+                let requested = request.requested_ip.unwrap_or(request.client_ip);
+                if requested == self.client {
+                    if self.lease != Some(request.client_hardware_address) {
+                        info!("DHCP server: leased {} to {}", self.client, request.client_hardware_address);
+                        self.lease = Some(request.client_hardware_address);
+                    }
+                    Some((DhcpMessageType::Ack, self.client))
+                } else {
+                    Some((DhcpMessageType::Nak, Ipv4Address::UNSPECIFIED))
+                }
+            },
+            DhcpMessageType::Inform => Some((DhcpMessageType::Ack, Ipv4Address::UNSPECIFIED)),
+            DhcpMessageType::Release if ours => {
+                info!("DHCP server: {} released", request.client_hardware_address);
+                self.lease = None;
+                None
+            },
+            DhcpMessageType::Decline if ours => {
+                warn!("DHCP server: {} declined {}", request.client_hardware_address, self.client);
+                self.lease = None;
+                None
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 2]);
+
+    fn cidr(a: u8, b: u8, c: u8, d: u8, prefix: u8) -> Ipv4Cidr {
+        Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), prefix)
+    }
+
+    fn request(message_type: DhcpMessageType) -> DhcpRepr<'static> {
+        DhcpRepr {
+            message_type,
+            transaction_id: 0x1234,
+            secs: 0,
+            client_hardware_address: CLIENT,
+            client_ip: Ipv4Address::UNSPECIFIED,
+            your_ip: Ipv4Address::UNSPECIFIED,
+            server_ip: Ipv4Address::UNSPECIFIED,
+            router: None,
+            subnet_mask: None,
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            broadcast: true,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: None,
+            parameter_request_list: None,
+            dns_servers: None,
+            max_size: None,
+            lease_duration: None,
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        }
+    }
+
+    fn server(address: Ipv4Cidr, storage: &mut DhcpServerStorage) -> DhcpServer {
+        let mut sockets = SocketSet::new(vec![]);
+        DhcpServer::new(address, &mut sockets, storage).unwrap()
+    }
+
+    #[test]
+    fn client_address() {
+        assert_eq!(DhcpServer::client_address(cidr(10, 0, 0, 1, 30)), Some(Ipv4Address::new(10, 0, 0, 2)));
+        assert_eq!(DhcpServer::client_address(cidr(10, 0, 0, 2, 30)), Some(Ipv4Address::new(10, 0, 0, 1)));
+        assert_eq!(DhcpServer::client_address(cidr(192, 168, 7, 42, 24)), Some(Ipv4Address::new(192, 168, 7, 1)));
+        assert_eq!(DhcpServer::client_address(cidr(10, 0, 0, 1, 31)), None);
+        assert_eq!(DhcpServer::client_address(cidr(10, 0, 0, 1, 32)), None);
+        assert_eq!(DhcpServer::client_address(cidr(10, 0, 0, 0, 30)), None);
+        assert_eq!(DhcpServer::client_address(cidr(10, 0, 0, 3, 30)), None);
+    }
+
+    #[test]
+    fn rejects_a_small_subnet() {
+        let mut storage = DhcpServerStorage::new();
+        let mut sockets = SocketSet::new(vec![]);
+        let server = DhcpServer::new(cidr(10, 0, 0, 1, 31), &mut sockets, &mut storage);
+        assert_eq!(server.err(), Some(ConfigError::DhcpServerSubnet));
+    }
+
+    #[test]
+    fn offers_then_acks() {
+        let mut storage = DhcpServerStorage::new();
+        let mut server = server(cidr(10, 0, 0, 1, 30), &mut storage);
+
+        let offer = server.reply(&request(DhcpMessageType::Discover)).unwrap();
+        assert_eq!(offer.message_type, DhcpMessageType::Offer);
+        assert_eq!(offer.transaction_id, 0x1234);
+        assert_eq!(offer.your_ip, Ipv4Address::new(10, 0, 0, 2));
+        assert_eq!(offer.subnet_mask, Some(Ipv4Address::new(255, 255, 255, 252)));
+        assert_eq!(offer.server_identifier, Some(Ipv4Address::new(10, 0, 0, 1)));
+        assert_eq!(offer.lease_duration, Some(3600));
+        assert_eq!(offer.router, None);
+        assert_eq!(server.lease, None);
+
+        let ack = server.reply(&DhcpRepr {
+            requested_ip: Some(Ipv4Address::new(10, 0, 0, 2)),
+            server_identifier: Some(Ipv4Address::new(10, 0, 0, 1)),
+            ..request(DhcpMessageType::Request)
+        }).unwrap();
+        assert_eq!(ack.message_type, DhcpMessageType::Ack);
+        assert_eq!(ack.your_ip, Ipv4Address::new(10, 0, 0, 2));
+        assert_eq!(ack.lease_duration, Some(3600));
+        assert_eq!(server.lease, Some(CLIENT));
+
+        // The reply has to fit the socket's buffer
+        let mut buffer = [0; DHCP_MESSAGE_SIZE];
+        assert!(ack.buffer_len() <= buffer.len());
+        ack.emit(&mut DhcpPacket::new_unchecked(&mut buffer[..])).unwrap();
+        let parsed = DhcpPacket::new_checked(&buffer[..ack.buffer_len()]).unwrap();
+        assert_eq!(DhcpRepr::parse(&parsed).unwrap(), ack);
+    }
+
+    #[test]
+    fn naks_another_address() {
+        let mut storage = DhcpServerStorage::new();
+        let mut server = server(cidr(10, 0, 0, 1, 30), &mut storage);
+        let nak = server.reply(&DhcpRepr {
+            requested_ip: Some(Ipv4Address::new(192, 168, 0, 10)),
+            ..request(DhcpMessageType::Request)
+        }).unwrap();
+        assert_eq!(nak.message_type, DhcpMessageType::Nak);
+        assert_eq!(nak.your_ip, Ipv4Address::UNSPECIFIED);
+        assert_eq!(nak.lease_duration, None);
+        assert_eq!(server.lease, None);
+    }
+
+    #[test]
+    fn ignores_requests_for_another_server() {
+        let mut storage = DhcpServerStorage::new();
+        let mut server = server(cidr(10, 0, 0, 1, 30), &mut storage);
+        assert!(server.reply(&DhcpRepr {
+            requested_ip: Some(Ipv4Address::new(10, 0, 0, 2)),
+            server_identifier: Some(Ipv4Address::new(10, 0, 0, 9)),
+            ..request(DhcpMessageType::Request)
+        }).is_none());
+    }
+
+    #[test]
+    fn informs_without_a_lease() {
+        let mut storage = DhcpServerStorage::new();
+        let mut server = server(cidr(10, 0, 0, 1, 30), &mut storage);
+        let ack = server.reply(&DhcpRepr {
+            client_ip: Ipv4Address::new(10, 0, 0, 2),
+            ..request(DhcpMessageType::Inform)
+        }).unwrap();
+        assert_eq!(ack.message_type, DhcpMessageType::Ack);
+        assert_eq!(ack.your_ip, Ipv4Address::UNSPECIFIED);
+        assert_eq!(ack.subnet_mask, None);
+        assert_eq!(ack.lease_duration, None);
+    }
+
+    #[test]
+    fn release_ends_the_lease() {
+        let mut storage = DhcpServerStorage::new();
+        let mut server = server(cidr(10, 0, 0, 1, 30), &mut storage);
+        server.reply(&DhcpRepr {
+            requested_ip: Some(Ipv4Address::new(10, 0, 0, 2)),
+            ..request(DhcpMessageType::Request)
+        });
+        assert_eq!(server.lease, Some(CLIENT));
+        assert!(server.reply(&request(DhcpMessageType::Release)).is_none());
+        assert_eq!(server.lease, None);
+    }
+}
@@ -0,0 +1,102 @@
+
+pub use usb_device::LangID;
+
+/// The most languages a device can have strings in.
+pub const MAX_LANGUAGES: usize = 16;
+
+/// The strings describing a device in one language.
+#[derive(Clone, Copy)]
+pub struct UsbStrings<'a> {
+    pub language: LangID,
+    pub manufacturer: &'a str,
+    pub product: &'a str,
+}
+
+/// How the gadget describes itself to the host. Give each product its own VID/PID (pid.codes
+/// allocates them for open source hardware), and each gadget its own serial number.
+#[derive(Clone, Copy)]
+pub struct UsbIdentity<'a> {
+    pub vid: u16,
+    pub pid: u16,
+    /// The strings in each language. The host usually asks for the first.
+    pub strings: &'a [UsbStrings<'a>],
+    /// The same in every language. `SerialNumber` makes one from the chip's unique id.
+    pub serial_number: &'a str,
+    /// The device release, in BCD: 0x0102 is 1.2.
+    pub device_release: u16,
+    /// The most the gadget draws from the bus, up to 500mA.
+    pub max_power_ma: usize,
+    pub self_powered: bool,
+}
+
+impl <'a> UsbIdentity<'a> {
+    /// The pid.codes test PID, for development only.
+    pub const TEST: UsbIdentity<'static> = UsbIdentity {
+        vid: 0x1209,
+        pid: 0x0004,
+        strings: &[UsbStrings { language: LangID::EN_US, manufacturer: "none", product: "none" }],
+        serial_number: "aux",
+        device_release: 0x0010,
+        max_power_ma: 100,
+        self_powered: false,
+    };
+}
+
+impl Default for UsbIdentity<'_> {
+    fn default() -> Self {
+        UsbIdentity::TEST
+    }
+}
+
+/// A serial number made from a chip's unique id, in upper case hex. `N` is twice the length
+/// of the id: 24 for the 96 bit id of an STM32 or RP2040 flash.
+pub struct SerialNumber<const N: usize> {
+    hex: [u8; N],
+}
+
+impl <const N: usize> SerialNumber<N> {
+    pub const fn new() -> Self {
+        Self { hex: [b'0'; N] }
+    }
+
+    /// Panics if `unique_id` isn't `N / 2` bytes.
+    pub fn set(&mut self, unique_id: &[u8]) -> &str {
+        assert!(unique_id.len() * 2 == N, "a serial number is twice as long as the unique id");
+        const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+        for (pair, byte) in self.hex.chunks_exact_mut(2).zip(unique_id) {
+            pair[0] = DIGITS[(byte >> 4) as usize];
+            pair[1] = DIGITS[(byte & 0xf) as usize];
+        }
+        self.as_str()
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever hex digits
+        core::str::from_utf8(&self.hex).unwrap()
+    }
+}
+
+impl <const N: usize> Default for SerialNumber<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_number_is_upper_case_hex() {
+        let mut serial = SerialNumber::<8>::new();
+        assert_eq!(serial.as_str(), "00000000");
+        assert_eq!(serial.set(&[0x01, 0xab, 0x5f, 0xf0]), "01AB5FF0");
+        assert_eq!(serial.as_str(), "01AB5FF0");
+    }
+
+    #[test]
+    #[should_panic]
+    fn serial_number_of_the_wrong_length() {
+        SerialNumber::<8>::new().set(&[0x01, 0x02]);
+    }
+}
@@ -1,4 +1,26 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 pub mod stream;
 pub mod codec;
+pub mod rpc;
 pub mod usb;
+pub mod addressing;
+pub mod client;
+pub mod identity;
+pub mod mux;
+#[cfg(feature = "dhcp-server")]
+mod dhcp_server;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+#[cfg(feature = "net-logger")]
+pub mod logger;
+#[cfg(feature = "ipv6")]
+mod slaac;
+#[cfg(feature = "serial")]
+pub mod serial;
+#[cfg(feature = "udp")]
+pub mod udp;
+#[cfg(feature = "update")]
+pub mod update;
+mod snoop;
+#[cfg(test)]
+mod testing;
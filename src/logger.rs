@@ -0,0 +1,291 @@
+
+//! A defmt global logger that doesn't need a debug probe. Log frames are encoded into a ring
+//! buffer, and `forward` copies them to a channel, so a host can read them over the network
+//! with `defmt-print`.
+//!
+//! Frames have to be delimited for a reader to join part way through, or to skip a frame
+//! that didn't fit, so leave defmt's encoding as the default, rzcobs.
+//!
+//! Anything logged while forwarding is forwarded too: keep the log level for this crate's
+//! channel code above `debug`, or each line of log will log another.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{ AtomicBool, AtomicU32, Ordering },
+};
+
+use critical_section::RestoreState;
+
+use crate::stream::{
+    ring::{ Ring, RingConsumer, RingProducer },
+    ByteSink,
+};
+
+/// Bytes of log held for `forward`.
+pub const LOG_BUFFER: usize = 2048;
+
+static LOG: Ring<LOG_BUFFER> = Ring::new();
+
+static CONSUMER_TAKEN: AtomicBool = AtomicBool::new(false);
+
+static DROPPED: Counter = Counter(AtomicU32::new(0));
+
+/// The number of log frames that didn't fit in the buffer, and were dropped, since the last
+/// call.
+pub fn dropped() -> u32 {
+    DROPPED.take()
+}
+
+/// A count that can be taken without compare-and-swap.
+struct Counter(AtomicU32);
+
+impl Counter {
+    fn add(&self) {
+        critical_section::with(|_| {
+            self.0.store(self.0.load(Ordering::Relaxed).wrapping_add(1), Ordering::Relaxed);
+        });
+    }
+
+    fn take(&self) -> u32 {
+        critical_section::with(|_| {
+            let count = self.0.load(Ordering::Relaxed);
+            self.0.store(0, Ordering::Relaxed);
+            count
+        })
+    }
+}
+
+/// The encoded log frames. There's only one reader, so this panics if it's called twice.
+pub fn log_stream() -> RingConsumer<'static, LOG_BUFFER> {
+    critical_section::with(|_| {
+        if CONSUMER_TAKEN.load(Ordering::Relaxed) {
+            panic!("the log stream has already been taken");
+        }
+        CONSUMER_TAKEN.store(true, Ordering::Relaxed);
+    });
+    // SAFETY: this is the only consumer, and LOG is never split
+    unsafe { LOG.consumer() }
+}
+
+/// Copy the log to `output`, usually the `send` side of a channel's `ApplicationEndpoint`,
+/// for as long as it's open. Takes the log stream, so it can only run once.
+pub async fn forward<O: ByteSink>(output: &mut O) -> Result<(), O::Error> {
+    let mut log = log_stream();
+    let mut buffer = [0; 64];
+    loop {
+        let count = log.read(&mut buffer).await;
+        output.write_all(&buffer[..count]).await?;
+    }
+}
+
+#[defmt::global_logger]
+struct NetLogger;
+
+/// Puts encoded frames in a ring. A frame is only committed once all of it has been written,
+/// so one that doesn't fit is dropped whole, and the reader never sees part of it.
+struct FrameWriter {
+    /// Bytes of this frame written so far.
+    written: usize,
+    /// This frame didn't fit.
+    dropping: bool,
+}
+
+impl FrameWriter {
+    const fn new() -> Self {
+        Self { written: 0, dropping: false }
+    }
+
+    fn start(&mut self) {
+        self.written = 0;
+        self.dropping = false;
+    }
+
+    fn write<const N: usize>(&mut self, log: &mut RingProducer<'_, N>, bytes: &[u8]) {
+        if self.dropping {
+            return;
+        }
+        match log.stage(self.written, bytes) {
+            true => self.written += bytes.len(),
+            false => self.dropping = true,
+        }
+    }
+
+    /// Commit the frame, or count it as dropped.
+    fn end<const N: usize>(&mut self, log: &mut RingProducer<'_, N>, dropped: &Counter) {
+        match self.dropping {
+            true => dropped.add(),
+            false => log.commit(self.written),
+        }
+    }
+}
+
+/// Everything here is only touched inside the critical section held from `acquire` to
+/// `release`.
+struct State {
+    taken: UnsafeCell<bool>,
+    restore: UnsafeCell<RestoreState>,
+    encoder: UnsafeCell<defmt::Encoder>,
+    frame: UnsafeCell<FrameWriter>,
+}
+
+unsafe impl Sync for State {}
+
+static STATE: State = State {
+    taken: UnsafeCell::new(false),
+    restore: UnsafeCell::new(RestoreState::invalid()),
+    encoder: UnsafeCell::new(defmt::Encoder::new()),
+    frame: UnsafeCell::new(FrameWriter::new()),
+};
+
+/// Write encoded bytes into the frame.
+///
+/// # Safety
+/// Only inside the logger's critical section.
+unsafe fn write_encoded(bytes: &[u8]) {
+    // SAFETY: the critical section makes this the only producer
+    (*STATE.frame.get()).write(&mut LOG.producer(), bytes);
+}
+
+unsafe impl defmt::Logger for NetLogger {
+    fn acquire() {
+        // SAFETY: released in `release`, which defmt always calls after `acquire`
+        let restore = unsafe { critical_section::acquire() };
+        // SAFETY: inside the critical section
+        unsafe {
+            if *STATE.taken.get() {
+                panic!("defmt logger taken reentrantly");
+            }
+            *STATE.taken.get() = true;
+            *STATE.restore.get() = restore;
+            (*STATE.frame.get()).start();
+            (*STATE.encoder.get()).start_frame(|bytes| write_encoded(bytes));
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        (*STATE.encoder.get()).end_frame(|bytes| write_encoded(bytes));
+        (*STATE.frame.get()).end(&mut LOG.producer(), &DROPPED);
+        *STATE.taken.get() = false;
+        critical_section::release(*STATE.restore.get());
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        (*STATE.encoder.get()).write(bytes, |bytes| write_encoded(bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// The logger's encoding, into a ring of the test's own.
+    struct TestLogger<'a, const N: usize> {
+        encoder: defmt::Encoder,
+        frame: FrameWriter,
+        log: RingProducer<'a, N>,
+        dropped: Counter,
+    }
+
+    impl <'a, const N: usize> TestLogger<'a, N> {
+        fn new(log: RingProducer<'a, N>) -> Self {
+            TestLogger {
+                encoder: defmt::Encoder::new(),
+                frame: FrameWriter::new(),
+                log,
+                dropped: Counter(AtomicU32::new(0)),
+            }
+        }
+
+        fn log(&mut self, data: &[u8]) {
+            let TestLogger { encoder, frame, log, dropped } = self;
+            frame.start();
+            encoder.start_frame(|bytes| frame.write(log, bytes));
+            encoder.write(data, |bytes| frame.write(log, bytes));
+            encoder.end_frame(|bytes| frame.write(log, bytes));
+            frame.end(log, dropped);
+        }
+    }
+
+    /// `data` encoded as a frame on its own.
+    fn encoded(data: &[u8]) -> Vec<u8> {
+        let mut encoder = defmt::Encoder::new();
+        let mut frame = Vec::new();
+        encoder.start_frame(|_| {});
+        encoder.write(data, |bytes| frame.extend_from_slice(bytes));
+        encoder.end_frame(|bytes| frame.extend_from_slice(bytes));
+        frame
+    }
+
+    /// The frames read from `log`, without the separator the encoder starts with.
+    fn frames<const N: usize>(log: &mut RingConsumer<'_, N>) -> Vec<Vec<u8>> {
+        let mut buf = [0; N];
+        let count = log.try_read(&mut buf);
+        buf[..count].split_inclusive(|&byte| byte == 0)
+            .filter(|frame| frame.len() > 1)
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    #[test]
+    fn frames_end_with_a_delimiter() {
+        let mut ring = Ring::<64>::new();
+        let (producer, mut consumer) = ring.split();
+        let mut logger = TestLogger::new(producer);
+        logger.log(&[1, 2, 3]);
+        logger.log(&[0, 0, 4]);
+
+        let frames = frames(&mut consumer);
+        assert_eq!(frames.len(), 2);
+        for frame in frames {
+            assert_eq!(frame.last(), Some(&0));
+            assert!(!frame[..frame.len() - 1].contains(&0));
+        }
+        assert_eq!(logger.dropped.take(), 0);
+    }
+
+    #[test]
+    fn drops_whole_frames_that_dont_fit() {
+        let mut ring = Ring::<32>::new();
+        let (producer, mut consumer) = ring.split();
+        let mut logger = TestLogger::new(producer);
+        logger.log(&[1; 8]);
+        let free = logger.log.free();
+
+        // Part of this one would fit, but none of it's written
+        logger.log(&[2; 28]);
+        assert_eq!(logger.log.free(), free);
+        logger.log(&[3; 8]);
+        let frames = frames(&mut consumer);
+        assert_eq!(frames, [encoded(&[1; 8]), encoded(&[3; 8])]);
+
+        // Reported once
+        assert_eq!(logger.dropped.take(), 1);
+        assert_eq!(logger.dropped.take(), 0);
+    }
+
+    #[test]
+    fn frames_wrap_around_the_ring() {
+        let mut ring = Ring::<32>::new();
+        let (producer, mut consumer) = ring.split();
+        let mut logger = TestLogger::new(producer);
+        logger.log(&[]);
+        frames(&mut consumer);
+        // Each lands somewhere else relative to the end of the buffer
+        for data in 1..20 {
+            logger.log(&[data; 11]);
+            assert_eq!(frames(&mut consumer), [encoded(&[data; 11])]);
+        }
+        assert_eq!(logger.dropped.take(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "already been taken")]
+    fn the_log_stream_can_only_be_taken_once() {
+        let _log = log_stream();
+        log_stream();
+    }
+}
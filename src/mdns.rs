@@ -0,0 +1,545 @@
+
+use defmt::{ debug, info, warn };
+
+use smoltcp::{
+    iface::{ SocketHandle, SocketSet },
+    socket::udp,
+    time::{ Duration, Instant },
+    wire::{ IpEndpoint, Ipv4Address },
+};
+
+pub const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_MESSAGE_SIZE: usize = 512;
+pub const MDNS_SERVICES: usize = 8;
+
+const SERVICE_TYPE: [&[u8]; 3] = [b"_pbstream", b"_tcp", b"local"];
+const SERVICES_META: [&[u8]; 4] = [b"_services", b"_dns-sd", b"_udp", b"local"];
+const LOCAL: &[u8] = b"local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+// RFC 6762 section 10
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+
+const ANSWERS: usize = 2 + MDNS_SERVICES;
+
+const ANNOUNCE_NUM: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A TCP service advertised over DNS-SD, as `<instance>._pbstream._tcp.local`.
+/// The TXT strings are `key=value` pairs, like `type=example.Request`.
+#[derive(Clone, Copy)]
+pub struct Service {
+    pub instance: &'static str,
+    pub port: u16,
+    pub txt: &'static [&'static str],
+}
+
+pub struct MdnsStorage {
+    rx_metadata: [udp::PacketMetadata; 2],
+    tx_metadata: [udp::PacketMetadata; 2],
+    rx_payload: [u8; MDNS_MESSAGE_SIZE],
+    tx_payload: [u8; 2 * MDNS_MESSAGE_SIZE],
+}
+
+impl MdnsStorage {
+    pub const fn new() -> Self {
+        Self {
+            rx_metadata: [udp::PacketMetadata::EMPTY; 2],
+            tx_metadata: [udp::PacketMetadata::EMPTY; 2],
+            rx_payload: [0; MDNS_MESSAGE_SIZE],
+            tx_payload: [0; 2 * MDNS_MESSAGE_SIZE],
+        }
+    }
+}
+
+impl Default for MdnsStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The records a response will carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    Host,
+    ServiceTypes,
+    Instances,
+    Instance(usize),
+}
+
+/// A multicast DNS responder for `<name>.local`, and DNS-SD for the registered services.
+/// Only multicast queries are answered, and answers are always multicast.
+pub(crate) struct Mdns {
+    handle: SocketHandle,
+    name: &'static [u8],
+    services: [Option<Service>; MDNS_SERVICES],
+    address: Option<Ipv4Address>,
+    announce: u8,
+    announce_at: Instant,
+}
+
+impl Mdns {
+    pub fn new<'a>(
+        name: &'static [u8],
+        sockets: &mut SocketSet<'a>,
+        storage: &'a mut MdnsStorage) -> Self {
+
+        let rx_buffer = udp::PacketBuffer::new(&mut storage.rx_metadata[..], &mut storage.rx_payload[..]);
+        let tx_buffer = udp::PacketBuffer::new(&mut storage.tx_metadata[..], &mut storage.tx_payload[..]);
+        let mut socket = udp::Socket::new(rx_buffer, tx_buffer);
+        socket.bind(MDNS_PORT).unwrap();
+        let handle = sockets.add(socket);
+
+        Mdns {
+            handle,
+            name,
+            services: [None; MDNS_SERVICES],
+            address: None,
+            announce: 0,
+            announce_at: Instant::ZERO,
+        }
+    }
+
+    pub fn advertise(&mut self, service: Service) -> Result<(), Service> {
+        match self.services.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                info!("mDNS: advertising {} on port {}", service.instance, service.port);
+                *slot = Some(service);
+                self.announce = ANNOUNCE_NUM;
+                Ok(())
+            },
+            None => Err(service),
+        }
+    }
+
+    /// When the next announcement is due. Queries are answered when they arrive.
+    pub fn poll_at(&self) -> Option<Instant> {
+        (self.announce > 0 && self.address.is_some()).then_some(self.announce_at)
+    }
+
+    /// Answer queries, and announce the records when the address changes.
+    /// Returns true if anything was sent.
+    pub fn poll(&mut self, now: Instant, sockets: &mut SocketSet<'_>, address: Option<Ipv4Address>) -> bool {
+        if address != self.address {
+            self.address = address;
+            self.announce = ANNOUNCE_NUM;
+            self.announce_at = now;
+        }
+
+        let Some(address) = address else { return false };
+        let socket = sockets.get_mut::<udp::Socket>(self.handle);
+        let mut sent = false;
+
+        while let Ok((query, metadata)) = socket.recv() {
+            if metadata.local_address != Some(MDNS_GROUP.into()) {
+                debug!("mDNS: ignoring unicast query from {}", metadata.endpoint);
+                continue;
+            }
+            let mut answers = [None; ANSWERS];
+            let count = self.questions(query, &mut answers);
+            if count > 0 {
+                sent |= Self::send(socket, address, |message| self.response(message, address, &answers[..count]));
+            }
+        }
+
+        if self.announce > 0 && now >= self.announce_at {
+            debug!("mDNS: announcing {}", address);
+            let mut answers = [None; ANSWERS];
+            answers[0] = Some(Answer::Host);
+            answers[1] = Some(Answer::Instances);
+            for (index, _) in self.services.iter().enumerate().filter(|(_, s)| s.is_some()) {
+                answers[2 + index] = Some(Answer::Instance(index));
+            }
+            sent |= Self::send(socket, address, |message| self.response(message, address, &answers));
+            self.announce -= 1;
+            self.announce_at = now + ANNOUNCE_INTERVAL;
+        }
+
+        sent
+    }
+
+    fn send<F>(socket: &mut udp::Socket<'_>, address: Ipv4Address, f: F) -> bool
+    where F: FnOnce(&mut [u8]) -> usize {
+        let mut metadata = udp::UdpMetadata::from(IpEndpoint::new(MDNS_GROUP.into(), MDNS_PORT));
+        metadata.local_address = Some(address.into());
+        match socket.send_with(MDNS_MESSAGE_SIZE, metadata, f) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("mDNS: failed to send response: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Collect the answers to the questions in a query. Returns the number of answers.
+    fn questions(&self, query: &[u8], answers: &mut [Option<Answer>]) -> usize {
+        if query.len() < 12 || query[2] & 0x80 != 0 {
+            // Too short, or a response
+            return 0;
+        }
+        let questions = u16::from_be_bytes([query[4], query[5]]);
+        let mut offset = 12;
+        let mut count = 0;
+
+        for _ in 0..questions {
+            let Some((name, next)) = Name::parse(query, offset) else { return count };
+            let Some(fields) = query.get(next..next + 4) else { return count };
+            let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+            // In a question, the top bit of the class asks for a unicast response
+            let qclass = u16::from_be_bytes([fields[2], fields[3]]) & !CLASS_CACHE_FLUSH;
+            offset = next + 4;
+
+            if qclass != CLASS_IN && qclass != CLASS_ANY {
+                continue;
+            }
+            let answer = self.answer(query, &name, qtype);
+            if let Some(answer) = answer {
+                if count < answers.len() && !answers[..count].contains(&Some(answer)) {
+                    answers[count] = Some(answer);
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    fn answer(&self, query: &[u8], name: &Name, qtype: u16) -> Option<Answer> {
+        let any = qtype == TYPE_ANY;
+        if (qtype == TYPE_A || any) && name.matches(query, &[self.name, LOCAL]) {
+            Some(Answer::Host)
+        } else if (qtype == TYPE_PTR || any) && name.matches(query, &SERVICES_META) {
+            Some(Answer::ServiceTypes)
+        } else if (qtype == TYPE_PTR || any) && name.matches(query, &SERVICE_TYPE) {
+            self.services.iter().any(Option::is_some).then_some(Answer::Instances)
+        } else if qtype == TYPE_SRV || qtype == TYPE_TXT || any {
+            self.services.iter()
+                .position(|service| service.is_some_and(|service| {
+                    name.matches(query, &Self::instance_name(&service))
+                }))
+                .map(Answer::Instance)
+        } else {
+            None
+        }
+    }
+
+    fn instance_name(service: &Service) -> [&[u8]; 4] {
+        [service.instance.as_bytes(), SERVICE_TYPE[0], SERVICE_TYPE[1], SERVICE_TYPE[2]]
+    }
+
+    fn response(&self, message: &mut [u8], address: Ipv4Address, answers: &[Option<Answer>]) -> usize {
+        let mut writer = Writer { buffer: message, position: 12, records: 0 };
+        for answer in answers.iter().flatten() {
+            match *answer {
+                Answer::Host => self.host_record(&mut writer, address),
+                Answer::ServiceTypes => {
+                    writer.record(&SERVICES_META, TYPE_PTR, CLASS_IN, SERVICE_TTL, |w| w.name(&SERVICE_TYPE));
+                },
+                Answer::Instances => {
+                    for service in self.services.iter().flatten() {
+                        writer.record(&SERVICE_TYPE, TYPE_PTR, CLASS_IN, SERVICE_TTL, |w| {
+                            w.name(&Self::instance_name(service))
+                        });
+                    }
+                },
+                Answer::Instance(index) => {
+                    if let Some(service) = &self.services[index] {
+                        self.instance_records(&mut writer, service);
+                    }
+                },
+            }
+        }
+        // Save the browser a round trip: a PTR answer is no use without the SRV, TXT and A
+        // records it leads to.
+        if answers.contains(&Some(Answer::Instances)) {
+            for (index, service) in self.services.iter().enumerate() {
+                if let Some(service) = service.filter(|_| !answers.contains(&Some(Answer::Instance(index)))) {
+                    self.instance_records(&mut writer, &service);
+                }
+            }
+        }
+        if !answers.contains(&Some(Answer::Host)) {
+            self.host_record(&mut writer, address);
+        }
+
+        let records = writer.records;
+        let length = writer.position;
+        message[0..12].fill(0);
+        message[2..4].copy_from_slice(&FLAGS_RESPONSE.to_be_bytes());
+        message[6..8].copy_from_slice(&records.to_be_bytes());
+        length
+    }
+
+    fn host_record(&self, writer: &mut Writer, address: Ipv4Address) {
+        writer.record(&[self.name, LOCAL], TYPE_A, CLASS_IN | CLASS_CACHE_FLUSH, HOST_TTL, |w| {
+            w.bytes(&address.octets())
+        });
+    }
+
+    fn instance_records(&self, writer: &mut Writer, service: &Service) {
+        let name = Self::instance_name(service);
+        writer.record(&name, TYPE_SRV, CLASS_IN | CLASS_CACHE_FLUSH, HOST_TTL, |w| {
+            w.bytes(&0u16.to_be_bytes()); // priority
+            w.bytes(&0u16.to_be_bytes()); // weight
+            w.bytes(&service.port.to_be_bytes());
+            w.name(&[self.name, LOCAL]);
+        });
+        writer.record(&name, TYPE_TXT, CLASS_IN | CLASS_CACHE_FLUSH, SERVICE_TTL, |w| {
+            if service.txt.is_empty() {
+                w.bytes(&[0]);
+            }
+            for entry in service.txt {
+                let entry = &entry.as_bytes()[..entry.len().min(255)];
+                w.bytes(&[entry.len() as u8]);
+                w.bytes(entry);
+            }
+        });
+    }
+}
+
+/// Writes resource records, dropping any record that doesn't fit.
+struct Writer<'b> {
+    buffer: &'b mut [u8],
+    position: usize,
+    records: u16,
+}
+
+impl Writer<'_> {
+    fn record<F: FnOnce(&mut Self)>(&mut self, name: &[&[u8]], rtype: u16, class: u16, ttl: u32, data: F) {
+        let start = self.position;
+        self.name(name);
+        self.bytes(&rtype.to_be_bytes());
+        self.bytes(&class.to_be_bytes());
+        self.bytes(&ttl.to_be_bytes());
+        let length = self.position;
+        self.bytes(&[0, 0]);
+        data(self);
+
+        if self.position > self.buffer.len() {
+            warn!("mDNS: response too long, dropping records");
+            self.position = start;
+            return;
+        }
+        let rdlength = (self.position - length - 2) as u16;
+        self.buffer[length..length + 2].copy_from_slice(&rdlength.to_be_bytes());
+        self.records += 1;
+    }
+
+    fn name(&mut self, labels: &[&[u8]]) {
+        for label in labels {
+            let label = &label[..label.len().min(63)];
+            self.bytes(&[label.len() as u8]);
+            self.bytes(label);
+        }
+        self.bytes(&[0]);
+    }
+
+    // Past the end of the buffer, only the position moves, so the record can be dropped.
+    fn bytes(&mut self, data: &[u8]) {
+        if let Some(target) = self.buffer.get_mut(self.position..self.position + data.len()) {
+            target.copy_from_slice(data);
+        }
+        self.position += data.len();
+    }
+}
+
+const MAX_LABELS: usize = 4;
+
+/// A name in a received message, as the offsets of up to `MAX_LABELS` labels.
+struct Name {
+    labels: [(usize, usize); MAX_LABELS],
+    count: usize,
+    /// It had more than `MAX_LABELS` labels, so it can't match anything we answer for.
+    long: bool,
+}
+
+impl Name {
+    /// Parse the name at `offset`, following compression pointers. Returns the name and the
+    /// offset of whatever follows it, or None if the message is malformed.
+    fn parse(message: &[u8], mut offset: usize) -> Option<(Name, usize)> {
+        let mut name = Name { labels: [(0, 0); MAX_LABELS], count: 0, long: false };
+        let mut next = None;
+        for _ in 0..128 {
+            let length = *message.get(offset)? as usize;
+            match length {
+                0 => return Some((name, next.unwrap_or(offset + 1))),
+                0xc0.. => {
+                    let pointer = ((length & 0x3f) << 8) | *message.get(offset + 1)? as usize;
+                    next.get_or_insert(offset + 2);
+                    offset = pointer;
+                },
+                1..=63 => {
+                    message.get(offset + 1..offset + 1 + length)?;
+                    if name.count == MAX_LABELS {
+                        name.long = true;
+                    } else {
+                        name.labels[name.count] = (offset + 1, length);
+                        name.count += 1;
+                    }
+                    offset += 1 + length;
+                },
+                _ => return None,
+            }
+        }
+        // A pointer loop
+        None
+    }
+
+    fn matches(&self, message: &[u8], labels: &[&[u8]]) -> bool {
+        !self.long
+            && self.count == labels.len()
+            && self.labels[..self.count].iter().zip(labels).all(|(&(offset, length), label)| {
+                message[offset..offset + length].eq_ignore_ascii_case(label)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &[u8] = b"gadget";
+
+    const SERVICE: Service = Service { instance: "sensor", port: 1234, txt: &["type=example.Request"] };
+
+    /// A query's header, for `questions` questions.
+    fn query(questions: u16) -> Vec<u8> {
+        let mut query = vec![0; 12];
+        query[4..6].copy_from_slice(&questions.to_be_bytes());
+        query
+    }
+
+    fn name(query: &mut Vec<u8>, labels: &[&[u8]]) {
+        for label in labels {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label);
+        }
+        query.push(0);
+    }
+
+    fn fields(query: &mut Vec<u8>, qtype: u16, qclass: u16) {
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&qclass.to_be_bytes());
+    }
+
+    fn answers(query: &[u8]) -> Vec<Answer> {
+        let mut storage = MdnsStorage::new();
+        let mut sockets = SocketSet::new(vec![]);
+        let mut mdns = Mdns::new(NAME, &mut sockets, &mut storage);
+        assert!(mdns.advertise(SERVICE).is_ok());
+        let mut answers = [None; ANSWERS];
+        let count = mdns.questions(query, &mut answers);
+        answers[..count].iter().map(|answer| answer.unwrap()).collect()
+    }
+
+    #[test]
+    fn several_questions() {
+        let mut query = query(3);
+        name(&mut query, &[NAME, LOCAL]);
+        fields(&mut query, TYPE_A, CLASS_IN);
+        name(&mut query, &SERVICE_TYPE);
+        fields(&mut query, TYPE_PTR, CLASS_IN | CLASS_CACHE_FLUSH);
+        name(&mut query, &[b"SENSOR", b"_pbstream", b"_TCP", b"local"]);
+        fields(&mut query, TYPE_SRV, CLASS_ANY);
+        assert_eq!(answers(&query), [Answer::Host, Answer::Instances, Answer::Instance(0)]);
+    }
+
+    #[test]
+    fn follows_compression_pointers() {
+        let mut query = query(2);
+        name(&mut query, &SERVICE_TYPE);
+        fields(&mut query, TYPE_PTR, CLASS_IN);
+        // "gadget" then a pointer to "local" in the first name
+        let local = 12 + 1 + 9 + 1 + 4;
+        assert_eq!(query[local..local + 6], *b"\x05local");
+        query.push(6);
+        query.extend_from_slice(NAME);
+        query.extend_from_slice(&[0xc0, local as u8]);
+        fields(&mut query, TYPE_A, CLASS_IN);
+        assert_eq!(answers(&query), [Answer::Instances, Answer::Host]);
+    }
+
+    #[test]
+    fn skips_long_names() {
+        let mut query = query(2);
+        name(&mut query, &[b"a", b"b", b"c", b"d", NAME, LOCAL]);
+        fields(&mut query, TYPE_A, CLASS_IN);
+        name(&mut query, &[NAME, LOCAL]);
+        fields(&mut query, TYPE_A, CLASS_IN);
+        assert_eq!(answers(&query), [Answer::Host]);
+
+        let (name, next) = Name::parse(&query, 12).unwrap();
+        assert!(name.long);
+        assert!(!name.matches(&query, &[b"a", b"b", b"c", b"d"]));
+        assert_eq!(next, 12 + 2 + 2 + 2 + 2 + 7 + 6 + 1);
+    }
+
+    #[test]
+    fn stops_at_a_pointer_loop() {
+        let mut query = query(2);
+        name(&mut query, &[NAME, LOCAL]);
+        fields(&mut query, TYPE_A, CLASS_IN);
+        let at = query.len();
+        query.extend_from_slice(&[0xc0, at as u8]);
+        fields(&mut query, TYPE_PTR, CLASS_IN);
+        assert!(Name::parse(&query, at).is_none());
+        assert_eq!(answers(&query), [Answer::Host]);
+    }
+
+    #[test]
+    fn stops_at_the_end_of_a_truncated_query() {
+        let mut full = query(2);
+        name(&mut full, &[NAME, LOCAL]);
+        fields(&mut full, TYPE_A, CLASS_IN);
+        let first = full.len();
+        name(&mut full, &SERVICE_TYPE);
+        fields(&mut full, TYPE_PTR, CLASS_IN);
+
+        for end in 0..full.len() {
+            let expected: &[Answer] = if end >= first { &[Answer::Host] } else { &[] };
+            assert_eq!(answers(&full[..end]), expected, "truncated to {}", end);
+        }
+        assert_eq!(answers(&full), [Answer::Host, Answer::Instances]);
+        // A pointer past the end
+        assert!(Name::parse(&[0xc0, 0xff], 0).is_none());
+    }
+
+    #[test]
+    fn ignores_responses_and_other_classes() {
+        let mut query = query(1);
+        name(&mut query, &[NAME, LOCAL]);
+        fields(&mut query, TYPE_A, 3);
+        assert!(answers(&query).is_empty());
+        query.truncate(query.len() - 4);
+        fields(&mut query, TYPE_A, CLASS_IN);
+        assert_eq!(answers(&query), [Answer::Host]);
+        query[2] |= 0x80;
+        assert!(answers(&query).is_empty());
+    }
+
+    #[test]
+    fn answers_with_the_host_record() {
+        let mut storage = MdnsStorage::new();
+        let mut sockets = SocketSet::new(vec![]);
+        let mdns = Mdns::new(NAME, &mut sockets, &mut storage);
+        let mut message = [0; MDNS_MESSAGE_SIZE];
+        let length = mdns.response(&mut message, Ipv4Address::new(10, 0, 0, 1), &[Some(Answer::Host)]);
+
+        let mut expected = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        name(&mut expected, &[NAME, LOCAL]);
+        fields(&mut expected, TYPE_A, CLASS_IN | CLASS_CACHE_FLUSH);
+        expected.extend_from_slice(&HOST_TTL.to_be_bytes());
+        expected.extend_from_slice(&[0, 4, 10, 0, 0, 1]);
+        assert_eq!(message[..length], expected[..]);
+    }
+}
@@ -0,0 +1,367 @@
+
+//! Several logical byte streams over one connection, so a single channel (one port, one
+//! `NetworkChannelStorage`) can carry control, telemetry and logs side by side.
+//!
+//! Each frame starts with a header byte. Below `GRANT` it's the stream id, and the rest of
+//! the frame is the next chunk of that stream, up to `MUX_CHUNK` bytes. With `GRANT` set, the
+//! low bits are the stream id and the rest is a little endian `u32`: the number of bytes
+//! the sender of the grant will accept on that stream, on top of any it has already granted.
+//! Each side starts with no credit at all, and grants as much as it has room for, so one
+//! stream that isn't read can't block the others.
+
+use core::{
+    cell::{ Cell, RefCell },
+    future::poll_fn,
+    marker::PhantomData,
+    pin::pin,
+    task::{ Poll, Waker },
+};
+
+use defmt::{ debug, warn };
+use futures::future::{ select, Either };
+use micropb::heapless::Vec;
+use pbstream_framing::{ Cobs, FrameDecode, FrameEncode, Framing };
+use rtic_common::waker_registration::CriticalSectionWakerRegistration;
+
+use crate::stream::{
+    ring::{ Ring, RingConsumer, RingProducer },
+    ByteSink, ByteStream,
+};
+
+/// The most bytes of a stream in one frame.
+pub const MUX_CHUNK: usize = 256;
+
+/// Marks a frame as a grant of credit.
+pub const GRANT: u8 = 0x80;
+
+/// Storage for `S` streams, each with `N` byte buffers in each direction.
+pub struct MuxStorage<const S: usize, const N: usize> {
+    rx: [Ring<N>; S],
+    tx: [Ring<N>; S],
+}
+
+impl <const S: usize, const N: usize> MuxStorage<S, N> {
+    pub const fn new() -> Self {
+        Self {
+            rx: [const { Ring::new() }; S],
+            tx: [const { Ring::new() }; S],
+        }
+    }
+}
+
+impl <const S: usize, const N: usize> Default for MuxStorage<S, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The application's end of one stream, for a `codec::Decoder` and `Encoder`.
+pub struct MuxChannel<'a, const N: usize> {
+    pub send: RingProducer<'a, N>,
+    pub recv: RingConsumer<'a, N>,
+}
+
+/// Carries `S` streams over one connection, framed with `F`.
+pub struct Mux<'a, const S: usize, const N: usize, F: Framing = Cobs> {
+    rx: [RefCell<RingProducer<'a, N>>; S],
+    tx: [RefCell<RingConsumer<'a, N>>; S],
+    /// Bytes the peer will accept on each stream.
+    credits: [Cell<usize>; S],
+    /// Bytes the peer may still send on each stream.
+    granted: [Cell<usize>; S],
+    /// The stream to send from first next time, so they take turns.
+    next: Cell<usize>,
+    sender: CriticalSectionWakerRegistration,
+    framing: PhantomData<F>,
+}
+
+impl <'a, const S: usize, const N: usize, F: Framing> Mux<'a, S, N, F> {
+    pub fn new(storage: &'a mut MuxStorage<S, N>) -> (Self, [MuxChannel<'a, N>; S]) {
+        assert!(S <= GRANT as usize, "a mux carries at most 128 streams");
+
+        let mut rx = Vec::<_, S>::new();
+        let mut tx = Vec::<_, S>::new();
+        let mut channels = Vec::<_, S>::new();
+        for (rx_ring, tx_ring) in storage.rx.iter_mut().zip(storage.tx.iter_mut()) {
+            let (net_send, app_recv) = rx_ring.split();
+            let (app_send, net_recv) = tx_ring.split();
+            rx.push(RefCell::new(net_send)).ok();
+            tx.push(RefCell::new(net_recv)).ok();
+            channels.push(MuxChannel { send: app_send, recv: app_recv }).ok();
+        }
+
+        let mux = Mux {
+            rx: rx.into_array().ok().unwrap(),
+            tx: tx.into_array().ok().unwrap(),
+            credits: [const { Cell::new(0) }; S],
+            granted: [const { Cell::new(0) }; S],
+            next: Cell::new(0),
+            sender: CriticalSectionWakerRegistration::new(),
+            framing: PhantomData,
+        };
+        (mux, channels.into_array().ok().unwrap())
+    }
+
+    /// Carry the streams over a connection until its input ends. Run it afresh for each
+    /// connection, and drop it when the connection goes: anything left over from the last
+    /// one is thrown away, and the credit starts again from nothing.
+    pub async fn run<I: ByteStream, O: ByteSink>(&self, input: &mut I, output: &mut O) -> Result<(), O::Error> {
+        self.reset();
+        match select(pin!(self.receive(input)), pin!(self.send(output))).await {
+            Either::Left(((), _)) => Ok(()),
+            Either::Right((result, _)) => result,
+        }
+    }
+
+    fn reset(&self) {
+        for stream in 0..S {
+            self.credits[stream].set(0);
+            self.granted[stream].set(0);
+            // A ring that's fenced has no room to grant until the application next reads,
+            // so only fence one with something left in it
+            let mut rx = self.rx[stream].borrow_mut();
+            if rx.free() < N {
+                rx.fence();
+            }
+            self.tx[stream].borrow_mut().clear();
+        }
+    }
+
+    async fn receive<I: ByteStream>(&self, input: &mut I) {
+        let mut decoder = F::Decoder::default();
+        let mut frame = [0; MUX_CHUNK + 1];
+        let mut pending = [0; 32];
+        loop {
+            let count = input.read(&mut pending).await;
+            if count == 0 {
+                return;
+            }
+            for byte in pending[..count].iter() {
+                match decoder.feed(&mut frame, *byte) {
+                    Ok(None) => {},
+                    Ok(Some(len)) => self.dispatch(&frame[..len]),
+                    Err(err) => warn!("mux: {}", err),
+                }
+            }
+        }
+    }
+
+    fn dispatch(&self, frame: &[u8]) {
+        let Some((&header, body)) = frame.split_first() else {
+            return;
+        };
+        let stream = (header & !GRANT) as usize;
+        if stream >= S {
+            warn!("mux: no stream {}", stream);
+            return;
+        }
+
+        if header & GRANT != 0 {
+            match body.try_into() {
+                Ok(credit) => {
+                    let credit = u32::from_le_bytes(credit) as usize;
+                    debug!("mux: {} bytes granted on stream {}", credit, stream);
+                    self.credits[stream].set(self.credits[stream].get() + credit);
+                    self.sender.wake();
+                },
+                Err(_) => warn!("mux: bad grant {:x}", frame),
+            }
+        } else {
+            let written = self.rx[stream].borrow_mut().try_write(body);
+            self.granted[stream].set(self.granted[stream].get().saturating_sub(body.len()));
+            if written < body.len() {
+                warn!("mux: stream {} overrun, {} bytes lost", stream, body.len() - written);
+            }
+        }
+    }
+
+    async fn send<O: ByteSink>(&self, output: &mut O) -> Result<(), O::Error> {
+        let mut encoder = F::Encoder::default();
+        let mut frame = [0; MUX_CHUNK + 1];
+        loop {
+            let len = poll_fn(|cx| match self.next_frame(&mut frame) {
+                0 => {
+                    // Anything that happened before the wakers were registered would be
+                    // missed, so look again
+                    self.register(cx.waker());
+                    match self.next_frame(&mut frame) {
+                        0 => Poll::Pending,
+                        len => Poll::Ready(len),
+                    }
+                },
+                len => Poll::Ready(len),
+            }).await;
+
+            encoder.start(len);
+            for byte in frame[..len].iter() {
+                if let Some(piece) = encoder.push(*byte) {
+                    output.write_all(piece).await?;
+                }
+            }
+            output.write_all(encoder.finish()).await?;
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        self.sender.register(waker);
+        for stream in 0..S {
+            self.rx[stream].borrow().register_writable(waker);
+            self.tx[stream].borrow().register_readable(waker);
+        }
+    }
+
+    /// Fill `frame` with the next frame to send, if there is one. Returns its length.
+    fn next_frame(&self, frame: &mut [u8; MUX_CHUNK + 1]) -> usize {
+        // Grants first: they're small, and the peer can't send without them. Wait until a
+        // useful amount has been read before granting more, rather than a byte at a time.
+        for stream in 0..S {
+            let granted = self.granted[stream].get();
+            let grant = self.rx[stream].borrow().free().saturating_sub(granted);
+            if grant > 0 && (granted == 0 || grant >= N / 4) {
+                self.granted[stream].set(granted + grant);
+                frame[0] = GRANT | stream as u8;
+                frame[1..5].copy_from_slice(&(grant as u32).to_le_bytes());
+                return 5;
+            }
+        }
+
+        for turn in 0..S {
+            let stream = (self.next.get() + turn) % S;
+            let credit = self.credits[stream].get();
+            let mut tx = self.tx[stream].borrow_mut();
+            let data = tx.peek();
+            let count = data.len().min(credit).min(MUX_CHUNK);
+            if count > 0 {
+                frame[0] = stream as u8;
+                frame[1..=count].copy_from_slice(&data[..count]);
+                tx.release(count);
+                self.credits[stream].set(credit - count);
+                self.next.set(stream + 1);
+                return count + 1;
+            }
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ future::Future, vec, vec::Vec };
+
+    use futures::{ executor::block_on, future::{ join, join3 } };
+
+    use super::*;
+
+    const N: usize = 64;
+
+    /// Run `test` with the streams of two muxes, connected back to back.
+    fn back_to_back<F: Future>(test: impl FnOnce([MuxChannel<'static, N>; 3], [MuxChannel<'static, N>; 3]) -> F) -> F::Output {
+        let (a, a_channels) = Mux::<3, N>::new(Box::leak(Box::new(MuxStorage::new())));
+        let (b, b_channels) = Mux::<3, N>::new(Box::leak(Box::new(MuxStorage::new())));
+        let (mut a_out, mut b_in) = Box::leak(Box::new(Ring::<256>::new())).split();
+        let (mut b_out, mut a_in) = Box::leak(Box::new(Ring::<256>::new())).split();
+
+        block_on(async {
+            let muxes = join(a.run(&mut a_in, &mut a_out), b.run(&mut b_in, &mut b_out));
+            // `run` clears anything written before it starts
+            let test = async {
+                settle().await;
+                test(a_channels, b_channels).await
+            };
+            match select(pin!(test), pin!(muxes)).await {
+                Either::Left((output, _)) => output,
+                Either::Right(_) => panic!("a mux stopped"),
+            }
+        })
+    }
+
+    fn pattern(stream: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| stream.wrapping_mul(85).wrapping_add(i as u8)).collect()
+    }
+
+    async fn read_exactly(recv: &mut RingConsumer<'_, N>, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            read += recv.read(&mut data[read..]).await;
+        }
+        data
+    }
+
+    /// Give the muxes time to do whatever they're going to.
+    async fn settle() {
+        for _ in 0..100 {
+            let mut yielded = false;
+            poll_fn(|cx| {
+                if yielded {
+                    return Poll::Ready(());
+                }
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }).await
+        }
+    }
+
+    #[test]
+    fn interleaved_streams() {
+        back_to_back(|[mut a0, mut a1, mut a2], [mut b0, mut b1, mut b2]| async move {
+            let data = [pattern(0, 1000), pattern(1, 700), pattern(2, 500)];
+            let sends = join3(a0.send.write_all(&data[0]), a1.send.write_all(&data[1]), b2.send.write_all(&data[2]));
+            let receives = join3(
+                read_exactly(&mut b0.recv, data[0].len()),
+                read_exactly(&mut b1.recv, data[1].len()),
+                read_exactly(&mut a2.recv, data[2].len()));
+            let (sent, (received0, received1, received2)) = join(sends, receives).await;
+
+            assert_eq!(sent, (Ok(()), Ok(()), Ok(())));
+            assert_eq!([received0, received1, received2], data);
+        });
+    }
+
+    #[test]
+    fn a_stream_out_of_credit_waits_for_a_grant() {
+        back_to_back(|[mut a0, mut a1, _], [mut b0, mut b1, _]| async move {
+            // More than both ends can buffer
+            let data = pattern(0, 4 * N);
+            let mut writer = pin!(a0.send.write_all(&data));
+
+            // Nobody reads stream 0, but stream 1 carries on
+            let other = pattern(1, 300);
+            let carried = join(a1.send.write_all(&other), read_exactly(&mut b1.recv, other.len()));
+            match select(writer.as_mut(), pin!(carried)).await {
+                Either::Left(_) => panic!("stream 0 wasn't held up"),
+                Either::Right(((written, received), _)) => {
+                    assert_eq!(written, Ok(()));
+                    assert_eq!(received, other);
+                },
+            }
+            settle().await;
+            // Filled, but not overrun
+            assert_eq!(b0.recv.available(), N);
+
+            // Reading grants more credit, and the rest follows
+            let (written, received) = join(writer, read_exactly(&mut b0.recv, data.len())).await;
+            assert_eq!(written, Ok(()));
+            assert_eq!(received, data);
+        });
+    }
+
+    #[test]
+    fn a_stream_closes_while_others_carry_on() {
+        back_to_back(|[mut a0, mut a1, mut a2], [mut b0, mut b1, mut b2]| async move {
+            a0.send.write_all(&pattern(0, 50)).await.unwrap();
+            drop(a0);
+            assert_eq!(read_exactly(&mut b0.recv, 50).await, pattern(0, 50));
+            drop(b0);
+            settle().await;
+
+            let data = [pattern(1, 500), pattern(2, 500)];
+            let sends = join(a1.send.write_all(&data[0]), b2.send.write_all(&data[1]));
+            let receives = join(read_exactly(&mut b1.recv, 500), read_exactly(&mut a2.recv, 500));
+            let (sent, (received1, received2)) = join(sends, receives).await;
+            assert_eq!(sent, (Ok(()), Ok(())));
+            assert_eq!([received1, received2], data);
+        });
+    }
+}
@@ -0,0 +1,307 @@
+
+use core::{
+    cell::Cell,
+    future::{ poll_fn, Future },
+    pin::pin,
+    task::Poll,
+};
+
+use defmt::{ debug, warn };
+
+use crate::stream::{ Sink, Stream };
+
+/// A request message: an id, and a body that's usually a micropb `oneof`.
+pub trait Request {
+    type Body;
+
+    fn id(&self) -> u32;
+    /// The body, or None if the `oneof` wasn't set.
+    fn into_body(self) -> Option<Self::Body>;
+}
+
+/// A response message, which carries the id of the request it answers.
+pub trait Response {
+    type Body;
+
+    fn new(id: u32, body: Self::Body) -> Self;
+    /// The answer to a request with no body, or one this end doesn't know, so the client
+    /// isn't left waiting for it. Usually an error status, or the `oneof` left unset.
+    fn no_body(id: u32) -> Self;
+}
+
+/// Handles requests. `call` usually matches on the body and hands each variant to its own
+/// async handler. It takes `&self` so calls can run concurrently: keep any mutable state in
+/// a `Cell` or `RefCell`, or behind a mutex.
+pub trait Service {
+    type Request: Request;
+    type Response: Response;
+
+    fn call(&self, request: <Self::Request as Request>::Body)
+        -> impl Future<Output = <Self::Response as Response>::Body>;
+}
+
+/// Reads requests, and runs up to `C` calls at once. Responses are sent as calls complete,
+/// which isn't necessarily the order the requests arrived in, so clients match them up by id.
+pub struct Dispatcher<S, const C: usize> {
+    service: S,
+}
+
+enum Step<R> {
+    Response(R),
+    End,
+}
+
+impl <S: Service, const C: usize> Dispatcher<S, C> {
+    pub fn new(service: S) -> Self {
+        Dispatcher { service }
+    }
+
+    /// Serve requests until the request stream ends, and every call has been answered.
+    pub async fn run<I, O>(&self, requests: &mut I, responses: &mut O) -> Result<(), O::Error>
+    where
+        I: Stream<Item = S::Request>,
+        O: Sink<Item = S::Response>,
+    {
+        // The reader owns the request stream for as long as the dispatcher runs, so a
+        // request that's half read is never dropped. It hands each one over through
+        // `incoming`, and is only polled once that's been taken and there's a free slot.
+        let incoming: Cell<Option<Option<S::Request>>> = Cell::new(None);
+        let mut reader = pin!(async {
+            loop {
+                let request = requests.next().await;
+                let end = request.is_none();
+                incoming.set(Some(request));
+                if end {
+                    return;
+                }
+                yield_once().await;
+            }
+        });
+
+        let call = |body: <S::Request as Request>::Body| self.service.call(body);
+        let mut calls = pin!(no_calls::<_, _, C>(&call));
+        let mut ids = [0u32; C];
+        let mut ended = false;
+
+        loop {
+            let step = poll_fn(|cx| loop {
+                for (slot, id) in ids.iter().enumerate() {
+                    // SAFETY: `calls` is pinned, and this only projects to one of its slots. A
+                    // call is never moved out of its slot: `set` drops it there, in place.
+                    let mut slot_call = unsafe { calls.as_mut().map_unchecked_mut(|calls| &mut calls[slot]) };
+                    if let Some(future) = slot_call.as_mut().as_pin_mut() {
+                        if let Poll::Ready(body) = future.poll(cx) {
+                            slot_call.set(None);
+                            debug!("request {} answered", id);
+                            return Poll::Ready(Step::Response(S::Response::new(*id, body)));
+                        }
+                    }
+                }
+
+                let free = calls.iter().position(Option::is_none);
+                match incoming.take() {
+                    Some(Some(request)) => {
+                        let id = request.id();
+                        match (request.into_body(), free) {
+                            (Some(body), Some(slot)) => {
+                                debug!("request {} in slot {}", id, slot);
+                                ids[slot] = id;
+                                // SAFETY: as above. The slot is empty, so nothing is dropped,
+                                // and the new call is pinned where it's put
+                                let mut slot_call = unsafe { calls.as_mut().map_unchecked_mut(|calls| &mut calls[slot]) };
+                                slot_call.set(Some(call(body)));
+                            },
+                            (None, _) => {
+                                warn!("request {} has no body", id);
+                                return Poll::Ready(Step::Response(S::Response::no_body(id)));
+                            },
+                            (Some(_), None) => unreachable!(),
+                        }
+                        // Poll the new call, and read the next request
+                        continue;
+                    },
+                    Some(None) => { ended = true; },
+                    None => {},
+                }
+
+                if ended {
+                    if calls.iter().all(Option::is_none) {
+                        return Poll::Ready(Step::End);
+                    }
+                    return Poll::Pending;
+                }
+
+                if free.is_none() || reader.as_mut().poll(cx).is_pending() {
+                    match incoming.take() {
+                        Some(request) => incoming.set(Some(request)),
+                        None => return Poll::Pending,
+                    }
+                }
+            }).await;
+
+            match step {
+                Step::Response(response) => responses.send(response).await?,
+                Step::End => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Empty slots for the futures `call` returns, which can't be named.
+fn no_calls<B, F, const C: usize>(_call: &impl Fn(B) -> F) -> [Option<F>; C] {
+    core::array::from_fn(|_| None)
+}
+
+/// Return to whoever's polling, once.
+async fn yield_once() {
+    let mut yielded = false;
+    poll_fn(|_| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            Poll::Pending
+        }
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ collections::VecDeque, convert::Infallible, vec::Vec };
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    struct TestRequest {
+        id: u32,
+        body: Option<u32>,
+    }
+
+    impl Request for TestRequest {
+        type Body = u32;
+
+        fn id(&self) -> u32 {
+            self.id
+        }
+
+        fn into_body(self) -> Option<u32> {
+            self.body
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct TestResponse {
+        id: u32,
+        body: Option<u32>,
+    }
+
+    impl Response for TestResponse {
+        type Body = u32;
+
+        fn new(id: u32, body: u32) -> Self {
+            TestResponse { id, body: Some(body) }
+        }
+
+        fn no_body(id: u32) -> Self {
+            TestResponse { id, body: None }
+        }
+    }
+
+    /// Each call yields as many times as its body says, then answers with the body.
+    #[derive(Default)]
+    struct Sleepy {
+        running: Cell<usize>,
+        most: Cell<usize>,
+    }
+
+    impl Service for Sleepy {
+        type Request = TestRequest;
+        type Response = TestResponse;
+
+        async fn call(&self, yields: u32) -> u32 {
+            self.running.set(self.running.get() + 1);
+            self.most.set(self.most.get().max(self.running.get()));
+            for _ in 0..yields {
+                yield_now().await;
+            }
+            self.running.set(self.running.get() - 1);
+            yields
+        }
+    }
+
+    /// Like `yield_once`, but asks to be polled again.
+    async fn yield_now() {
+        let mut yielded = false;
+        poll_fn(|cx| {
+            if yielded {
+                Poll::Ready(())
+            } else {
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }).await
+    }
+
+    struct Requests(VecDeque<TestRequest>);
+
+    impl Stream for Requests {
+        type Item = TestRequest;
+
+        async fn next(&mut self) -> Option<TestRequest> {
+            self.0.pop_front()
+        }
+    }
+
+    struct Responses(Vec<TestResponse>);
+
+    impl Sink for Responses {
+        type Item = TestResponse;
+        type Error = Infallible;
+
+        async fn send(&mut self, response: TestResponse) -> Result<(), Infallible> {
+            self.0.push(response);
+            Ok(())
+        }
+    }
+
+    fn serve<const C: usize>(requests: &[(u32, Option<u32>)]) -> (Vec<TestResponse>, Sleepy) {
+        let dispatcher: Dispatcher<Sleepy, C> = Dispatcher::new(Sleepy::default());
+        let mut requests = Requests(requests.iter().map(|&(id, body)| TestRequest { id, body }).collect());
+        let mut responses = Responses(Vec::new());
+        block_on(dispatcher.run(&mut requests, &mut responses)).unwrap();
+        (responses.0, dispatcher.service)
+    }
+
+    #[test]
+    fn answers_calls_as_they_finish() {
+        let (responses, service) = serve::<4>(&[(1, Some(20)), (2, Some(10)), (3, Some(0))]);
+        assert_eq!(responses, [
+            TestResponse { id: 3, body: Some(0) },
+            TestResponse { id: 2, body: Some(10) },
+            TestResponse { id: 1, body: Some(20) },
+        ]);
+        assert_eq!(service.most.get(), 3);
+    }
+
+    #[test]
+    fn holds_requests_until_a_slot_is_free() {
+        let requests: Vec<_> = (1..=5).map(|id| (id, Some(3))).collect();
+        let (responses, service) = serve::<2>(&requests);
+        let mut ids: Vec<_> = responses.iter().map(|response| response.id).collect();
+        ids.sort();
+        assert_eq!(ids, [1, 2, 3, 4, 5]);
+        assert_eq!(service.most.get(), 2);
+        assert_eq!(service.running.get(), 0);
+    }
+
+    #[test]
+    fn answers_a_request_without_a_body() {
+        let (responses, _) = serve::<2>(&[(1, Some(5)), (2, None), (3, Some(5))]);
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0], TestResponse { id: 2, body: None });
+        assert!(responses.contains(&TestResponse { id: 1, body: Some(5) }));
+        assert!(responses.contains(&TestResponse { id: 3, body: Some(5) }));
+    }
+}
@@ -0,0 +1,417 @@
+
+use core::task::Waker;
+
+use defmt::{ debug, warn };
+
+use usb_device::{
+    class_prelude::*,
+    control::{ Recipient, RequestType },
+};
+
+use crate::stream::ring::{ Ring, RingConsumer, RingProducer };
+
+/// The bytes buffered each way between the host and the application.
+pub const SERIAL_BUFFER: usize = 256;
+
+const PACKET: usize = 64;
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const REQ_SEND_BREAK: u8 = 0x23;
+
+/// 115200 baud, 8N1. The host can set anything, and it makes no difference.
+const DEFAULT_LINE_CODING: [u8; 7] = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+
+pub struct SerialStorage<const N: usize> {
+    rx: Ring<N>,
+    tx: Ring<N>,
+}
+
+impl <const N: usize> SerialStorage<N> {
+    pub const fn new() -> Self {
+        Self {
+            rx: Ring::new(),
+            tx: Ring::new(),
+        }
+    }
+}
+
+impl <const N: usize> Default for SerialStorage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The application's end of the serial port, for a `codec::Decoder` and `Encoder`. What's
+/// written while no terminal has the port open is thrown away, and when the port is closed,
+/// the `Decoder` drops any frame it was part way through.
+pub struct SerialEndpoint<'a, const N: usize> {
+    pub send: RingProducer<'a, N>,
+    pub recv: RingConsumer<'a, N>,
+}
+
+/// A CDC-ACM function, `/dev/ttyACM*` on Linux, next to the Ethernet one.
+pub(crate) struct Serial<'a, U: UsbBus, const N: usize> {
+    comm_if: InterfaceNumber,
+    comm_ep: EndpointIn<'a, U>,
+    data_if: InterfaceNumber,
+    read_ep: EndpointOut<'a, U>,
+    write_ep: EndpointIn<'a, U>,
+    rx: RingProducer<'a, N>,
+    tx: RingConsumer<'a, N>,
+    line_coding: [u8; 7],
+    /// A terminal has the port open.
+    dtr: bool,
+    writing: bool,
+    /// The last packet was full, so the host waits for more until a short one.
+    zlp: bool,
+}
+
+impl <'a, U: UsbBus, const N: usize> Serial<'a, U, N> {
+    pub fn new(alloc: &'a UsbBusAllocator<U>, storage: &'a mut SerialStorage<N>) -> (Self, SerialEndpoint<'a, N>) {
+        let (rx, recv) = storage.rx.split();
+        let (send, tx) = storage.tx.split();
+        let serial = Serial {
+            comm_if: alloc.interface(),
+            comm_ep: alloc.interrupt(8, 255),
+            data_if: alloc.interface(),
+            read_ep: alloc.bulk(PACKET as u16),
+            write_ep: alloc.bulk(PACKET as u16),
+            rx,
+            tx,
+            line_coding: DEFAULT_LINE_CODING,
+            dtr: false,
+            writing: false,
+            zlp: false,
+        };
+        (serial, SerialEndpoint { send, recv })
+    }
+
+    /// Move bytes between the endpoints and the rings. `UsbDevice::poll` only polls the
+    /// classes when there's been a USB event, so the gadget does this itself as well, to send
+    /// what the application has written since.
+    pub fn pump(&mut self) {
+        self.read();
+        self.write();
+    }
+
+    /// Wake `waker` when the application writes something, or reads enough to make room for
+    /// a packet that's waiting.
+    pub fn register(&self, waker: &Waker) {
+        self.tx.register_readable(waker);
+        self.rx.register_writable(waker);
+    }
+
+    fn read(&mut self) {
+        // Leave a packet in the endpoint until there's room for it, which holds the host off
+        if self.rx.free() < PACKET {
+            return;
+        }
+        let mut packet = [0; PACKET];
+        match self.read_ep.read(&mut packet) {
+            Ok(count) => {
+                self.rx.try_write(&packet[..count]);
+            },
+            Err(UsbError::WouldBlock) => {},
+            Err(e) => warn!("serial read failed: {}", e),
+        }
+    }
+
+    fn write(&mut self) {
+        // Nobody's listening, and the application mustn't block on a full ring
+        if !self.dtr {
+            self.tx.clear();
+            return;
+        }
+        if self.writing {
+            return;
+        }
+        let data = self.tx.peek();
+        let count = data.len().min(PACKET);
+        if count == 0 && !self.zlp {
+            return;
+        }
+        match self.write_ep.write(&data[..count]) {
+            Ok(count) => {
+                self.tx.release(count);
+                self.writing = true;
+                self.zlp = count == PACKET;
+            },
+            Err(UsbError::WouldBlock) => {},
+            Err(e) => warn!("serial write failed: {}", e),
+        }
+    }
+
+    fn set_dtr(&mut self, dtr: bool) {
+        if self.dtr && !dtr {
+            debug!("serial port closed");
+            self.tx.clear();
+            // Even when it's empty, so a decoder with half a frame sees the port was closed
+            self.rx.fence();
+        } else if dtr && !self.dtr {
+            debug!("serial port opened");
+        }
+        self.dtr = dtr;
+    }
+}
+
+impl <U: UsbBus, const N: usize> UsbClass<U> for Serial<'_, U, N> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> usb_device::Result<()> {
+        writer.iad(self.comm_if, 2, USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE, None)?;
+        writer.interface(self.comm_if, USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE)?;
+        // CDC 1.10
+        writer.write(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_CALL_MANAGEMENT, 0x00, self.data_if.into()])?;
+        // Supports the line coding and control line state requests
+        writer.write(CS_INTERFACE, &[CDC_TYPE_ACM, 0x02])?;
+        writer.write(CS_INTERFACE, &[CDC_TYPE_UNION, self.comm_if.into(), self.data_if.into()])?;
+        writer.endpoint(&self.comm_ep)?;
+        writer.interface(self.data_if, USB_CLASS_CDC_DATA, 0x00, 0x00)?;
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.set_dtr(false);
+        self.line_coding = DEFAULT_LINE_CODING;
+        self.writing = false;
+        self.zlp = false;
+    }
+
+    fn poll(&mut self) {
+        self.pump();
+    }
+
+    fn control_in(&mut self, transfer: ControlIn<U>) {
+        let req = *transfer.request();
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != u16::from(u8::from(self.comm_if)) {
+            return;
+        }
+        let result = match req.request {
+            REQ_GET_LINE_CODING => transfer.accept_with(&self.line_coding),
+            _ => transfer.reject(),
+        };
+        if let Err(e) = result {
+            warn!("serial control in failed: {}", e);
+        }
+    }
+
+    fn control_out(&mut self, transfer: ControlOut<U>) {
+        let req = *transfer.request();
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != u16::from(u8::from(self.comm_if)) {
+            return;
+        }
+        let result = match req.request {
+            REQ_SET_LINE_CODING if transfer.data().len() == self.line_coding.len() => {
+                self.line_coding.copy_from_slice(transfer.data());
+                transfer.accept()
+            },
+            REQ_SET_CONTROL_LINE_STATE => {
+                self.set_dtr(req.value & 1 != 0);
+                transfer.accept()
+            },
+            REQ_SEND_BREAK => transfer.accept(),
+            _ => transfer.reject(),
+        };
+        if let Err(e) = result {
+            warn!("serial control out failed: {}", e);
+        }
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr == self.read_ep.address() {
+            self.read();
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if addr == self.write_ep.address() {
+            self.writing = false;
+            self.write();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use usb_device::{ device::{ UsbDevice, UsbDeviceBuilder, UsbVidPid }, UsbDirection };
+
+    use super::*;
+    use crate::testing::FakeBus;
+
+    const N: usize = 256;
+
+    struct Host<'a> {
+        device: UsbDevice<'a, FakeBus>,
+        serial: Serial<'a, FakeBus, N>,
+    }
+
+    impl Host<'_> {
+        /// Make a class request of the communications interface.
+        fn request(&mut self, direction: UsbDirection, request: u8, value: u16, data: &[u8]) {
+            let request_type = match direction {
+                UsbDirection::In => 0xa1,
+                UsbDirection::Out => 0x21,
+            };
+            let index = u8::from(self.serial.comm_if) as u16;
+            let length = match direction {
+                UsbDirection::In => 7,
+                UsbDirection::Out => data.len() as u16,
+            };
+            let mut setup = std::vec![request_type, request];
+            for field in [value, index, length] {
+                setup.extend(field.to_le_bytes());
+            }
+            {
+                let mut endpoints = self.device.bus().0.lock().unwrap();
+                endpoints.setup = true;
+                endpoints.out[0].push_back(setup);
+            }
+            self.device.poll(&mut [&mut self.serial]);
+            if direction == UsbDirection::Out && !data.is_empty() {
+                self.device.bus().0.lock().unwrap().out[0].push_back(data.to_vec());
+                self.device.poll(&mut [&mut self.serial]);
+            }
+        }
+
+        fn set_dtr(&mut self, dtr: bool) {
+            self.request(UsbDirection::Out, REQ_SET_CONTROL_LINE_STATE, dtr as u16, &[]);
+        }
+
+        /// Packets sent to the host.
+        fn sent(&self) -> Vec<Vec<u8>> {
+            self.device.bus().written(self.serial.write_ep.address())
+        }
+
+        /// The host has taken the last packet.
+        fn complete(&mut self) {
+            self.serial.endpoint_in_complete(self.serial.write_ep.address());
+        }
+    }
+
+    fn control() -> EndpointAddress {
+        EndpointAddress::from_parts(0, UsbDirection::In)
+    }
+
+    fn host<'a>(alloc: &'a UsbBusAllocator<FakeBus>, storage: &'a mut SerialStorage<N>) -> (Host<'a>, SerialEndpoint<'a, N>) {
+        let (serial, endpoint) = Serial::new(alloc, storage);
+        let device = UsbDeviceBuilder::new(alloc, UsbVidPid(0x1209, 0x0004)).build();
+        (Host { device, serial }, endpoint)
+    }
+
+    #[test]
+    fn line_coding() {
+        let alloc = UsbBusAllocator::new(FakeBus::default());
+        let mut storage = SerialStorage::new();
+        let (mut host, _endpoint) = host(&alloc, &mut storage);
+
+        host.request(UsbDirection::In, REQ_GET_LINE_CODING, 0, &[]);
+        assert_eq!(host.device.bus().written(control()), [DEFAULT_LINE_CODING]);
+
+        // 9600 baud, 7E1
+        let coding = [0x80, 0x25, 0x00, 0x00, 0, 2, 7];
+        host.request(UsbDirection::Out, REQ_SET_LINE_CODING, 0, &coding);
+        host.device.bus().written(control());
+        host.request(UsbDirection::In, REQ_GET_LINE_CODING, 0, &[]);
+        assert_eq!(host.device.bus().written(control()), [coding]);
+
+        // Until the bus is reset
+        host.serial.reset();
+        host.request(UsbDirection::In, REQ_GET_LINE_CODING, 0, &[]);
+        assert_eq!(host.device.bus().written(control()), [DEFAULT_LINE_CODING]);
+    }
+
+    #[test]
+    fn only_sends_while_the_port_is_open() {
+        let alloc = UsbBusAllocator::new(FakeBus::default());
+        let mut storage = SerialStorage::new();
+        let (mut host, mut endpoint) = host(&alloc, &mut storage);
+
+        // Dropped, so the application never waits on a full ring
+        for _ in 0..4 {
+            assert_eq!(endpoint.send.try_write(&[1; N]), N);
+            host.serial.pump();
+        }
+        assert!(host.sent().is_empty());
+
+        host.set_dtr(true);
+        endpoint.send.try_write(b"hello");
+        host.serial.pump();
+        assert_eq!(host.sent(), [b"hello"]);
+        host.complete();
+
+        host.set_dtr(false);
+        endpoint.send.try_write(b"nobody");
+        host.serial.pump();
+        host.set_dtr(true);
+        host.serial.pump();
+        assert!(host.sent().is_empty());
+    }
+
+    #[test]
+    fn closing_the_port_is_a_boundary() {
+        let alloc = UsbBusAllocator::new(FakeBus::default());
+        let mut storage = SerialStorage::new();
+        let (mut host, mut endpoint) = host(&alloc, &mut storage);
+        let read_ep = host.serial.read_ep.address();
+
+        host.set_dtr(true);
+        host.device.bus().0.lock().unwrap().out[read_ep.index()].push_back(b"half a fr".to_vec());
+        host.serial.endpoint_out(read_ep);
+        let mut buf = [0; 16];
+        assert_eq!(endpoint.recv.try_read(&mut buf), 9);
+        assert!(!endpoint.recv.take_boundary());
+
+        // Closed with nothing left unread
+        host.set_dtr(false);
+        assert_eq!(endpoint.recv.available(), 0);
+        assert!(endpoint.recv.take_boundary());
+    }
+
+    #[test]
+    fn zero_length_packet_after_a_full_one() {
+        let alloc = UsbBusAllocator::new(FakeBus::default());
+        let mut storage = SerialStorage::new();
+        let (mut host, mut endpoint) = host(&alloc, &mut storage);
+        host.set_dtr(true);
+
+        endpoint.send.try_write(&[7; PACKET]);
+        host.serial.pump();
+        assert_eq!(host.sent(), [[7; PACKET]]);
+        // One packet at a time
+        host.serial.pump();
+        assert!(host.sent().is_empty());
+        host.complete();
+        assert_eq!(host.sent(), [[]]);
+        host.complete();
+        host.serial.pump();
+        assert!(host.sent().is_empty());
+
+        // A short packet ends the transfer by itself
+        endpoint.send.try_write(&[8; PACKET + 10]);
+        host.serial.pump();
+        host.complete();
+        host.complete();
+        host.serial.pump();
+        assert_eq!(host.sent(), [&[8; PACKET][..], &[8; 10][..]]);
+    }
+}
@@ -0,0 +1,479 @@
+//! IPv6 addressing: an EUI-64 link-local address, and RFC 4862 stateless autoconfiguration
+//! from router advertisements, which smoltcp doesn't do. Duplicate address detection is left
+//! out: every address has the interface id of the gadget's own MAC address, and the host at
+//! the other end of the link is the only neighbour.
+
+use defmt::{ debug, info, warn };
+
+use micropb::heapless::Vec;
+use smoltcp::{
+    phy::{ ChecksumCapabilities, Device, TxToken },
+    time::{ Duration, Instant },
+    wire::{
+        EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr, Icmpv6Message,
+        Icmpv6Packet, Icmpv6Repr, IpProtocol, Ipv6Address, Ipv6Cidr, Ipv6Packet, Ipv6Repr,
+        NdiscOption, NdiscOptionRepr, NdiscPrefixInfoFlags, NdiscRepr,
+        IPV6_LINK_LOCAL_ALL_ROUTERS,
+    },
+};
+
+use crate::snoop::FrameObserver;
+
+/// The most prefixes the gadget takes addresses from.
+pub(crate) const MAX_PREFIXES: usize = 2;
+
+const PREFIX_LEN: u8 = 64;
+const LINK_LOCAL_PREFIX: Ipv6Address = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 0);
+const ALL_ROUTERS_MAC: EthernetAddress = EthernetAddress([0x33, 0x33, 0, 0, 0, 2]);
+
+// RFC 4861 section 10
+const MAX_RTR_SOLICITATIONS: u8 = 3;
+const RTR_SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+// RFC 4862 section 5.5.3
+const TWO_HOURS: Duration = Duration::from_secs(2 * 60 * 60);
+
+#[derive(Clone, Copy)]
+struct Prefix {
+    cidr: Ipv6Cidr,
+    expires: Instant,
+}
+
+/// A router advertisement that's been seen, but not acted on: observers don't know the time.
+struct Advert {
+    router: Ipv6Address,
+    lifetime: Duration,
+    prefixes: Vec<(Ipv6Address, Duration), MAX_PREFIXES>,
+}
+
+/// The addresses and router the gadget has learnt, and the solicitations it has sent.
+pub(crate) struct Slaac {
+    mac_address: EthernetAddress,
+    prefixes: Vec<Prefix, MAX_PREFIXES>,
+    router: Option<(Ipv6Address, Instant)>,
+    advert: Option<Advert>,
+    link_up: bool,
+    /// Router solicitations sent, and when to send the next.
+    solicit: Option<(u8, Instant)>,
+}
+
+impl Slaac {
+    pub fn new(mac_address: EthernetAddress) -> Self {
+        Slaac {
+            mac_address,
+            prefixes: Vec::new(),
+            router: None,
+            advert: None,
+            link_up: false,
+            solicit: None,
+        }
+    }
+
+    /// The address for `prefix`, with the modified EUI-64 interface id of RFC 4291.
+    fn address(&self, prefix: Ipv6Address) -> Ipv6Address {
+        let mac = self.mac_address.as_bytes();
+        let mut octets = prefix.octets();
+        octets[8..].copy_from_slice(&[mac[0] ^ 0x02, mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5]]);
+        Ipv6Address::from(octets)
+    }
+
+    pub fn link_local(&self) -> Ipv6Cidr {
+        Ipv6Cidr::new(self.address(LINK_LOCAL_PREFIX), PREFIX_LEN)
+    }
+
+    /// The link-local address, then one for each prefix.
+    pub fn addresses(&self) -> impl Iterator<Item = Ipv6Cidr> + '_ {
+        core::iter::once(self.link_local()).chain(self.prefixes.iter().map(|prefix| prefix.cidr))
+    }
+
+    pub fn router(&self) -> Option<Ipv6Address> {
+        self.router.map(|(router, _)| router)
+    }
+
+    /// Solicit a router advertisement when the host brings the link up, rather than waiting
+    /// minutes for the next one.
+    pub fn link(&mut self, up: bool, now: Instant) {
+        if up && !self.link_up {
+            self.solicit = Some((0, now));
+        }
+        self.link_up = up;
+    }
+
+    /// When the next solicitation is due, or an address or the router expires.
+    pub fn poll_at(&self) -> Option<Instant> {
+        let advert = self.advert.as_ref().map(|_| Instant::ZERO);
+        let solicit = self.solicit.map(|(_, next)| next);
+        let router = self.router.map(|(_, expires)| expires);
+        let prefixes = self.prefixes.iter().map(|prefix| prefix.expires).min();
+        [advert, solicit, router, prefixes].into_iter().flatten().min()
+    }
+
+    /// Act on advertisements, expire what's out of date, and solicit. Returns true if the
+    /// addresses or the router have changed.
+    pub fn poll<D: Device>(&mut self, now: Instant, device: &mut D) -> bool {
+        let mut changed = false;
+        if let Some(advert) = self.advert.take() {
+            self.solicit = None;
+            changed |= self.apply(now, advert);
+        }
+
+        let count = self.prefixes.len();
+        self.prefixes.retain(|prefix| {
+            let valid = prefix.expires > now;
+            if !valid {
+                info!("IPv6 address {} expired", prefix.cidr);
+            }
+            valid
+        });
+        changed |= self.prefixes.len() != count;
+        if matches!(self.router, Some((_, expires)) if expires <= now) {
+            debug!("IPv6 router expired");
+            self.router = None;
+            changed = true;
+        }
+
+        match self.solicit {
+            Some((sent, next)) if now >= next => {
+                if sent == MAX_RTR_SOLICITATIONS {
+                    debug!("no IPv6 router");
+                    self.solicit = None;
+                } else if self.send_solicitation(now, device) {
+                    self.solicit = Some((sent + 1, now + RTR_SOLICITATION_INTERVAL));
+                }
+            },
+            _ => {}
+        }
+        changed
+    }
+
+    fn apply(&mut self, now: Instant, advert: Advert) -> bool {
+        let mut changed = false;
+        match self.router {
+            Some((router, _)) if router == advert.router && advert.lifetime == Duration::ZERO => {
+                info!("IPv6 router {} withdrawn", router);
+                self.router = None;
+                changed = true;
+            },
+            _ if advert.lifetime > Duration::ZERO => {
+                changed |= self.router() != Some(advert.router);
+                self.router = Some((advert.router, now + advert.lifetime));
+            },
+            _ => {}
+        }
+
+        for (prefix, valid) in advert.prefixes {
+            let cidr = Ipv6Cidr::new(self.address(prefix), PREFIX_LEN);
+            match self.prefixes.iter_mut().find(|known| known.cidr == cidr) {
+                // Section 5.5.3 e: an advertisement can't cut a lifetime short to less than
+                // two hours, so a spoofed one can't take the address away
+                Some(known) => {
+                    let remaining = if known.expires > now { known.expires - now } else { Duration::ZERO };
+                    if valid > TWO_HOURS || valid > remaining {
+                        known.expires = now + valid;
+                    } else if remaining > TWO_HOURS {
+                        known.expires = now + TWO_HOURS;
+                    }
+                },
+                None if valid > Duration::ZERO => {
+                    if self.prefixes.push(Prefix { cidr, expires: now + valid }).is_ok() {
+                        info!("IPv6 address:    {}", cidr);
+                        changed = true;
+                    } else {
+                        warn!("more than {} IPv6 prefixes, ignoring {}", MAX_PREFIXES, prefix);
+                    }
+                },
+                None => {}
+            }
+        }
+        changed
+    }
+
+    fn send_solicitation<D: Device>(&self, now: Instant, device: &mut D) -> bool {
+        let source = self.link_local().address();
+        let icmp = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { lladdr: Some(self.mac_address.into()) });
+        let ip = Ipv6Repr {
+            src_addr: source,
+            dst_addr: IPV6_LINK_LOCAL_ALL_ROUTERS,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp.buffer_len(),
+            hop_limit: 255,
+        };
+        let ethernet = EthernetRepr {
+            src_addr: self.mac_address,
+            dst_addr: ALL_ROUTERS_MAC,
+            ethertype: EthernetProtocol::Ipv6,
+        };
+
+        match device.transmit(now) {
+            Some(token) => {
+                debug!("IPv6 router solicitation");
+                token.consume(ethernet.buffer_len() + ip.buffer_len() + icmp.buffer_len(), |buffer| {
+                    let mut frame = EthernetFrame::new_unchecked(buffer);
+                    ethernet.emit(&mut frame);
+                    let mut packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+                    ip.emit(&mut packet);
+                    icmp.emit(
+                        &source,
+                        &IPV6_LINK_LOCAL_ALL_ROUTERS,
+                        &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+                        &ChecksumCapabilities::default());
+                });
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl FrameObserver for Slaac {
+    fn observe(&mut self, frame: &[u8]) {
+        let Ok(frame) = EthernetFrame::new_checked(frame) else { return };
+        if frame.ethertype() != EthernetProtocol::Ipv6 {
+            return;
+        }
+        let Ok(packet) = Ipv6Packet::new_checked(frame.payload()) else { return };
+        // RFC 4861 section 6.1.2: only from a router on the link
+        let (source, destination) = (packet.src_addr(), packet.dst_addr());
+        if packet.next_header() != IpProtocol::Icmpv6
+            || packet.hop_limit() != 255
+            || !source.is_unicast_link_local() {
+            return;
+        }
+        let Ok(icmp) = Icmpv6Packet::new_checked(packet.payload()) else { return };
+        if icmp.msg_type() != Icmpv6Message::RouterAdvert
+            || icmp.msg_code() != 0
+            || !icmp.verify_checksum(&source, &destination) {
+            return;
+        }
+
+        let mut advert = Advert { router: source, lifetime: icmp.router_lifetime(), prefixes: Vec::new() };
+        let mut options = icmp.payload();
+        while !options.is_empty() {
+            let Ok(option) = NdiscOption::new_checked(options) else { return };
+            let len = option.data_len() as usize * 8;
+            if len == 0 {
+                return;
+            }
+            if let Ok(NdiscOptionRepr::PrefixInformation(info)) = NdiscOptionRepr::parse(&option) {
+                // Section 5.5.3 of RFC 4862
+                if info.flags.contains(NdiscPrefixInfoFlags::ADDRCONF)
+                    && info.prefix_len == PREFIX_LEN
+                    && !info.prefix.is_unicast_link_local()
+                    && info.preferred_lifetime <= info.valid_lifetime
+                    && advert.prefixes.push((info.prefix, info.valid_lifetime)).is_err() {
+                    warn!("more than {} IPv6 prefixes advertised", MAX_PREFIXES);
+                }
+            }
+            options = &options[len.min(options.len())..];
+        }
+        debug!("IPv6 router advertisement from {}", source);
+        self.advert = Some(advert);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::{
+        phy::{ Loopback, Medium, RxToken },
+        wire::{ NdiscPrefixInformation, NdiscRouterFlags, IPV6_LINK_LOCAL_ALL_NODES },
+    };
+
+    use super::*;
+
+    const MAC: EthernetAddress = EthernetAddress([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    const ROUTER: Ipv6Address = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    const PREFIX: Ipv6Address = Ipv6Address::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 0);
+    const ADDRESS: Ipv6Address = Ipv6Address::new(0x2001, 0xdb8, 1, 0, 0x0211, 0x22ff, 0xfe33, 0x4455);
+
+    /// A router advertisement from `ROUTER`, with `prefix` and its valid lifetime.
+    fn advert(lifetime: Duration, prefix: Option<(Ipv6Address, Duration)>) -> std::vec::Vec<u8> {
+        let prefix_info = prefix.map(|(prefix, valid)| NdiscPrefixInformation {
+            prefix_len: PREFIX_LEN,
+            flags: NdiscPrefixInfoFlags::ON_LINK | NdiscPrefixInfoFlags::ADDRCONF,
+            valid_lifetime: valid,
+            preferred_lifetime: valid,
+            prefix,
+        });
+        let icmp = Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert {
+            hop_limit: 64,
+            flags: NdiscRouterFlags::empty(),
+            router_lifetime: lifetime,
+            reachable_time: Duration::ZERO,
+            retrans_time: Duration::ZERO,
+            lladdr: None,
+            mtu: None,
+            prefix_info,
+        });
+        let ip = Ipv6Repr {
+            src_addr: ROUTER,
+            dst_addr: IPV6_LINK_LOCAL_ALL_NODES,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp.buffer_len(),
+            hop_limit: 255,
+        };
+        let ethernet = EthernetRepr {
+            src_addr: EthernetAddress([0x02, 0, 0, 0, 0, 1]),
+            dst_addr: EthernetAddress([0x33, 0x33, 0, 0, 0, 1]),
+            ethertype: EthernetProtocol::Ipv6,
+        };
+
+        let mut buffer = vec![0; ethernet.buffer_len() + ip.buffer_len() + icmp.buffer_len()];
+        let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+        ethernet.emit(&mut frame);
+        let mut packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+        ip.emit(&mut packet);
+        icmp.emit(
+            &ROUTER,
+            &IPV6_LINK_LOCAL_ALL_NODES,
+            &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+            &ChecksumCapabilities::default());
+        buffer
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    fn at(secs: u64) -> Instant {
+        Instant::from_secs(secs as i64)
+    }
+
+    fn addresses(slaac: &Slaac) -> std::vec::Vec<Ipv6Address> {
+        slaac.addresses().skip(1).map(|cidr| cidr.address()).collect()
+    }
+
+    #[test]
+    fn eui64_link_local() {
+        let slaac = Slaac::new(MAC);
+        let link_local = Ipv6Address::new(0xfe80, 0, 0, 0, 0x0211, 0x22ff, 0xfe33, 0x4455);
+        assert_eq!(slaac.link_local(), Ipv6Cidr::new(link_local, 64));
+        assert_eq!(slaac.addresses().collect::<std::vec::Vec<_>>(), [slaac.link_local()]);
+    }
+
+    #[test]
+    fn address_and_router_from_an_advert() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut slaac = Slaac::new(MAC);
+        slaac.observe(&advert(secs(1800), Some((PREFIX, secs(3600)))));
+        assert_eq!(slaac.poll_at(), Some(Instant::ZERO));
+        assert!(slaac.poll(at(10), &mut device));
+        assert_eq!(addresses(&slaac), [ADDRESS]);
+        assert_eq!(slaac.router(), Some(ROUTER));
+        assert_eq!(slaac.poll_at(), Some(at(1810)));
+
+        // The same again changes nothing
+        slaac.observe(&advert(secs(1800), Some((PREFIX, secs(3600)))));
+        assert!(!slaac.poll(at(20), &mut device));
+    }
+
+    #[test]
+    fn ignores_what_isnt_a_usable_advert() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut slaac = Slaac::new(MAC);
+
+        let mut corrupt = advert(secs(1800), Some((PREFIX, secs(3600))));
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        slaac.observe(&corrupt);
+        let mut forwarded = advert(secs(1800), Some((PREFIX, secs(3600))));
+        // The IPv6 hop limit: an advertisement from off the link
+        forwarded[14 + 7] = 254;
+        slaac.observe(&forwarded);
+        assert!(!slaac.poll(at(0), &mut device));
+
+        // A link-local prefix isn't one to take an address from
+        slaac.observe(&advert(secs(1800), Some((LINK_LOCAL_PREFIX, secs(3600)))));
+        assert!(slaac.poll(at(0), &mut device));
+        assert!(addresses(&slaac).is_empty());
+        assert_eq!(slaac.router(), Some(ROUTER));
+    }
+
+    #[test]
+    fn two_hour_rule() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut slaac = Slaac::new(MAC);
+        slaac.observe(&advert(Duration::ZERO, Some((PREFIX, secs(10 * 3600)))));
+        slaac.poll(at(0), &mut device);
+        assert_eq!(slaac.poll_at(), Some(at(10 * 3600)));
+
+        // Cut short, but to no less than two hours
+        slaac.observe(&advert(Duration::ZERO, Some((PREFIX, secs(60)))));
+        slaac.poll(at(100), &mut device);
+        assert_eq!(slaac.poll_at(), Some(at(100 + 2 * 3600)));
+
+        // With less than two hours left, a shorter lifetime is ignored
+        slaac.observe(&advert(Duration::ZERO, Some((PREFIX, secs(60)))));
+        slaac.poll(at(3600), &mut device);
+        assert_eq!(slaac.poll_at(), Some(at(100 + 2 * 3600)));
+
+        // but a longer one, or one over two hours, is taken as it is
+        slaac.observe(&advert(Duration::ZERO, Some((PREFIX, secs(3 * 3600)))));
+        slaac.poll(at(3600), &mut device);
+        assert_eq!(slaac.poll_at(), Some(at(4 * 3600)));
+        assert_eq!(addresses(&slaac), [ADDRESS]);
+    }
+
+    #[test]
+    fn router_and_prefix_expire() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut slaac = Slaac::new(MAC);
+        slaac.observe(&advert(secs(100), Some((PREFIX, secs(200)))));
+        assert!(slaac.poll(at(0), &mut device));
+
+        assert!(!slaac.poll(at(99), &mut device));
+        assert!(slaac.poll(at(100), &mut device));
+        assert_eq!(slaac.router(), None);
+        assert_eq!(addresses(&slaac), [ADDRESS]);
+        assert_eq!(slaac.poll_at(), Some(at(200)));
+
+        assert!(slaac.poll(at(200), &mut device));
+        assert!(addresses(&slaac).is_empty());
+        assert_eq!(slaac.poll_at(), None);
+    }
+
+    #[test]
+    fn router_withdrawn() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut slaac = Slaac::new(MAC);
+        slaac.observe(&advert(secs(1800), None));
+        assert!(slaac.poll(at(0), &mut device));
+        assert_eq!(slaac.router(), Some(ROUTER));
+
+        slaac.observe(&advert(Duration::ZERO, None));
+        assert!(slaac.poll(at(10), &mut device));
+        assert_eq!(slaac.router(), None);
+    }
+
+    #[test]
+    fn solicits_when_the_link_comes_up() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let mut slaac = Slaac::new(MAC);
+        let sent = |device: &mut Loopback| {
+            let mut count = 0;
+            while let Some((rx, _)) = device.receive(Instant::ZERO) {
+                rx.consume(|frame| {
+                    let frame = EthernetFrame::new_checked(frame).unwrap();
+                    assert_eq!(frame.dst_addr(), ALL_ROUTERS_MAC);
+                });
+                count += 1;
+            }
+            count
+        };
+
+        slaac.link(true, at(0));
+        for secs in 0..20 {
+            slaac.poll(at(secs), &mut device);
+        }
+        assert_eq!(sent(&mut device), MAX_RTR_SOLICITATIONS);
+        assert_eq!(slaac.poll_at(), None);
+
+        // Again when it comes back, but an advert stops it
+        slaac.link(false, at(20));
+        slaac.link(true, at(21));
+        slaac.poll(at(21), &mut device);
+        slaac.observe(&advert(secs(1800), None));
+        for secs in 22..40 {
+            slaac.poll(at(secs), &mut device);
+        }
+        assert_eq!(sent(&mut device), 1);
+    }
+}
@@ -0,0 +1,67 @@
+
+use smoltcp::{
+    phy::{ Device, DeviceCapabilities, RxToken },
+    time::Instant,
+};
+
+/// Something that wants to see every frame received by the interface, before smoltcp
+/// processes it. Used for protocols smoltcp doesn't implement, like ARP probing.
+pub(crate) trait FrameObserver {
+    fn observe(&mut self, frame: &[u8]);
+}
+
+impl <A: FrameObserver, B: FrameObserver> FrameObserver for (&mut A, &mut B) {
+    fn observe(&mut self, frame: &[u8]) {
+        self.0.observe(frame);
+        self.1.observe(frame);
+    }
+}
+
+/// A device wrapper that shows each received frame to an observer, then passes it on
+/// to the interface unchanged.
+pub(crate) struct Snoop<'d, D: Device, O: FrameObserver> {
+    device: &'d mut D,
+    observer: &'d mut O,
+}
+
+impl <'d, D: Device, O: FrameObserver> Snoop<'d, D, O> {
+    pub fn new(device: &'d mut D, observer: &'d mut O) -> Self {
+        Snoop { device, observer }
+    }
+}
+
+pub(crate) struct SnoopRxToken<'a, R: RxToken, O: FrameObserver> {
+    token: R,
+    observer: &'a mut O,
+}
+
+impl <R: RxToken, O: FrameObserver> RxToken for SnoopRxToken<'_, R, O> {
+    fn consume<T, F>(self, f: F) -> T
+    where
+        F: FnOnce(&[u8]) -> T {
+        let observer = self.observer;
+        self.token.consume(|frame| {
+            observer.observe(frame);
+            f(frame)
+        })
+    }
+}
+
+impl <D: Device, O: FrameObserver> Device for Snoop<'_, D, O> {
+    type RxToken<'a> = SnoopRxToken<'a, D::RxToken<'a>, O> where Self: 'a;
+    type TxToken<'a> = D::TxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let observer = &mut *self.observer;
+        self.device.receive(timestamp)
+            .map(|(token, tx)| (SnoopRxToken { token, observer }, tx))
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        self.device.transmit(timestamp)
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.device.capabilities()
+    }
+}
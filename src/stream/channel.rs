@@ -19,10 +19,7 @@ impl <'a, T, const N: usize> Stream for ChannelStream<'a, T, N> {
     type Item = T;
 
     async fn next(&mut self) -> Option<T> {
-        match self.0.recv().await {
-            Ok(byte) => Some(byte),
-            Err(_) => None,
-        }
+        self.0.recv().await.ok()
     }
 }
 
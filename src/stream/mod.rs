@@ -1,5 +1,6 @@
 
 pub mod channel;
+pub mod ring;
 
 use core::{
     future::Future, result::Result
@@ -14,7 +15,30 @@ pub trait Stream {
     fn next(&mut self) -> impl Future<Output = Option<Self::Item>>;
 }
 
-pub trait ByteStream: Stream<Item = u8> {}
+pub trait ByteStream: Stream<Item = u8> {
+    /// Wait for at least one byte, then read as many as are available into `buf`.
+    /// Returns 0 at the end of the stream, or straight away if `buf` is empty.
+    fn read(&mut self, buf: &mut [u8]) -> impl Future<Output = usize> {
+        async move {
+            if buf.is_empty() {
+                return 0;
+            }
+            match self.next().await {
+                Some(byte) => {
+                    buf[0] = byte;
+                    1
+                },
+                None => 0,
+            }
+        }
+    }
+
+    /// True, once, if the bytes just read start a new session, like a new connection: what
+    /// came before them was thrown away unread, so a decoder should drop any partial frame.
+    fn take_boundary(&mut self) -> bool {
+        false
+    }
+}
 
 /// A trait for a sink that can accept items asynchronously.
 /// This trait is similar to the `Sink` trait in the `futures` crate,
@@ -39,4 +63,48 @@ where
     Ok(())
 }
 
-pub trait ByteSink: Sink<Item = u8> {}
+pub trait ByteSink: Sink<Item = u8> {
+    fn write_all(&mut self, data: &[u8]) -> impl Future<Output = Result<(), Self::Error>> {
+        async move {
+            for byte in data {
+                self.send(*byte).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// A stream with only `next`, to test `ByteStream`'s default methods.
+    struct Bytes<'a>(&'a [u8]);
+
+    impl Stream for Bytes<'_> {
+        type Item = u8;
+
+        async fn next(&mut self) -> Option<u8> {
+            let (first, rest) = self.0.split_first()?;
+            self.0 = rest;
+            Some(*first)
+        }
+    }
+
+    impl ByteStream for Bytes<'_> {}
+
+    #[test]
+    fn read_a_byte_at_a_time() {
+        let mut stream = Bytes(&[1, 2]);
+        let mut buf = [0; 4];
+        assert_eq!(block_on(stream.read(&mut buf)), 1);
+        assert_eq!(buf[0], 1);
+        assert_eq!(block_on(stream.read(&mut [])), 0);
+        assert_eq!(block_on(stream.read(&mut buf)), 1);
+        assert_eq!(buf[0], 2);
+        assert_eq!(block_on(stream.read(&mut buf)), 0);
+        assert!(!stream.take_boundary());
+    }
+}
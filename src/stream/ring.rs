@@ -0,0 +1,682 @@
+
+use core::{
+    cell::UnsafeCell,
+    future::poll_fn,
+    sync::atomic::{ AtomicBool, AtomicUsize, Ordering },
+    task::{ Poll, Waker },
+};
+
+use rtic_common::waker_registration::CriticalSectionWakerRegistration;
+
+use crate::stream::{ ByteSink, ByteStream, Sink, Stream };
+
+/// A single producer, single consumer byte ring, in the style of bbqueue: each side works on
+/// contiguous slices of the buffer, so bytes move in bulk rather than one at a time.
+/// Only atomic loads and stores are used, so it works on cores without compare-and-swap,
+/// like the Cortex-M0.
+///
+/// The read and write indices run over `0..2N`, so a full ring can be told apart from an
+/// empty one without wasting a byte.
+///
+/// The producer can fence the ring, to throw away what the consumer hasn't read yet, for
+/// example at the end of a connection. The consumer sees a session boundary where it did.
+/// That's wherever the producer had got to, which can be part way through a write: what's
+/// committed after the consumer has seen the fence comes after the boundary, even if it was
+/// granted, or was the rest of a `try_write`, from before. A producer whose writes have to
+/// land whole on one side should only be fenced between them.
+pub struct Ring<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    read: AtomicUsize,
+    write: AtomicUsize,
+    producer_closed: AtomicBool,
+    consumer_closed: AtomicBool,
+    fenced: AtomicBool,
+    /// The consumer has discarded fenced bytes, and hasn't reported it yet.
+    boundary: AtomicBool,
+    readable: CriticalSectionWakerRegistration,
+    writable: CriticalSectionWakerRegistration,
+}
+
+// SAFETY: the producer only touches the free part of the buffer, the consumer only the used
+// part, and the indices hand bytes from one to the other with release/acquire ordering. There's
+// only one of each, as `split` borrows the ring mutably for as long as they live.
+unsafe impl <const N: usize> Sync for Ring<N> {}
+
+impl <const N: usize> Ring<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new([0; N]),
+            read: AtomicUsize::new(0),
+            write: AtomicUsize::new(0),
+            producer_closed: AtomicBool::new(false),
+            consumer_closed: AtomicBool::new(false),
+            fenced: AtomicBool::new(false),
+            boundary: AtomicBool::new(false),
+            readable: CriticalSectionWakerRegistration::new(),
+            writable: CriticalSectionWakerRegistration::new(),
+        }
+    }
+
+    pub fn split(&mut self) -> (RingProducer<'_, N>, RingConsumer<'_, N>) {
+        *self.read.get_mut() = 0;
+        *self.write.get_mut() = 0;
+        *self.producer_closed.get_mut() = false;
+        *self.consumer_closed.get_mut() = false;
+        *self.fenced.get_mut() = false;
+        *self.boundary.get_mut() = false;
+        (RingProducer { ring: self }, RingConsumer { ring: self })
+    }
+
+    /// A producer for a ring that's never split, like a static one. It doesn't close the
+    /// ring when it's dropped, so another can be made later.
+    ///
+    /// # Safety
+    /// There must be only one producer at a time, and the ring must never be split.
+    #[cfg(feature = "net-logger")]
+    pub(crate) unsafe fn producer(&self) -> core::mem::ManuallyDrop<RingProducer<'_, N>> {
+        core::mem::ManuallyDrop::new(RingProducer { ring: self })
+    }
+
+    /// A consumer for a ring that's never split.
+    ///
+    /// # Safety
+    /// There must be only one consumer, and the ring must never be split.
+    #[cfg(feature = "net-logger")]
+    pub(crate) unsafe fn consumer(&self) -> RingConsumer<'_, N> {
+        RingConsumer { ring: self }
+    }
+
+    fn len(&self) -> usize {
+        let read = self.read.load(Ordering::Acquire);
+        let write = self.write.load(Ordering::Acquire);
+        (write + 2 * N - read) % (2 * N)
+    }
+
+    fn fence(&self) {
+        self.fenced.store(true, Ordering::Release);
+        self.readable.wake();
+    }
+
+    fn advance(index: &AtomicUsize, count: usize) {
+        let value = index.load(Ordering::Relaxed);
+        index.store((value + count) % (2 * N), Ordering::Release);
+    }
+
+    /// # Safety
+    /// The range must lie in the part of the buffer owned by the caller.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slice(&self, start: usize, len: usize) -> &mut [u8] {
+        debug_assert!(start + len <= N);
+        // SAFETY: the range is inside the buffer, and the caller owns it, so nothing else
+        // reads or writes it while the slice lives
+        core::slice::from_raw_parts_mut((self.buffer.get() as *mut u8).add(start), len)
+    }
+}
+
+impl <const N: usize> Default for Ring<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The consumer has gone, so nothing written will ever be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct NoConsumer;
+
+pub struct RingProducer<'a, const N: usize> {
+    ring: &'a Ring<N>,
+}
+
+impl <'a, const N: usize> RingProducer<'a, N> {
+    /// The number of bytes that can be written. None while the ring is fenced.
+    pub fn free(&self) -> usize {
+        match self.is_fenced() {
+            true => 0,
+            false => N - self.ring.len(),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.free() == 0
+    }
+
+    /// True once the consumer has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.ring.consumer_closed.load(Ordering::Acquire)
+    }
+
+    /// Discard everything written so far. The consumer drops it the next time it looks,
+    /// and nothing more can be written until it has.
+    pub fn fence(&mut self) {
+        self.ring.fence();
+    }
+
+    /// A handle that fences the ring from somewhere else, like the other half of a channel
+    /// that sees the connection end.
+    pub fn fencer(&self) -> RingFence<'a, N> {
+        RingFence { ring: self.ring }
+    }
+
+    /// True until the consumer has dropped the bytes written before the last `fence`.
+    pub fn is_fenced(&self) -> bool {
+        self.ring.fenced.load(Ordering::Acquire)
+    }
+
+    /// Wake `waker` the next time the consumer frees some space, or goes. This replaces the
+    /// waker of any `write` in progress, so it's only for a producer that polls the ring
+    /// itself, alongside other things.
+    pub fn register_writable(&self, waker: &Waker) {
+        self.ring.writable.register(waker);
+    }
+
+    /// The largest contiguous free slice. Fill some of it, then `commit` what was written.
+    /// It may be shorter than `free` when the free space wraps around the end of the buffer.
+    pub fn grant(&mut self) -> &mut [u8] {
+        let free = self.free();
+        let start = self.ring.write.load(Ordering::Relaxed) % N;
+        // SAFETY: the `free` bytes from the write index belong to the producer until it
+        // commits them, as the consumer stops at the write index. The range stops at the end
+        // of the buffer, and the slice borrows the producer mutably, so there's one at a time.
+        unsafe { self.ring.slice(start, free.min(N - start)) }
+    }
+
+    /// Hand `count` bytes of the last grant to the consumer.
+    pub fn commit(&mut self, count: usize) {
+        // Not `free`, which is 0 if a `RingFence` fenced the ring since the grant
+        debug_assert!(count <= N - self.ring.len());
+        if count > 0 {
+            Ring::<N>::advance(&self.ring.write, count);
+            self.ring.readable.wake();
+        }
+    }
+
+    /// Write `data` `offset` bytes past what's been committed, without committing it, so
+    /// something can be built up a piece at a time and then committed whole, or not at all.
+    /// Writes nothing, and returns false, if it doesn't all fit.
+    #[cfg(feature = "net-logger")]
+    pub(crate) fn stage(&mut self, offset: usize, data: &[u8]) -> bool {
+        if offset + data.len() > self.free() {
+            return false;
+        }
+        let start = (self.ring.write.load(Ordering::Relaxed) + offset) % N;
+        let first = data.len().min(N - start);
+        // SAFETY: as for `grant`, the free bytes from the write index belong to the producer.
+        // These two ranges are within them, one up to the end of the buffer and the rest from
+        // the start, and they only live for the copy.
+        unsafe {
+            self.ring.slice(start, first).copy_from_slice(&data[..first]);
+            self.ring.slice(0, data.len() - first).copy_from_slice(&data[first..]);
+        }
+        true
+    }
+
+    /// Write as much of `data` as fits. Returns the number of bytes written.
+    pub fn try_write(&mut self, data: &[u8]) -> usize {
+        let mut written = 0;
+        while written < data.len() {
+            let grant = self.grant();
+            let count = grant.len().min(data.len() - written);
+            if count == 0 {
+                break;
+            }
+            grant[..count].copy_from_slice(&data[written..written + count]);
+            self.commit(count);
+            written += count;
+        }
+        written
+    }
+
+    /// Wait until at least one byte of `data` can be written, then write as much as fits.
+    pub async fn write(&mut self, data: &[u8]) -> Result<usize, NoConsumer> {
+        poll_fn(|cx| {
+            if self.is_closed() {
+                return Poll::Ready(Err(NoConsumer));
+            }
+            self.ring.writable.register(cx.waker());
+            match self.try_write(data) {
+                0 if !data.is_empty() => Poll::Pending,
+                written => Poll::Ready(Ok(written)),
+            }
+        }).await
+    }
+
+    pub async fn write_all(&mut self, mut data: &[u8]) -> Result<(), NoConsumer> {
+        while !data.is_empty() {
+            let written = self.write(data).await?;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+}
+
+impl <const N: usize> Drop for RingProducer<'_, N> {
+    fn drop(&mut self) {
+        self.ring.producer_closed.store(true, Ordering::Release);
+        self.ring.readable.wake();
+    }
+}
+
+impl <const N: usize> Sink for RingProducer<'_, N> {
+    type Item = u8;
+    type Error = NoConsumer;
+
+    async fn send(&mut self, item: u8) -> Result<(), NoConsumer> {
+        self.write_all(&[item]).await
+    }
+}
+
+impl <const N: usize> ByteSink for RingProducer<'_, N> {
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), NoConsumer> {
+        RingProducer::write_all(self, data).await
+    }
+}
+
+/// Fences a ring for its producer, as `RingProducer::fence`.
+pub struct RingFence<'a, const N: usize> {
+    ring: &'a Ring<N>,
+}
+
+impl <const N: usize> RingFence<'_, N> {
+    pub fn fence(&self) {
+        self.ring.fence();
+    }
+
+    /// True if there's nothing written and unread, so nothing for a fence to throw away.
+    pub fn is_empty(&self) -> bool {
+        self.ring.len() == 0
+    }
+}
+
+pub struct RingConsumer<'a, const N: usize> {
+    ring: &'a Ring<N>,
+}
+
+impl <const N: usize> RingConsumer<'_, N> {
+    /// The number of bytes that can be read.
+    pub fn available(&self) -> usize {
+        self.discard_fenced();
+        self.ring.len()
+    }
+
+    fn discard_fenced(&self) {
+        // The producer can't write while the ring is fenced, so everything up to the write
+        // index was written before the fence.
+        if self.ring.fenced.load(Ordering::Acquire) {
+            let write = self.ring.write.load(Ordering::Acquire);
+            self.ring.read.store(write, Ordering::Release);
+            self.ring.boundary.store(true, Ordering::Relaxed);
+            self.ring.fenced.store(false, Ordering::Release);
+            self.ring.writable.wake();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.available() == 0
+    }
+
+    /// True, once, after fenced bytes have been discarded, so what's been read since starts a
+    /// new session.
+    pub fn take_boundary(&mut self) -> bool {
+        let boundary = self.ring.boundary.load(Ordering::Relaxed);
+        if boundary {
+            self.ring.boundary.store(false, Ordering::Relaxed);
+        }
+        boundary
+    }
+
+    /// True once the producer has been dropped. There may still be bytes to read.
+    pub fn is_closed(&self) -> bool {
+        self.ring.producer_closed.load(Ordering::Acquire)
+    }
+
+    /// Wake `waker` the next time the producer writes something, or goes. This replaces the
+    /// waker of any `read` in progress, as for `RingProducer::register_writable`.
+    pub fn register_readable(&self, waker: &Waker) {
+        self.ring.readable.register(waker);
+    }
+
+    /// The largest contiguous readable slice. Read some of it, then `release` what was read.
+    pub fn peek(&self) -> &[u8] {
+        let available = self.available();
+        let start = self.ring.read.load(Ordering::Relaxed) % N;
+        // SAFETY: the `available` bytes from the read index belong to the consumer until it
+        // releases them, as the producer stops at the read index, and `release` can't be
+        // called while the slice borrows the consumer. The range stops at the end of the buffer.
+        unsafe { self.ring.slice(start, available.min(N - start)) }
+    }
+
+    /// Hand `count` bytes back to the producer.
+    pub fn release(&mut self, count: usize) {
+        debug_assert!(count <= self.available());
+        if count > 0 {
+            Ring::<N>::advance(&self.ring.read, count);
+            self.ring.writable.wake();
+        }
+    }
+
+    /// Read as much as is available into `buf`. Returns the number of bytes read.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> usize {
+        let mut read = 0;
+        while read < buf.len() {
+            let data = self.peek();
+            let count = data.len().min(buf.len() - read);
+            if count == 0 {
+                break;
+            }
+            buf[read..read + count].copy_from_slice(&data[..count]);
+            self.release(count);
+            read += count;
+        }
+        read
+    }
+
+    /// Discard everything that has been written so far.
+    pub fn clear(&mut self) {
+        self.release(self.available());
+    }
+
+    /// Wait until there is something to read, then read as much as fits into `buf`.
+    /// Returns 0 once the producer has gone and the ring is empty.
+    pub async fn read(&mut self, buf: &mut [u8]) -> usize {
+        poll_fn(|cx| {
+            let closed = self.is_closed();
+            self.ring.readable.register(cx.waker());
+            match self.try_read(buf) {
+                0 if !closed && !buf.is_empty() => Poll::Pending,
+                read => Poll::Ready(read),
+            }
+        }).await
+    }
+}
+
+impl <const N: usize> Drop for RingConsumer<'_, N> {
+    fn drop(&mut self) {
+        self.ring.consumer_closed.store(true, Ordering::Release);
+        self.ring.writable.wake();
+    }
+}
+
+impl <const N: usize> Stream for RingConsumer<'_, N> {
+    type Item = u8;
+
+    async fn next(&mut self) -> Option<u8> {
+        let mut byte = [0u8];
+        match RingConsumer::read(self, &mut byte).await {
+            0 => None,
+            _ => Some(byte[0]),
+        }
+    }
+}
+
+impl <const N: usize> ByteStream for RingConsumer<'_, N> {
+    async fn read(&mut self, buf: &mut [u8]) -> usize {
+        RingConsumer::read(self, buf).await
+    }
+
+    fn take_boundary(&mut self) -> bool {
+        RingConsumer::take_boundary(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{ atomic::{ AtomicUsize, Ordering }, Arc },
+        task::{ Context, Wake },
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl CountingWaker {
+        fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Write and read `count` bytes, to move both indices on.
+    fn skip<const N: usize>(producer: &mut RingProducer<N>, consumer: &mut RingConsumer<N>, mut count: usize) {
+        while count > 0 {
+            let written = producer.try_write(&[0xee; 4][..count.min(4)]);
+            assert_eq!(consumer.try_read(&mut [0; 4]), written);
+            count -= written;
+        }
+    }
+
+    #[test]
+    fn empty_and_full() {
+        let mut ring = Ring::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+        assert!(consumer.is_empty());
+        assert!(consumer.peek().is_empty());
+        assert_eq!(consumer.try_read(&mut [0; 8]), 0);
+        assert_eq!(producer.free(), 8);
+
+        assert_eq!(producer.try_write(&[1, 2, 3, 4, 5, 6, 7, 8, 9]), 8);
+        assert!(producer.is_full());
+        assert!(producer.grant().is_empty());
+        assert_eq!(producer.try_write(&[10]), 0);
+        assert_eq!(consumer.available(), 8);
+
+        let mut buf = [0; 9];
+        assert_eq!(consumer.try_read(&mut buf), 8);
+        assert_eq!(buf[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(consumer.is_empty());
+        assert_eq!(producer.free(), 8);
+    }
+
+    #[test]
+    fn full_and_empty_at_every_index() {
+        // Starting at each index in 0..2N covers both ends of the buffer, and the wrap of the
+        // indices at 2N, where a full ring and an empty one would otherwise look the same
+        for start in 0..16 {
+            let mut ring = Ring::<8>::new();
+            let (mut producer, mut consumer) = ring.split();
+            skip(&mut producer, &mut consumer, start);
+            assert!(consumer.is_empty(), "empty from {}", start);
+
+            let data: [u8; 8] = core::array::from_fn(|i| start as u8 * 16 + i as u8);
+            assert_eq!(producer.try_write(&data), 8);
+            assert!(producer.is_full(), "full from {}", start);
+            assert_eq!(consumer.available(), 8);
+
+            let mut buf = [0; 8];
+            assert_eq!(consumer.try_read(&mut buf), 8);
+            assert_eq!(buf, data, "from {}", start);
+            assert!(consumer.is_empty());
+        }
+    }
+
+    #[test]
+    fn indices_wrap() {
+        let mut ring = Ring::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+        let mut next = 0u8;
+        let mut expected = 0u8;
+        // Odd sized writes, so the data lands everywhere relative to the wrap
+        for _ in 0..100 {
+            let data = [next, next.wrapping_add(1), next.wrapping_add(2)];
+            assert_eq!(producer.try_write(&data), 3);
+            next = next.wrapping_add(3);
+            let mut buf = [0; 3];
+            assert_eq!(consumer.try_read(&mut buf), 3);
+            for byte in buf {
+                assert_eq!(byte, expected);
+                expected = expected.wrapping_add(1);
+            }
+        }
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn peek_and_release_across_the_end() {
+        let mut ring = Ring::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+        skip(&mut producer, &mut consumer, 6);
+
+        // Only the two bytes before the end are contiguous
+        assert_eq!(producer.grant().len(), 2);
+        assert_eq!(producer.try_write(&[1, 2, 3, 4, 5]), 5);
+        assert_eq!(producer.grant().len(), 3);
+        assert_eq!(consumer.available(), 5);
+        assert_eq!(consumer.peek(), [1, 2]);
+        consumer.release(1);
+        assert_eq!(consumer.peek(), [2]);
+        consumer.release(1);
+        assert_eq!(consumer.peek(), [3, 4, 5]);
+        consumer.release(3);
+        assert!(consumer.is_empty());
+        assert!(consumer.peek().is_empty());
+
+        // A grant committed in part leaves the rest free
+        let grant = producer.grant();
+        assert_eq!(grant.len(), 8 - 3);
+        grant[..2].copy_from_slice(&[6, 7]);
+        producer.commit(2);
+        assert_eq!(consumer.peek(), [6, 7]);
+    }
+
+    #[test]
+    fn fence_discards_what_was_written() {
+        let mut ring = Ring::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+        skip(&mut producer, &mut consumer, 5);
+        producer.try_write(&[1, 2, 3, 4, 5]);
+        producer.fence();
+        assert!(producer.is_fenced());
+        assert_eq!(producer.free(), 0);
+        assert_eq!(producer.try_write(&[6]), 0);
+
+        assert!(consumer.is_empty());
+        assert!(!producer.is_fenced());
+        assert_eq!(producer.try_write(&[6]), 1);
+        assert_eq!(consumer.peek(), [6]);
+    }
+
+    #[test]
+    fn fence_is_a_boundary() {
+        let mut ring = Ring::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+        let fence = producer.fencer();
+        assert!(fence.is_empty());
+        producer.try_write(&[1, 2]);
+        assert!(!fence.is_empty());
+        assert!(!consumer.take_boundary());
+
+        fence.fence();
+        assert!(producer.is_fenced());
+        // Reported after what's read since, not before
+        assert!(!consumer.take_boundary());
+        assert_eq!(consumer.try_read(&mut [0; 8]), 0);
+        assert!(consumer.take_boundary());
+        assert!(!consumer.take_boundary());
+    }
+
+    #[test]
+    fn a_fence_part_way_through_a_write() {
+        let mut ring = Ring::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+        let fence = producer.fencer();
+        producer.try_write(&[1, 2]);
+
+        // Fenced while the producer holds a grant, and seen before it commits
+        producer.grant()[..2].copy_from_slice(&[3, 4]);
+        fence.fence();
+        assert!(consumer.is_empty());
+        producer.commit(2);
+        let mut buf = [0; 8];
+        assert_eq!(consumer.try_read(&mut buf), 2);
+        assert_eq!(buf[..2], [3, 4]);
+        assert!(consumer.take_boundary());
+
+        // Fenced and not yet seen, so the commit is discarded with the rest
+        producer.grant()[..1].copy_from_slice(&[5]);
+        fence.fence();
+        producer.commit(1);
+        assert_eq!(consumer.try_read(&mut buf), 0);
+        assert!(consumer.take_boundary());
+    }
+
+    #[test]
+    fn wakes_the_other_side() {
+        let mut ring = Ring::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+        let readable = Arc::new(CountingWaker::default());
+        let writable = Arc::new(CountingWaker::default());
+        consumer.register_readable(&Waker::from(readable.clone()));
+        producer.register_writable(&Waker::from(writable.clone()));
+
+        // Nothing written, nothing woken
+        assert_eq!(producer.try_write(&[]), 0);
+        producer.commit(0);
+        assert_eq!(readable.count(), 0);
+        producer.try_write(&[1, 2]);
+        assert_eq!(readable.count(), 1);
+
+        // The registration is used up, so each wake needs another
+        producer.try_write(&[3]);
+        assert_eq!(readable.count(), 1);
+
+        consumer.release(0);
+        assert_eq!(writable.count(), 0);
+        consumer.release(1);
+        assert_eq!(writable.count(), 1);
+
+        consumer.register_readable(&Waker::from(readable.clone()));
+        producer.fence();
+        assert_eq!(readable.count(), 2);
+        producer.register_writable(&Waker::from(writable.clone()));
+        assert!(consumer.is_empty());
+        assert_eq!(writable.count(), 2);
+
+        consumer.register_readable(&Waker::from(readable.clone()));
+        drop(producer);
+        assert_eq!(readable.count(), 3);
+        assert!(consumer.is_closed());
+    }
+
+    #[test]
+    fn wakes_the_producer_when_the_consumer_goes() {
+        let mut ring = Ring::<8>::new();
+        let (producer, consumer) = ring.split();
+        let writable = Arc::new(CountingWaker::default());
+        producer.register_writable(&Waker::from(writable.clone()));
+        drop(consumer);
+        assert_eq!(writable.count(), 1);
+        assert!(producer.is_closed());
+    }
+
+    #[test]
+    fn read_and_write_wait() {
+        let mut ring = Ring::<4>::new();
+        let (mut producer, mut consumer) = ring.split();
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut buf = [0; 8];
+
+        assert_eq!(pin!(consumer.read(&mut buf)).poll(&mut cx), Poll::Pending);
+        assert_eq!(pin!(producer.write(&[1, 2, 3, 4, 5])).poll(&mut cx), Poll::Ready(Ok(4)));
+        assert_eq!(counter.count(), 1);
+        assert_eq!(pin!(producer.write(&[5])).poll(&mut cx), Poll::Pending);
+        assert_eq!(pin!(consumer.read(&mut buf)).poll(&mut cx), Poll::Ready(4));
+        assert_eq!(counter.count(), 2);
+
+        drop(producer);
+        assert_eq!(pin!(consumer.read(&mut buf)).poll(&mut cx), Poll::Ready(0));
+    }
+}
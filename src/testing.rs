@@ -0,0 +1,126 @@
+
+//! What the unit tests share.
+
+use core::cell::Cell;
+use std::{ collections::VecDeque, sync::Mutex, vec::Vec };
+
+use usb_device::{
+    bus::{ PollResult, UsbBus },
+    endpoint::{ EndpointAddress, EndpointType },
+    UsbDirection,
+    UsbError,
+};
+
+#[cfg(not(feature = "net-logger"))]
+#[defmt::global_logger]
+struct Logger;
+
+#[cfg(not(feature = "net-logger"))]
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
+
+std::thread_local! {
+    static NOW: Cell<u64> = const { Cell::new(0) };
+}
+
+/// A clock that only moves when the test moves it, separately for each test's thread.
+pub(crate) struct TestClock;
+
+impl TestClock {
+    pub(crate) fn advance(duration: fugit::MicrosDurationU64) {
+        NOW.set(NOW.get() + duration.to_micros());
+    }
+}
+
+impl crate::usb::Clock for TestClock {
+    type Instant = fugit::Instant<u64, 1, 1_000_000>;
+
+    fn now() -> Self::Instant {
+        Self::Instant::from_ticks(NOW.get())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Endpoints {
+    pub(crate) next: [u8; 2],
+    /// Packets from the host for each OUT endpoint.
+    pub(crate) out: [VecDeque<Vec<u8>>; 8],
+    /// The next packet on endpoint 0 is a SETUP.
+    pub(crate) setup: bool,
+    /// Packets written to each IN endpoint.
+    pub(crate) written: [Vec<Vec<u8>>; 8],
+}
+
+/// A bus the test plays the host on.
+#[derive(Default)]
+pub(crate) struct FakeBus(pub(crate) Mutex<Endpoints>);
+
+impl FakeBus {
+    #[cfg(feature = "serial")]
+    pub(crate) fn written(&self, ep: EndpointAddress) -> Vec<Vec<u8>> {
+        core::mem::take(&mut self.0.lock().unwrap().written[ep.index()])
+    }
+}
+
+impl UsbBus for FakeBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8) -> usb_device::Result<EndpointAddress> {
+
+        let next = &mut self.0.get_mut().unwrap().next[ep_dir as usize >> 7];
+        Ok(ep_addr.unwrap_or_else(|| {
+            *next += 1;
+            EndpointAddress::from_parts(*next as usize, ep_dir)
+        }))
+    }
+
+    fn enable(&mut self) {}
+    fn reset(&self) {}
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        self.0.lock().unwrap().written[ep_addr.index()].push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+        let mut endpoints = self.0.lock().unwrap();
+        if ep_addr.index() == 0 {
+            endpoints.setup = false;
+        }
+        let packet = endpoints.out[ep_addr.index()].pop_front().ok_or(UsbError::WouldBlock)?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    fn suspend(&self) {}
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let endpoints = self.0.lock().unwrap();
+        let ep_out = endpoints.out.iter().enumerate()
+            .filter(|(_, packets)| !packets.is_empty())
+            .fold(0, |bits, (index, _)| bits | 1 << index);
+        let ep_setup = endpoints.setup as u16;
+        match ep_out {
+            0 => PollResult::None,
+            _ => PollResult::Data { ep_out: ep_out & !ep_setup, ep_in_complete: 0, ep_setup },
+        }
+    }
+}
@@ -0,0 +1,299 @@
+
+use core::{ cell::Cell, task::Waker };
+
+use critical_section::Mutex;
+use defmt::{ debug, warn };
+use micropb::heapless::Vec;
+use rtic_sync::channel::{ Channel, ReceiveError, Receiver, Sender, TrySendError };
+
+use smoltcp::{
+    iface::{ SocketHandle, SocketSet },
+    socket::udp,
+    wire::IpEndpoint,
+};
+
+use crate::usb::{ NetworkRecv, NetworkSend };
+
+/// One UDP payload. Each datagram carries exactly one encoded message.
+pub type Datagram<const N: usize> = Vec<u8, N>;
+
+/// Where a `UdpChannel` sends its datagrams.
+#[derive(Clone, Copy)]
+pub enum UdpPeer {
+    /// Reply to whoever sent the most recent datagram. Anything sent before the first datagram
+    /// is received is dropped.
+    LastSender,
+    /// Always send to this endpoint.
+    Fixed(IpEndpoint),
+}
+
+/// Storage for a UDP channel of datagrams of up to `N` bytes, queueing up to `P` in each
+/// direction.
+pub struct UdpChannelStorage<const N: usize, const P: usize> {
+    pub sender: Channel<Datagram<N>, P>,
+    pub receiver: Channel<Datagram<N>, P>,
+    last_sender: Mutex<Cell<Option<IpEndpoint>>>,
+    tx_metadata: [udp::PacketMetadata; P],
+    rx_metadata: [udp::PacketMetadata; P],
+    tx_storage: [u8; N],
+    rx_storage: [u8; N],
+}
+
+impl <const N: usize, const P: usize> UdpChannelStorage<N, P> {
+    pub const fn new() -> Self {
+        Self {
+            sender: Channel::new(),
+            receiver: Channel::new(),
+            last_sender: Mutex::new(Cell::new(None)),
+            tx_metadata: [udp::PacketMetadata::EMPTY; P],
+            rx_metadata: [udp::PacketMetadata::EMPTY; P],
+            tx_storage: [0x0; N],
+            rx_storage: [0x0; N],
+        }
+    }
+}
+
+impl <const N: usize, const P: usize> Default for UdpChannelStorage<N, P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct UdpRecvChannel<'a, const N: usize, const P: usize> {
+    port: u16,
+    handle: SocketHandle,
+    last_sender: &'a Mutex<Cell<Option<IpEndpoint>>>,
+    sender: Sender<'a, Datagram<N>, P>,
+}
+
+impl <const N: usize, const P: usize> NetworkRecv for UdpRecvChannel<'_, N, P> {
+    fn try_recv(&mut self, sockets: &mut SocketSet<'_>) -> bool {
+        let socket: &mut udp::Socket = sockets.get_mut(self.handle);
+        let mut received = false;
+
+        while !self.sender.is_full() {
+            match socket.recv() {
+                Ok((data, metadata)) => {
+                    match Datagram::from_slice(data) {
+                        Ok(datagram) => match self.sender.try_send(datagram) {
+                            Ok(()) => {
+                                // Only once it's queued, so replies go to a peer the
+                                // application has heard from
+                                critical_section::with(|cs| self.last_sender.borrow(cs).set(Some(metadata.endpoint)));
+                                received = true;
+                            },
+                            Err(TrySendError::Full(_)) => unreachable!(),
+                            Err(TrySendError::NoReceiver(_)) => { panic!("no receiver"); },
+                        },
+                        Err(()) => warn!("dropped {} byte datagram on {}", data.len(), self.port),
+                    }
+                },
+                Err(udp::RecvError::Exhausted) => break,
+                Err(udp::RecvError::Truncated) => warn!("dropped truncated datagram on {}", self.port),
+            }
+        }
+
+        if received {
+            debug!("received datagrams on {}", self.port);
+        }
+        received
+    }
+
+    /// An rtic-sync channel only wakes a task waiting on it.
+    fn register(&self, _waker: &Waker) -> bool {
+        false
+    }
+}
+
+pub struct UdpSendChannel<'a, const N: usize, const P: usize> {
+    port: u16,
+    handle: SocketHandle,
+    peer: UdpPeer,
+    last_sender: &'a Mutex<Cell<Option<IpEndpoint>>>,
+    receiver: Receiver<'a, Datagram<N>, P>,
+    /// Taken from the channel, but the socket hadn't room for it.
+    pending: Option<Datagram<N>>,
+}
+
+impl <const N: usize, const P: usize> NetworkSend for UdpSendChannel<'_, N, P> {
+    fn try_send(&mut self, sockets: &mut SocketSet<'_>) -> Result<bool, ReceiveError> {
+        let socket: &mut udp::Socket = sockets.get_mut(self.handle);
+        let peer = match self.peer {
+            UdpPeer::Fixed(endpoint) => Some(endpoint),
+            UdpPeer::LastSender => critical_section::with(|cs| self.last_sender.borrow(cs).get()),
+        };
+
+        let mut count: usize = 0;
+        loop {
+            let datagram = match self.pending.take() {
+                Some(datagram) => datagram,
+                None => match self.receiver.try_recv() {
+                    Ok(datagram) => datagram,
+                    Err(ReceiveError::Empty) => break,
+                    Err(err) => return Err(err),
+                },
+            };
+            let Some(endpoint) = peer else {
+                warn!("no peer yet, dropped datagram on {}", self.port);
+                continue;
+            };
+            match socket.send_slice(&datagram, endpoint) {
+                Ok(()) => { count += 1; },
+                Err(udp::SendError::BufferFull) => {
+                    self.pending = Some(datagram);
+                    break;
+                },
+                Err(e) => warn!("failed to send datagram on {}: {}", self.port, e),
+            }
+        }
+        Ok(count != 0)
+    }
+
+    fn register(&self, _waker: &Waker) -> bool {
+        false
+    }
+}
+
+pub struct UdpNetworkEndpoint<'a, const N: usize, const P: usize> {
+    pub send: UdpSendChannel<'a, N, P>,
+    pub recv: UdpRecvChannel<'a, N, P>,
+}
+
+pub struct UdpApplicationEndpoint<'a, const N: usize, const P: usize> {
+    pub send: Sender<'a, Datagram<N>, P>,
+    pub recv: Receiver<'a, Datagram<N>, P>,
+}
+
+pub struct UdpChannel<'a, const N: usize, const P: usize> {
+    pub net: UdpNetworkEndpoint<'a, N, P>,
+    pub app: UdpApplicationEndpoint<'a, N, P>,
+}
+
+impl <'a, const N: usize, const P: usize> UdpChannel<'a, N, P> {
+    pub(crate) fn new(
+        port: u16,
+        peer: UdpPeer,
+        sockets: &mut SocketSet<'a>,
+        storage: &'a mut UdpChannelStorage<N, P>) -> Self {
+
+        let rx_buffer = udp::PacketBuffer::new(&mut storage.rx_metadata[..], &mut storage.rx_storage[..]);
+        let tx_buffer = udp::PacketBuffer::new(&mut storage.tx_metadata[..], &mut storage.tx_storage[..]);
+        let mut socket = udp::Socket::new(rx_buffer, tx_buffer);
+        socket.bind(port).unwrap();
+        let handle = sockets.add(socket);
+
+        let last_sender = &storage.last_sender;
+        let (net_send, app_recv) = storage.receiver.split();
+        let (app_send, net_recv) = storage.sender.split();
+
+        UdpChannel {
+            net: UdpNetworkEndpoint {
+                send: UdpSendChannel { port, handle, peer, last_sender, receiver: net_recv, pending: None },
+                recv: UdpRecvChannel { port, handle, last_sender, sender: net_send },
+            },
+            app: UdpApplicationEndpoint { send: app_send, recv: app_recv },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::{
+        iface::{ Config, Interface },
+        phy::{ Loopback, Medium },
+        time::{ Duration, Instant },
+        wire::{ EthernetAddress, IpAddress, IpCidr },
+    };
+
+    use super::*;
+
+    const ADDRESS: IpAddress = IpAddress::v4(127, 0, 0, 1);
+    const PORT: u16 = 1234;
+    const PEER: u16 = 5678;
+
+    #[test]
+    fn keeps_a_datagram_the_socket_has_no_room_for() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let config = Config::new(EthernetAddress([0x02, 0, 0, 0, 0, 1]).into());
+        let mut interface = Interface::new(config, &mut device, Instant::ZERO);
+        interface.update_ip_addrs(|addrs| addrs.push(IpCidr::new(ADDRESS, 8)).unwrap());
+
+        let mut sockets = SocketSet::new(vec![]);
+        let storage = Box::leak(Box::new(UdpChannelStorage::<64, 4>::new()));
+        let mut channel = UdpChannel::new(PORT, UdpPeer::Fixed((ADDRESS, PEER).into()), &mut sockets, storage);
+        let mut peer = udp::Socket::new(
+            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 256]),
+            udp::PacketBuffer::new(vec![], vec![]));
+        peer.bind(PEER).unwrap();
+        let peer = sockets.add(peer);
+
+        for byte in 1..=3 {
+            channel.app.send.try_send(Datagram::from_slice(&[byte; 40]).unwrap()).unwrap();
+        }
+        // Only one fits the socket's 64 bytes at a time
+        let mut received = std::vec::Vec::new();
+        let mut now = Instant::ZERO;
+        for _ in 0..3 {
+            assert_eq!(channel.net.send.try_send(&mut sockets), Ok(true));
+            assert_eq!(channel.net.send.try_send(&mut sockets), Ok(false));
+            for _ in 0..10 {
+                now += Duration::from_millis(50);
+                interface.poll(now, &mut device, &mut sockets);
+            }
+            while let Ok((data, _)) = sockets.get_mut::<udp::Socket>(peer).recv() {
+                received.push(data.to_vec());
+            }
+        }
+        assert_eq!(received, [[1; 40], [2; 40], [3; 40]]);
+        assert_eq!(channel.net.send.try_send(&mut sockets), Ok(false));
+    }
+
+    #[test]
+    fn replies_to_the_last_datagram_queued() {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let config = Config::new(EthernetAddress([0x02, 0, 0, 0, 0, 1]).into());
+        let mut interface = Interface::new(config, &mut device, Instant::ZERO);
+        interface.update_ip_addrs(|addrs| addrs.push(IpCidr::new(ADDRESS, 8)).unwrap());
+
+        let mut sockets = SocketSet::new(vec![]);
+        let storage = Box::leak(Box::new(UdpChannelStorage::<64, 2>::new()));
+        let mut channel = UdpChannel::new(PORT, UdpPeer::LastSender, &mut sockets, storage);
+        let peers = [PEER, PEER + 1].map(|port| {
+            let mut peer = udp::Socket::new(
+                udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 256]),
+                udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0; 256]));
+            peer.bind(port).unwrap();
+            sockets.add(peer)
+        });
+        let mut now = Instant::ZERO;
+        let mut poll = |sockets: &mut SocketSet<'_>| {
+            for _ in 0..10 {
+                now += Duration::from_millis(50);
+                interface.poll(now, &mut device, sockets);
+            }
+        };
+
+        // The first peer fills the application's queue, so the second's waits in the socket
+        for byte in 1..=2 {
+            sockets.get_mut::<udp::Socket>(peers[0]).send_slice(&[byte; 8], (ADDRESS, PORT)).unwrap();
+        }
+        poll(&mut sockets);
+        assert!(channel.net.recv.try_recv(&mut sockets));
+        sockets.get_mut::<udp::Socket>(peers[1]).send_slice(&[3; 8], (ADDRESS, PORT)).unwrap();
+        poll(&mut sockets);
+        assert!(!channel.net.recv.try_recv(&mut sockets));
+
+        let mut reply = |sockets: &mut SocketSet<'_>, byte: u8| {
+            channel.app.send.try_send(Datagram::from_slice(&[byte; 4]).unwrap()).unwrap();
+            assert_eq!(channel.net.send.try_send(sockets), Ok(true));
+            poll(sockets);
+            peers.map(|peer| sockets.get_mut::<udp::Socket>(peer).recv().ok().map(|(data, _)| data.to_vec()))
+        };
+        assert_eq!(reply(&mut sockets, 4), [Some(vec![4; 4]), None]);
+
+        // Once there's room, it's queued, and the next reply goes to it
+        assert_eq!(channel.app.recv.try_recv().unwrap(), [1; 8]);
+        assert!(channel.net.recv.try_recv(&mut sockets));
+        assert_eq!(reply(&mut sockets, 5), [None, Some(vec![5; 4])]);
+    }
+}
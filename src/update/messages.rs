@@ -0,0 +1,346 @@
+
+//! The messages in `proto/update.proto`, written out the way micropb-gen would generate them.
+
+use micropb::{
+    heapless::Vec, size::{ sizeof_int32, sizeof_len_record, sizeof_varint32 },
+    DecodeError, MessageDecode, MessageEncode, PbDecoder, PbEncoder, PbRead, PbWrite,
+    Presence, Tag, WIRE_TYPE_LEN, WIRE_TYPE_VARINT,
+};
+
+use crate::rpc::{ Request, Response };
+
+/// The most image bytes in one `Chunk`.
+pub const UPDATE_CHUNK: usize = 256;
+
+/// The length of a SHA-256 digest.
+pub const DIGEST_SIZE: usize = 32;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Begin {
+    pub size: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Chunk {
+    pub offset: u32,
+    pub data: Vec<u8, UPDATE_CHUNK>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Verify {
+    pub sha256: Vec<u8, DIGEST_SIZE>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Commit {}
+
+// Nearly every request is a chunk, so there's nothing to gain from boxing it
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Begin(Begin),
+    Chunk(Chunk),
+    Verify(Verify),
+    Commit(Commit),
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UpdateRequest {
+    pub id: u32,
+    pub body: Option<Body>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Status(pub i32);
+
+impl Status {
+    pub const OK: Self = Self(0);
+    pub const BAD_STATE: Self = Self(1);
+    pub const TOO_LARGE: Self = Self(2);
+    pub const BAD_OFFSET: Self = Self(3);
+    pub const BAD_LENGTH: Self = Self(4);
+    pub const FLASH_ERROR: Self = Self(5);
+    pub const BAD_DIGEST: Self = Self(6);
+    pub const SWAP_FAILED: Self = Self(7);
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UpdateResponse {
+    pub id: u32,
+    pub status: Status,
+    pub written: u32,
+}
+
+/// The body of an `UpdateResponse`: everything but the id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    pub status: Status,
+    pub written: u32,
+}
+
+impl Request for UpdateRequest {
+    type Body = Body;
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn into_body(self) -> Option<Body> {
+        self.body
+    }
+}
+
+impl Response for UpdateResponse {
+    type Body = Reply;
+
+    fn new(id: u32, body: Reply) -> Self {
+        UpdateResponse { id, status: body.status, written: body.written }
+    }
+
+    fn no_body(id: u32) -> Self {
+        UpdateResponse { id, status: Status::BAD_STATE, written: 0 }
+    }
+}
+
+fn encode_nested<W: PbWrite, M: MessageEncode>(encoder: &mut PbEncoder<W>, field: u32, message: &M) -> Result<(), W::Error> {
+    encoder.encode_tag(Tag::from_parts(field, WIRE_TYPE_LEN))?;
+    encoder.encode_varint32(message.compute_size() as u32)?;
+    message.encode(encoder)
+}
+
+fn decode_nested<R: PbRead, M: MessageDecode + Default>(decoder: &mut PbDecoder<R>) -> Result<M, DecodeError<R::Error>> {
+    let len = decoder.decode_varint32()? as usize;
+    decoder.decode_message(len)
+}
+
+impl MessageEncode for Begin {
+    const MAX_SIZE: Option<usize> = Some(6);
+
+    fn encode<W: PbWrite>(&self, encoder: &mut PbEncoder<W>) -> Result<(), W::Error> {
+        if self.size != 0 {
+            encoder.encode_tag(Tag::from_parts(1, WIRE_TYPE_VARINT))?;
+            encoder.encode_varint32(self.size)?;
+        }
+        Ok(())
+    }
+
+    fn compute_size(&self) -> usize {
+        match self.size {
+            0 => 0,
+            size => 1 + sizeof_varint32(size),
+        }
+    }
+}
+
+impl MessageDecode for Begin {
+    fn decode<R: PbRead>(&mut self, decoder: &mut PbDecoder<R>, len: usize) -> Result<(), DecodeError<R::Error>> {
+        let start = decoder.bytes_read();
+        while decoder.bytes_read() - start < len {
+            let tag = decoder.decode_tag()?;
+            match tag.field_num() {
+                1 => self.size = decoder.decode_varint32()?,
+                _ => decoder.skip_wire_value(tag.wire_type())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageEncode for Chunk {
+    const MAX_SIZE: Option<usize> = Some(6 + 3 + UPDATE_CHUNK);
+
+    fn encode<W: PbWrite>(&self, encoder: &mut PbEncoder<W>) -> Result<(), W::Error> {
+        if self.offset != 0 {
+            encoder.encode_tag(Tag::from_parts(1, WIRE_TYPE_VARINT))?;
+            encoder.encode_varint32(self.offset)?;
+        }
+        if !self.data.is_empty() {
+            encoder.encode_tag(Tag::from_parts(2, WIRE_TYPE_LEN))?;
+            encoder.encode_bytes(&self.data)?;
+        }
+        Ok(())
+    }
+
+    fn compute_size(&self) -> usize {
+        let mut size = 0;
+        if self.offset != 0 {
+            size += 1 + sizeof_varint32(self.offset);
+        }
+        if !self.data.is_empty() {
+            size += 1 + sizeof_len_record(self.data.len());
+        }
+        size
+    }
+}
+
+impl MessageDecode for Chunk {
+    fn decode<R: PbRead>(&mut self, decoder: &mut PbDecoder<R>, len: usize) -> Result<(), DecodeError<R::Error>> {
+        let start = decoder.bytes_read();
+        while decoder.bytes_read() - start < len {
+            let tag = decoder.decode_tag()?;
+            match tag.field_num() {
+                1 => self.offset = decoder.decode_varint32()?,
+                2 => decoder.decode_bytes(&mut self.data, Presence::Implicit)?,
+                _ => decoder.skip_wire_value(tag.wire_type())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageEncode for Verify {
+    const MAX_SIZE: Option<usize> = Some(2 + DIGEST_SIZE);
+
+    fn encode<W: PbWrite>(&self, encoder: &mut PbEncoder<W>) -> Result<(), W::Error> {
+        if !self.sha256.is_empty() {
+            encoder.encode_tag(Tag::from_parts(1, WIRE_TYPE_LEN))?;
+            encoder.encode_bytes(&self.sha256)?;
+        }
+        Ok(())
+    }
+
+    fn compute_size(&self) -> usize {
+        match self.sha256.len() {
+            0 => 0,
+            len => 1 + sizeof_len_record(len),
+        }
+    }
+}
+
+impl MessageDecode for Verify {
+    fn decode<R: PbRead>(&mut self, decoder: &mut PbDecoder<R>, len: usize) -> Result<(), DecodeError<R::Error>> {
+        let start = decoder.bytes_read();
+        while decoder.bytes_read() - start < len {
+            let tag = decoder.decode_tag()?;
+            match tag.field_num() {
+                1 => decoder.decode_bytes(&mut self.sha256, Presence::Implicit)?,
+                _ => decoder.skip_wire_value(tag.wire_type())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageEncode for Commit {
+    const MAX_SIZE: Option<usize> = Some(0);
+
+    fn encode<W: PbWrite>(&self, _encoder: &mut PbEncoder<W>) -> Result<(), W::Error> {
+        Ok(())
+    }
+
+    fn compute_size(&self) -> usize {
+        0
+    }
+}
+
+impl MessageDecode for Commit {
+    fn decode<R: PbRead>(&mut self, decoder: &mut PbDecoder<R>, len: usize) -> Result<(), DecodeError<R::Error>> {
+        let start = decoder.bytes_read();
+        while decoder.bytes_read() - start < len {
+            let tag = decoder.decode_tag()?;
+            decoder.skip_wire_value(tag.wire_type())?;
+        }
+        Ok(())
+    }
+}
+
+impl MessageEncode for UpdateRequest {
+    const MAX_SIZE: Option<usize> = None;
+
+    fn encode<W: PbWrite>(&self, encoder: &mut PbEncoder<W>) -> Result<(), W::Error> {
+        if self.id != 0 {
+            encoder.encode_tag(Tag::from_parts(1, WIRE_TYPE_VARINT))?;
+            encoder.encode_varint32(self.id)?;
+        }
+        match &self.body {
+            Some(Body::Begin(begin)) => encode_nested(encoder, 2, begin),
+            Some(Body::Chunk(chunk)) => encode_nested(encoder, 3, chunk),
+            Some(Body::Verify(verify)) => encode_nested(encoder, 4, verify),
+            Some(Body::Commit(commit)) => encode_nested(encoder, 5, commit),
+            None => Ok(()),
+        }
+    }
+
+    fn compute_size(&self) -> usize {
+        let mut size = 0;
+        if self.id != 0 {
+            size += 1 + sizeof_varint32(self.id);
+        }
+        size += match &self.body {
+            Some(Body::Begin(begin)) => 1 + sizeof_len_record(begin.compute_size()),
+            Some(Body::Chunk(chunk)) => 1 + sizeof_len_record(chunk.compute_size()),
+            Some(Body::Verify(verify)) => 1 + sizeof_len_record(verify.compute_size()),
+            Some(Body::Commit(commit)) => 1 + sizeof_len_record(commit.compute_size()),
+            None => 0,
+        };
+        size
+    }
+}
+
+impl MessageDecode for UpdateRequest {
+    fn decode<R: PbRead>(&mut self, decoder: &mut PbDecoder<R>, len: usize) -> Result<(), DecodeError<R::Error>> {
+        let start = decoder.bytes_read();
+        while decoder.bytes_read() - start < len {
+            let tag = decoder.decode_tag()?;
+            match tag.field_num() {
+                1 => self.id = decoder.decode_varint32()?,
+                2 => self.body = Some(Body::Begin(decode_nested(decoder)?)),
+                3 => self.body = Some(Body::Chunk(decode_nested(decoder)?)),
+                4 => self.body = Some(Body::Verify(decode_nested(decoder)?)),
+                5 => self.body = Some(Body::Commit(decode_nested(decoder)?)),
+                _ => decoder.skip_wire_value(tag.wire_type())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageEncode for UpdateResponse {
+    const MAX_SIZE: Option<usize> = Some(3 * 6 + 5);
+
+    fn encode<W: PbWrite>(&self, encoder: &mut PbEncoder<W>) -> Result<(), W::Error> {
+        if self.id != 0 {
+            encoder.encode_tag(Tag::from_parts(1, WIRE_TYPE_VARINT))?;
+            encoder.encode_varint32(self.id)?;
+        }
+        if self.status != Status::OK {
+            encoder.encode_tag(Tag::from_parts(2, WIRE_TYPE_VARINT))?;
+            encoder.encode_int32(self.status.0)?;
+        }
+        if self.written != 0 {
+            encoder.encode_tag(Tag::from_parts(3, WIRE_TYPE_VARINT))?;
+            encoder.encode_varint32(self.written)?;
+        }
+        Ok(())
+    }
+
+    fn compute_size(&self) -> usize {
+        let mut size = 0;
+        if self.id != 0 {
+            size += 1 + sizeof_varint32(self.id);
+        }
+        if self.status != Status::OK {
+            size += 1 + sizeof_int32(self.status.0);
+        }
+        if self.written != 0 {
+            size += 1 + sizeof_varint32(self.written);
+        }
+        size
+    }
+}
+
+impl MessageDecode for UpdateResponse {
+    fn decode<R: PbRead>(&mut self, decoder: &mut PbDecoder<R>, len: usize) -> Result<(), DecodeError<R::Error>> {
+        let start = decoder.bytes_read();
+        while decoder.bytes_read() - start < len {
+            let tag = decoder.decode_tag()?;
+            match tag.field_num() {
+                1 => self.id = decoder.decode_varint32()?,
+                2 => self.status = Status(decoder.decode_int32()?),
+                3 => self.written = decoder.decode_varint32()?,
+                _ => decoder.skip_wire_value(tag.wire_type())?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+
+//! Firmware updates over a channel, as an `rpc::Service`. The host sends `Begin` with the size
+//! of the image, then the image in order as `Chunk`s, then `Verify` with its SHA-256, and
+//! finally `Commit`, which hands over to the bootloader to swap it in on the next reboot.
+//!
+//! The image is written to a `NorFlash` from `embedded-storage`, usually the bootloader's DFU
+//! partition. Sectors are erased as the image reaches them, and `Verify` reads the whole image
+//! back rather than trusting what was written. A chunk that isn't the next one is refused with
+//! `BAD_OFFSET` and how much has been written, so a host that lost a response can carry on.
+
+mod messages;
+
+use core::cell::RefCell;
+
+use defmt::{ info, warn };
+use embedded_storage::nor_flash::{ NorFlash, NorFlashError };
+use sha2::{ Digest, Sha256 };
+
+use crate::rpc::Service;
+
+pub use messages::{
+    Begin, Body, Chunk, Commit, Reply, Status, UpdateRequest, UpdateResponse, Verify,
+    DIGEST_SIZE, UPDATE_CHUNK,
+};
+
+/// The largest flash write or read block `FirmwareUpdate` supports.
+const MAX_BLOCK: usize = 64;
+
+/// Swaps in a new image. With embassy-boot, for example, this marks the DFU partition as
+/// ready with `mark_updated`, and the bootloader swaps it on the next reboot.
+pub trait SwapOnReboot {
+    type Error;
+
+    /// Arrange for the `size` byte image that's just been verified to be booted next. It's up
+    /// to the application when to reboot: usually soon after the response has been sent.
+    fn swap(&mut self, size: u32) -> Result<(), Self::Error>;
+}
+
+enum State {
+    Idle,
+    Receiving {
+        size: u32,
+        written: u32,
+        /// The start of the flash that hasn't been erased yet.
+        erased: u32,
+    },
+    Verified { size: u32 },
+}
+
+struct Inner<F, S> {
+    flash: F,
+    swap: S,
+    state: State,
+}
+
+/// Writes an image to `flash`, and hands it to `swap` once it's verified.
+pub struct FirmwareUpdate<F, S> {
+    inner: RefCell<Inner<F, S>>,
+}
+
+fn reply(status: Status, written: u32) -> Reply {
+    Reply { status, written }
+}
+
+const fn round_up(value: u32, block: u32) -> u32 {
+    value.div_ceil(block) * block
+}
+
+impl <F: NorFlash, S: SwapOnReboot> FirmwareUpdate<F, S> {
+    pub fn new(flash: F, swap: S) -> Self {
+        assert!(F::WRITE_SIZE <= MAX_BLOCK, "flash write size over 64 bytes");
+        assert!(F::READ_SIZE <= MAX_BLOCK, "flash read size over 64 bytes");
+        FirmwareUpdate {
+            inner: RefCell::new(Inner { flash, swap, state: State::Idle }),
+        }
+    }
+
+    pub fn into_inner(self) -> (F, S) {
+        let inner = self.inner.into_inner();
+        (inner.flash, inner.swap)
+    }
+}
+
+impl <F: NorFlash, S: SwapOnReboot> Inner<F, S> {
+    fn handle(&mut self, body: Body) -> Reply {
+        match body {
+            Body::Begin(begin) => self.begin(begin.size),
+            Body::Chunk(chunk) => self.chunk(chunk.offset, &chunk.data),
+            Body::Verify(verify) => self.verify(&verify.sha256),
+            Body::Commit(_) => self.commit(),
+        }
+    }
+
+    fn begin(&mut self, size: u32) -> Reply {
+        self.state = State::Idle;
+        if size == 0 {
+            return reply(Status::BAD_LENGTH, 0);
+        }
+        let erased = size.checked_next_multiple_of(F::ERASE_SIZE as u32);
+        if erased.is_none_or(|erased| erased as usize > self.flash.capacity()) {
+            warn!("update: {} bytes won't fit", size);
+            return reply(Status::TOO_LARGE, 0);
+        }
+        info!("update: receiving {} bytes", size);
+        self.state = State::Receiving { size, written: 0, erased: 0 };
+        reply(Status::OK, 0)
+    }
+
+    fn chunk(&mut self, offset: u32, data: &[u8]) -> Reply {
+        let State::Receiving { size, written, erased } = self.state else {
+            return reply(Status::BAD_STATE, 0);
+        };
+        if offset != written {
+            return reply(Status::BAD_OFFSET, written);
+        }
+        let end = offset + data.len() as u32;
+        if end > size {
+            return reply(Status::TOO_LARGE, written);
+        }
+        // Only the last chunk can end part way through a write block
+        if data.is_empty() || (end < size && !data.len().is_multiple_of(F::WRITE_SIZE)) {
+            return reply(Status::BAD_LENGTH, written);
+        }
+
+        match self.write(offset, data, erased) {
+            Ok(erased) => {
+                self.state = State::Receiving { size, written: end, erased };
+                reply(Status::OK, end)
+            },
+            Err(err) => {
+                warn!("update: flash write at {} failed: {}", offset, defmt::Debug2Format(&err.kind()));
+                self.state = State::Idle;
+                reply(Status::FLASH_ERROR, written)
+            },
+        }
+    }
+
+    /// Write `data` at `offset`, erasing first if it reaches past `erased`. Returns the new
+    /// end of the erased flash.
+    fn write(&mut self, offset: u32, data: &[u8], mut erased: u32) -> Result<u32, F::Error> {
+        let end = round_up(offset + data.len() as u32, F::WRITE_SIZE as u32);
+        if end > erased {
+            let to = round_up(end, F::ERASE_SIZE as u32);
+            self.flash.erase(erased, to)?;
+            erased = to;
+        }
+
+        let whole = data.len() / F::WRITE_SIZE * F::WRITE_SIZE;
+        self.flash.write(offset, &data[..whole])?;
+        let rest = &data[whole..];
+        if !rest.is_empty() {
+            // The end of the image, padded to a whole write block as if it were erased flash
+            let mut block = [0xff; MAX_BLOCK];
+            block[..rest.len()].copy_from_slice(rest);
+            self.flash.write(offset + whole as u32, &block[..F::WRITE_SIZE])?;
+        }
+        Ok(erased)
+    }
+
+    fn verify(&mut self, sha256: &[u8]) -> Reply {
+        let (size, written) = match self.state {
+            State::Receiving { size, written, .. } => (size, written),
+            State::Verified { size } => (size, size),
+            State::Idle => return reply(Status::BAD_STATE, 0),
+        };
+        if written != size {
+            return reply(Status::BAD_STATE, written);
+        }
+
+        let mut digest = Sha256::new();
+        let mut block = [0; MAX_BLOCK];
+        let read = MAX_BLOCK / F::READ_SIZE * F::READ_SIZE;
+        let mut offset = 0;
+        while offset < size {
+            let count = (size - offset).min(read as u32) as usize;
+            let len = round_up(count as u32, F::READ_SIZE as u32) as usize;
+            if let Err(err) = self.flash.read(offset, &mut block[..len]) {
+                warn!("update: flash read at {} failed: {}", offset, defmt::Debug2Format(&err.kind()));
+                self.state = State::Idle;
+                return reply(Status::FLASH_ERROR, 0);
+            }
+            digest.update(&block[..count]);
+            offset += count as u32;
+        }
+
+        if digest.finalize().as_slice() != sha256 {
+            warn!("update: image doesn't match its digest");
+            self.state = State::Idle;
+            return reply(Status::BAD_DIGEST, 0);
+        }
+        info!("update: {} bytes verified", size);
+        self.state = State::Verified { size };
+        reply(Status::OK, size)
+    }
+
+    fn commit(&mut self) -> Reply {
+        let State::Verified { size } = self.state else {
+            return reply(Status::BAD_STATE, 0);
+        };
+        self.state = State::Idle;
+        match self.swap.swap(size) {
+            Ok(()) => {
+                info!("update: committed, swapping on reboot");
+                reply(Status::OK, size)
+            },
+            Err(_) => {
+                warn!("update: swap failed");
+                reply(Status::SWAP_FAILED, size)
+            },
+        }
+    }
+}
+
+impl <F: NorFlash, S: SwapOnReboot> Service for FirmwareUpdate<F, S> {
+    type Request = UpdateRequest;
+    type Response = UpdateResponse;
+
+    async fn call(&self, request: Body) -> Reply {
+        self.inner.borrow_mut().handle(request)
+    }
+}
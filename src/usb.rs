@@ -1,18 +1,40 @@
 
-use core::{future::poll_fn, marker::PhantomData};
+use core::{future::{ pending, poll_fn, Future }, marker::PhantomData, pin::pin, task::Waker};
 
-use futures::task::Poll;
+use embedded_hal_async::delay::DelayNs;
+use futures::{ future::select, task::Poll };
 
 use defmt::{ debug, error, info, warn };
 use fugit::Instant;
-use rtic_sync::channel::{ Channel, ReceiveError, Receiver, Sender, TrySendError};
+use micropb::heapless::Vec;
+use rtic_sync::channel::{ Channel, ReceiveError, Receiver, Sender, TrySendError };
 
 use smoltcp::{
     iface::{self, Interface, SocketHandle, SocketSet, SocketStorage }, 
+    phy::{ Device, DeviceCapabilities },
     socket::{ dhcpv4, tcp },  
-    wire::{ DhcpOption, EthernetAddress, IpCidr, Ipv4Address, Ipv4Cidr }
+    wire::{ DhcpOption, EthernetAddress, IpCidr, IpEndpoint, IpVersion, Ipv4Address, Ipv4Cidr }
 };
 
+use crate::{
+    addressing::{ Addressing, LinkLocal, LinkLocalEvent },
+    client::{ Connector, Remote, MAX_CLIENTS },
+    identity::{ UsbIdentity, MAX_LANGUAGES },
+    snoop::Snoop,
+    stream::ring::{ Ring, RingConsumer, RingFence, RingProducer },
+};
+
+#[cfg(feature = "dhcp-server")]
+use crate::dhcp_server::{ DhcpServer, DhcpServerStorage };
+#[cfg(feature = "ipv6")]
+use crate::slaac::Slaac;
+#[cfg(feature = "mdns")]
+use crate::mdns::{ Mdns, MdnsStorage, Service, MDNS_GROUP };
+#[cfg(feature = "serial")]
+use crate::serial::{ Serial, SerialEndpoint, SerialStorage, SERIAL_BUFFER };
+#[cfg(feature = "udp")]
+use crate::udp::{ UdpChannel, UdpChannelStorage, UdpPeer };
+
 use usbd_ethernet::{ Ethernet, DeviceState };
 use usb_device::{
     bus::UsbBus, 
@@ -24,24 +46,208 @@ pub use usb_device::bus::UsbBusAllocator;
 
 
 pub const IP_ADDRESS: Ipv4Address = Ipv4Address::new(0, 0, 0, 0);
-pub const MTU: u16 = 64;
+/// The NTB buffer size. usbd-ethernet fixes it, and negotiates nothing bigger with the host.
+pub const NTB_SIZE: usize = 2048;
+/// The largest IP MTU usbd-ethernet's 1514 byte Ethernet frames carry.
+pub const MAX_MTU: usize = 1500;
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Why `Gadget::new` couldn't make a gadget from its configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConfigError {
+    /// The DHCP server's subnet hasn't room for the gadget and the host, or the gadget's
+    /// address is the subnet's network or broadcast address.
+    DhcpServerSubnet,
+    /// The bulk endpoints' packet size isn't 8, 16, 32, 64 or 512.
+    MaxPacketSize,
+    /// The MTU is over `MAX_MTU`, under 576, or with IPv6, under 1280.
+    Mtu,
+    /// A link speed of zero.
+    LinkSpeed,
+    /// USB strings in more than `MAX_LANGUAGES` languages.
+    Languages,
+    /// A USB max power over 500mA.
+    MaxPower,
+}
+
+/// Everything `Gadget::new` needs to know, apart from where to keep it.
+#[derive(Clone, Copy)]
+pub struct GadgetConfig<'a> {
+    /// The host name, sent with DHCP requests and answered to over mDNS.
+    pub name: &'static [u8],
+    pub identity: UsbIdentity<'a>,
+    pub link: LinkConfig,
+    pub addressing: Addressing,
+    /// The MAC address of the host's end of the link.
+    pub interface_mac_address: [u8; 6],
+    /// The gadget's own MAC address.
+    pub gadget_mac_address: [u8; 6],
+    /// Seeds smoltcp and the local ports. Make it differ between gadgets, and between boots
+    /// if there's a source of randomness. Link-local addresses come from the MAC address
+    /// instead, so a gadget keeps the same one.
+    pub seed: u64,
+}
+
+impl GadgetConfig<'_> {
+    /// Check everything `Gadget::new` would, without making a gadget.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.link.validate()?;
+        if self.identity.strings.len() > MAX_LANGUAGES {
+            return Err(ConfigError::Languages);
+        }
+        if self.identity.max_power_ma > 500 {
+            return Err(ConfigError::MaxPower);
+        }
+        Ok(())
+    }
+}
+
+/// The link's parameters.
+#[derive(Clone, Copy)]
+pub struct LinkConfig {
+    /// The bulk endpoints' packet size: 8, 16, 32 or 64 at full speed, 512 at high speed.
+    /// Only 512 is allowed at high speed, and only a high speed bus can use it, but the gadget
+    /// can't tell which the bus is, so that's left to the caller.
+    pub max_packet_size: u16,
+    /// The IP MTU, from 576 up to `MAX_MTU`, and at least 1280 with IPv6. A smaller one
+    /// makes smaller TCP segments, so sockets can have smaller buffers.
+    pub mtu: usize,
+    /// The link speed reported to the host, in bits per second. It only shows in the host's
+    /// tools, and doesn't limit anything.
+    pub download_speed: u32,
+    pub upload_speed: u32,
+}
+
+impl LinkConfig {
+    /// Full speed USB.
+    pub const DEFAULT: LinkConfig = LinkConfig {
+        max_packet_size: 64,
+        mtu: MAX_MTU,
+        download_speed: 1_000_000,
+        upload_speed: 1_000_000,
+    };
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        // IPv6 needs at least 1280
+        let min_mtu = if cfg!(feature = "ipv6") { 1280 } else { 576 };
+        if !matches!(self.max_packet_size, 8 | 16 | 32 | 64 | 512) {
+            Err(ConfigError::MaxPacketSize)
+        } else if !(min_mtu..=MAX_MTU).contains(&self.mtu) {
+            Err(ConfigError::Mtu)
+        } else if self.download_speed == 0 || self.upload_speed == 0 {
+            Err(ConfigError::LinkSpeed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig::DEFAULT
+    }
+}
 
 
 fn usb_ethernet<'a, U: UsbBus>(
     mac_address: [u8; 6],
+    max_packet_size: u16,
     usb_alloc: &'a usb_device::bus::UsbBusAllocator<U>,
-    in_buffer: &'a mut [u8; 2048],
-    out_buffer: &'a mut [u8; 2048]) ->  Ethernet<'a, U> {
+    in_buffer: &'a mut [u8; NTB_SIZE],
+    out_buffer: &'a mut [u8; NTB_SIZE]) ->  Ethernet<'a, U> {
 
     info!("interface MAC address: {}", EthernetAddress(mac_address));
     Ethernet::new(
         usb_alloc,
         mac_address,
-        MTU,
+        max_packet_size,
         in_buffer,
         out_buffer)
 }
 
+/// A device with a smaller MTU than it says. smoltcp only asks for the capabilities when the
+/// interface is made, so it's only needed then.
+struct LimitMtu<'d, D: Device> {
+    device: &'d mut D,
+    mtu: usize,
+}
+
+impl <D: Device> Device for LimitMtu<'_, D> {
+    type RxToken<'a> = D::RxToken<'a> where Self: 'a;
+    type TxToken<'a> = D::TxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, timestamp: smoltcp::time::Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.device.receive(timestamp)
+    }
+
+    fn transmit(&mut self, timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        self.device.transmit(timestamp)
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = self.device.capabilities();
+        let frame = self.mtu + ETHERNET_HEADER_LEN;
+        capabilities.max_transmission_unit = capabilities.max_transmission_unit.min(frame);
+        capabilities
+    }
+}
+
+
+/// The network side of a channel that moves data from a socket to the application.
+pub trait NetworkRecv {
+    /// Forward whatever the socket has received. Returns true if anything was consumed.
+    fn try_recv(&mut self, sockets: &mut SocketSet<'_>) -> bool;
+
+    /// Wake `waker` when the application makes room for more, for `Gadget::run`. Returns false
+    /// if the channel can't, so it has to be polled.
+    fn register(&self, waker: &Waker) -> bool;
+}
+
+/// The network side of a channel that moves data from the application to a socket.
+pub trait NetworkSend {
+    /// Forward whatever the application has sent. Returns true if anything was queued.
+    fn try_send(&mut self, sockets: &mut SocketSet<'_>) -> Result<bool, ReceiveError>;
+
+    /// Wake `waker` when the application sends something, as for `NetworkRecv::register`.
+    fn register(&self, waker: &Waker) -> bool;
+}
+
+impl <T: NetworkRecv + ?Sized> NetworkRecv for &mut T {
+    fn try_recv(&mut self, sockets: &mut SocketSet<'_>) -> bool {
+        (**self).try_recv(sockets)
+    }
+
+    fn register(&self, waker: &Waker) -> bool {
+        (**self).register(waker)
+    }
+}
+
+impl <T: NetworkSend + ?Sized> NetworkSend for &mut T {
+    fn try_send(&mut self, sockets: &mut SocketSet<'_>) -> Result<bool, ReceiveError> {
+        (**self).try_send(sockets)
+    }
+
+    fn register(&self, waker: &Waker) -> bool {
+        (**self).register(waker)
+    }
+}
+
+/// How many connection events a channel queues for the application.
+pub const CONNECTION_EVENTS: usize = 4;
+
+/// A change in a channel's connection. When a connection ends, the application can still
+/// read what it sent, so a client that closes right after its last request is still
+/// answered. Whatever's left unread is discarded when the next connection is accepted, so
+/// that one starts clean. By the time the application sees `Connected`, anything it wrote
+/// before has been discarded too.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConnectionEvent {
+    Connected { peer: IpEndpoint },
+    /// The remote closed the connection.
+    Disconnected,
+    /// The connection was reset or timed out.
+    Reset,
+}
 
 #[derive(Clone, Copy)]
 enum RecvChannelState {
@@ -53,37 +259,56 @@ enum RecvChannelState {
 pub struct RecvChannel<'a, const N: usize> {
     port: u16,
     handle: SocketHandle,
-    sender: Sender<'a, u8, N>,
+    sender: RingProducer<'a, N>,
+    /// Fences what the application sends, so nothing written before a connection goes to it.
+    send_fence: RingFence<'a, N>,
+    events: Sender<'a, ConnectionEvent, CONNECTION_EVENTS>,
     state: RecvChannelState,
+    /// Listen again once a connection closes. Client sockets are reconnected by the gadget.
+    listen: bool,
+    /// There's been a connection, so the next one starts a new session.
+    connected: bool,
 }
 
 impl <const N: usize> RecvChannel<'_, N> {
+    /// Wait until something the socket received has been passed to the application. It's
+    /// woken when the socket receives or changes state, and when the application reads.
+    pub async fn recv(&mut self, sockets: &mut SocketSet<'_>) -> bool {
+        poll_fn(|cx| {
+            self.sender.register_writable(cx.waker());
+            sockets.get_mut::<tcp::Socket>(self.handle).register_recv_waker(cx.waker());
+            match self.try_recv(sockets) {
+                true => Poll::Ready(true),
+                false => Poll::Pending,
+            }
+        }).await
+    }
+
     pub fn try_recv(&mut self,  sockets: &mut SocketSet<'_>) -> bool {
         let socket: &mut tcp::Socket = sockets.get_mut(self.handle);
         let mut consumed: usize = 0;
 
+        if self.sender.is_closed() {
+            panic!("no receiver");
+        }
+
         if self.may_recv(socket) {
-            let mut buf = [0u8; N];
-            // peek at the bytes, because we don't know how many we can forward
-            match socket.peek_slice(&mut buf[..]) {
-                Ok(received) => {
-                    for index in 0..received {
-                        match self.sender.try_send(buf[index]) {
-                            Ok(()) => { consumed += 1; },
-                            Err(TrySendError::Full(_)) => break,
-                            Err(TrySendError::NoReceiver(_)) => { panic!("no receiver"); },
-                        }
-                    }
+            // Both the socket buffer and the ring wrap around, so it can take a few
+            // slices to move everything that fits.
+            while !self.sender.is_full() {
+                match socket.recv(|data| {
+                    let written = self.sender.try_write(data);
+                    (written, written)
+                }) {
+                    Ok(0) | Err(_) => break,
+                    Ok(written) => { consumed += written; },
+                }
+            }
 
-                    // Read however many bytes we could send to the channel
-                    socket.recv_slice(&mut buf[0..consumed]).unwrap();
-                    if consumed < received {
-                        warn!("sender is full. received {}, consumed {} for {}", received, consumed, self.port);
-                    } else {
-                        debug!("consumed {} bytes on {}", consumed, self.port);
-                    }
-                },
-                Err(e) => { panic!("Error peeking socket input: {}", e); },
+            if socket.recv_queue() > 0 && !self.sender.is_fenced() {
+                warn!("sender is full. consumed {}, {} left for {}", consumed, socket.recv_queue(), self.port);
+            } else if consumed > 0 {
+                debug!("consumed {} bytes on {}", consumed, self.port);
             }
         }
 
@@ -96,22 +321,44 @@ impl <const N: usize> RecvChannel<'_, N> {
         // separately, but it's simpler (the socket state is complicated), and it makes
         // logging the transitions possible.
         let (state, may_recv) = match (self.state, socket.may_recv()) {
-            (RecvChannelState::Listening, true) => {
+            (RecvChannelState::Listening | RecvChannelState::Closing, true) => {
                 info!("accepted connection, state: {} on {}", socket.state(), self.port);
+                // Before the event, so everything the application writes once it's seen it
+                // goes to this connection
+                if !self.send_fence.is_empty() {
+                    self.send_fence.fence();
+                }
+                // Even when it's all been read, so a decoder with half a frame drops it
+                if self.connected {
+                    self.sender.fence();
+                }
+                self.connected = true;
+                if let Some(peer) = socket.remote_endpoint() {
+                    self.event(ConnectionEvent::Connected { peer });
+                }
                 (RecvChannelState::Receiving, true)
             },
             (RecvChannelState::Receiving, false) => {
                 info!("remote closed socket, state: {}, closing", socket.state());
+                // A reset or timeout leaves the socket closed, without the remote's FIN
+                self.event(match socket.state() {
+                    tcp::State::Closed => ConnectionEvent::Reset,
+                    _ => ConnectionEvent::Disconnected,
+                });
                 socket.close();
                 (RecvChannelState::Closing, false)
             },
             (RecvChannelState::Closing, false) => {
                 match socket.is_active() {
                     true => (RecvChannelState::Closing, false),
-                    false => {
+                    false if self.listen => {
                         info!("socket closed, state {}, listenning on {}", socket.state(), self.port);
                         socket.listen(self.port).ok();
                         (RecvChannelState::Listening, false)
+                    },
+                    false => {
+                        info!("socket closed, state {}", socket.state());
+                        (RecvChannelState::Listening, false)
                     }
                 }
             }
@@ -121,62 +368,91 @@ impl <const N: usize> RecvChannel<'_, N> {
         self.state = state;
         may_recv
     }
+
+    fn event(&mut self, event: ConnectionEvent) {
+        match self.events.try_send(event) {
+            Ok(()) | Err(TrySendError::NoReceiver(_)) => {},
+            Err(TrySendError::Full(_)) => warn!("dropped {} on {}", event, self.port),
+        }
+    }
+}
+
+impl <const N: usize> NetworkRecv for RecvChannel<'_, N> {
+    fn try_recv(&mut self, sockets: &mut SocketSet<'_>) -> bool {
+        RecvChannel::try_recv(self, sockets)
+    }
+
+    fn register(&self, waker: &Waker) -> bool {
+        self.sender.register_writable(waker);
+        true
+    }
 }
+
 pub struct SendChannel<'a, const N: usize> {
     handle: SocketHandle,
-    receiver: Receiver<'a, u8, N>
+    receiver: RingConsumer<'a, N>,
 }
 
 impl <const N: usize> SendChannel<'_, N> {
+    /// Wait until something the application sent has been queued on the socket. It's woken
+    /// when the application sends, and when the socket has room or changes state.
     pub async fn send(&mut self,  sockets: &mut SocketSet<'_>) -> Result<bool, ReceiveError> {
-        poll_fn(|_cx| {
+        poll_fn(|cx| {
+            // Register before trying, so nothing that happens in between is missed
+            self.receiver.register_readable(cx.waker());
+            sockets.get_mut::<tcp::Socket>(self.handle).register_send_waker(cx.waker());
             match self.try_send(sockets) {
                 Ok(false) => Poll::Pending,
                 Ok(true) => Poll::Ready(Ok(true)),
-                Err(err) => return Poll::Ready(Err(err)),
+                Err(err) => Poll::Ready(Err(err)),
             }
         }).await
     }
 
     pub fn try_send(&mut self, sockets: &mut SocketSet<'_>) -> Result<bool, ReceiveError> {
         let socket:&mut tcp::Socket = sockets.get_mut(self.handle);
+        // Check before draining, so nothing the application sent before it went is lost
+        let closed = self.receiver.is_closed();
 
         if socket.may_send() {
             let mut count: usize = 0;
-            while socket.can_send() {
-                match self.receiver.try_recv() {
-                    Ok(data) => {
-                        socket.send_slice(&[data]).ok();
-                        count += 1;
-                    },
-                    Err(ReceiveError::Empty) => { 
-                        break; 
-                    },
-                    Err(err) => {
-                        return Err(err);
-                    }
+            while socket.can_send() && !self.receiver.is_empty() {
+                let sent = socket.send_slice(self.receiver.peek()).unwrap_or(0);
+                if sent == 0 {
+                    break;
                 }
+                self.receiver.release(sent);
+                count += sent;
+            }
+            if count == 0 && closed && self.receiver.is_empty() {
+                return Err(ReceiveError::NoSender);
             }
             Ok(count != 0)
         } else {
-            loop {
-                match self.receiver.try_recv() {
-                    Ok(_) => { },
-                    Err(ReceiveError::Empty) => { 
-                        return Ok(false);
-                    },
-                    Err(err) => {
-                        return Err(err);
-                    }
-                }
+            self.receiver.clear();
+            match closed {
+                true => Err(ReceiveError::NoSender),
+                false => Ok(false),
             }
         }
     }   
 }
 
+impl <const N: usize> NetworkSend for SendChannel<'_, N> {
+    fn try_send(&mut self, sockets: &mut SocketSet<'_>) -> Result<bool, ReceiveError> {
+        SendChannel::try_send(self, sockets)
+    }
+
+    fn register(&self, waker: &Waker) -> bool {
+        self.receiver.register_readable(waker);
+        true
+    }
+}
+
 pub struct NetworkChannelStorage<const N: usize> {
-    pub sender: Channel<u8, N>,
-    pub receiver: Channel<u8, N>,
+    pub sender: Ring<N>,
+    pub receiver: Ring<N>,
+    pub events: Channel<ConnectionEvent, CONNECTION_EVENTS>,
     pub tx_storage: [u8; N],
     pub rx_storage: [u8; N],
 }
@@ -185,22 +461,30 @@ impl  <const N: usize> NetworkChannelStorage<N> {
 
     pub const fn new() -> Self {
         Self {
-            sender: Channel::new(),
-            receiver: Channel::new(),
+            sender: Ring::new(),
+            receiver: Ring::new(),
+            events: Channel::new(),
             tx_storage: [0x0; N],
             rx_storage: [0x0; N],
         }
     }
 }
 
+impl <const N: usize> Default for NetworkChannelStorage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct NetworkEndpoint<'a, const N: usize> {
     pub send: SendChannel<'a, N>,
     pub recv: RecvChannel<'a, N>,
 }
 
 pub struct ApplicationEndpoint<'a, const N: usize> {
-    pub send: Sender<'a, u8, N>,
-    pub recv: Receiver<'a, u8, N>,
+    pub send: RingProducer<'a, N>,
+    pub recv: RingConsumer<'a, N>,
+    pub events: Receiver<'a, ConnectionEvent, CONNECTION_EVENTS>,
 }
 
 pub struct NetworkChannel<'a, const N: usize> {
@@ -208,32 +492,148 @@ pub struct NetworkChannel<'a, const N: usize> {
     pub app: ApplicationEndpoint<'a, N>
 }
 
+impl <'a, const N: usize> NetworkChannel<'a, N> {
+    /// A channel on a new TCP socket in `sockets`, for an interface the application polls
+    /// itself. `listen` makes it listen on `port` again after each connection closes; it's up
+    /// to the caller to start it listening, or connect it. `Gadget::channel` is the usual way.
+    pub fn new(sockets: &mut SocketSet<'a>, port: u16, listen: bool, storage: &'a mut NetworkChannelStorage<N>) -> Self {
+        let rx_buffer = tcp::SocketBuffer::new(&mut storage.rx_storage[..]);
+        let tx_buffer = tcp::SocketBuffer::new(&mut storage.tx_storage[..]);
+
+        let socket = tcp::Socket::new(rx_buffer, tx_buffer);
+        let handle = sockets.add(socket);
+      
+        let (net_send, app_recv) = storage.receiver.split();
+        let (app_send, net_recv) = storage.sender.split();
+        let send_fence = app_send.fencer();
+        let (events, app_events) = storage.events.split();
+
+        NetworkChannel {
+            net: NetworkEndpoint { 
+                send: SendChannel { handle, receiver: net_recv },
+                recv: RecvChannel {
+                    port,
+                    handle,
+                    sender: net_send,
+                    send_fence,
+                    events,
+                    state: RecvChannelState::Listening,
+                    listen,
+                    connected: false,
+                },
+            },
+            app: ApplicationEndpoint { send: app_send, recv: app_recv, events: app_events }
+        }
+    }
+
+    /// The channel's socket.
+    pub fn handle(&self) -> SocketHandle {
+        self.net.send.handle
+    }
+}
+
+/// Storage for `K` connections to one port.
+pub struct ListenerStorage<const N: usize, const K: usize> {
+    pub channels: [NetworkChannelStorage<N>; K],
+}
+
+impl <const N: usize, const K: usize> ListenerStorage<N, K> {
+    pub const fn new() -> Self {
+        Self {
+            channels: [const { NetworkChannelStorage::new() }; K],
+        }
+    }
+}
+
+impl <const N: usize, const K: usize> Default for ListenerStorage<N, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The network side of a listener. The arrays can be passed straight to `Gadget::poll`.
+pub struct ListenerNetworkEndpoint<'a, const N: usize, const K: usize> {
+    pub send: [SendChannel<'a, N>; K],
+    pub recv: [RecvChannel<'a, N>; K],
+}
+
+/// `K` sockets listening on the same port, so up to `K` clients can be connected at once.
+/// Each connection gets its own application endpoint: a client is handed whichever socket
+/// is free when it connects, and keeps it until it disconnects.
+pub struct Listener<'a, const N: usize, const K: usize> {
+    pub net: ListenerNetworkEndpoint<'a, N, K>,
+    pub app: [ApplicationEndpoint<'a, N>; K],
+}
+
+impl <'a, const N: usize, const K: usize> Listener<'a, N, K> {
+    /// `K` new sockets in `sockets`, all listening on `port`, for an interface the
+    /// application polls itself. `Gadget::listener` is the usual way.
+    pub fn new(sockets: &mut SocketSet<'a>, port: u16, storage: &'a mut ListenerStorage<N, K>) -> Self {
+        let mut send = Vec::<_, K>::new();
+        let mut recv = Vec::<_, K>::new();
+        let mut app = Vec::<_, K>::new();
+        for storage in storage.channels.iter_mut() {
+            let channel = NetworkChannel::new(sockets, port, true, storage);
+            sockets.get_mut::<tcp::Socket>(channel.handle()).listen(port).ok();
+            send.push(channel.net.send).ok();
+            recv.push(channel.net.recv).ok();
+            app.push(channel.app).ok();
+        }
+
+        Listener {
+            net: ListenerNetworkEndpoint {
+                send: send.into_array().ok().unwrap(),
+                recv: recv.into_array().ok().unwrap(),
+            },
+            app: app.into_array().ok().unwrap(),
+        }
+    }
+}
+
 
 pub struct GadgetStorage<'a, U: UsbBus, const SOCKETS: usize> {
     usb_bus_allocator: Option<usb_device::bus::UsbBusAllocator<U>>,
-    in_buffer: [u8; 2048],
-    out_buffer: [u8; 2048],
+    in_buffer: [u8; NTB_SIZE],
+    out_buffer: [u8; NTB_SIZE],
     socket_storage: [SocketStorage<'a>; SOCKETS],
     dhcp_options: [DhcpOption<'a>; 1],
+    #[cfg(feature = "dhcp-server")]
+    dhcp_server: DhcpServerStorage,
+    #[cfg(feature = "mdns")]
+    mdns: MdnsStorage,
+    #[cfg(feature = "serial")]
+    serial: SerialStorage<SERIAL_BUFFER>,
 }
 
 const DHCP_HOST_NAME: u8 = 12;
 
-impl <'a, U: UsbBus, const SOCKETS: usize> GadgetStorage<'_, U, SOCKETS> {
+impl <U: UsbBus, const SOCKETS: usize> GadgetStorage<'_, U, SOCKETS> {
     pub const fn new() -> Self {
         Self {
             usb_bus_allocator: None,
-            in_buffer:  [0; 2048],
-            out_buffer: [0; 2048],
+            in_buffer:  [0; NTB_SIZE],
+            out_buffer: [0; NTB_SIZE],
             socket_storage: [SocketStorage::EMPTY; SOCKETS],
             dhcp_options: [
                 DhcpOption { kind: DHCP_HOST_NAME, data: b"none" }
             ],
+            #[cfg(feature = "dhcp-server")]
+            dhcp_server: DhcpServerStorage::new(),
+            #[cfg(feature = "mdns")]
+            mdns: MdnsStorage::new(),
+            #[cfg(feature = "serial")]
+            serial: SerialStorage::new(),
         }
     }
 
     fn set_name(&mut self, data: &'static [u8]) {
-        self.dhcp_options[0] = DhcpOption { kind: DHCP_HOST_NAME, data: data };
+        self.dhcp_options[0] = DhcpOption { kind: DHCP_HOST_NAME, data };
+    }
+}
+
+impl <U: UsbBus, const SOCKETS: usize> Default for GadgetStorage<'_, U, SOCKETS> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -248,6 +648,16 @@ impl <const NOM: u32, const DENOM: u32> IntoInstant for Instant<u64, NOM, DENOM>
     }
 }
 
+/// Waits for the USB peripheral's interrupt, for `Gadget::run`.
+pub trait UsbInterrupt {
+    /// Wait for the next interrupt. It stays pending until the device is polled, so the
+    /// handler usually masks it and signals this: unmask it here, before waiting.
+    fn wait(&mut self) -> impl Future<Output = ()>;
+}
+
+/// How often `Gadget::run` polls channels that can't wake it, like UDP channels.
+pub const CHANNEL_POLL_INTERVAL: smoltcp::time::Duration = smoltcp::time::Duration::from_millis(10);
+
 pub trait Clock {
     type Instant: IntoInstant;
     fn now() -> Self::Instant;
@@ -256,6 +666,7 @@ pub trait Clock {
 #[derive(PartialEq, Eq, Clone, Copy)]
 enum IpState {
     Unconfigured,
+    LinkLocal,
     Configured,
 }
 
@@ -263,8 +674,26 @@ pub struct Gadget<'a, CLOCK: Clock, U: UsbBus> {
     pub ethernet: Ethernet<'a, U>,
     interface: Interface,
     sockets: SocketSet<'a>,
-    dhcp: SocketHandle,
+    addressing: Addressing,
+    dhcp: Option<SocketHandle>,
+    link_local: LinkLocal,
+    link_local_at: Option<smoltcp::time::Instant>,
+    #[cfg(feature = "ipv6")]
+    slaac: Slaac,
+    router: Option<Ipv4Address>,
+    dns_server: Option<Ipv4Address>,
+    clients: Vec<Connector, MAX_CLIENTS>,
+    local_port: u16,
+    #[cfg(feature = "dhcp-server")]
+    dhcp_server: Option<DhcpServer>,
+    #[cfg(feature = "mdns")]
+    mdns: Mdns,
+    #[cfg(feature = "serial")]
+    serial: Serial<'a, U, SERIAL_BUFFER>,
+    #[cfg(feature = "serial")]
+    serial_endpoint: Option<SerialEndpoint<'a, SERIAL_BUFFER>>,
     usb_device: UsbDevice<'a, U>,
+    link: LinkConfig,
     state: IpState,
     clock: PhantomData<CLOCK>,
 }
@@ -273,64 +702,142 @@ pub struct Gadget<'a, CLOCK: Clock, U: UsbBus> {
 impl <'a, CLOCK: Clock, U: UsbBus> Gadget<'a, CLOCK, U> {
 
     pub fn new<const SOCKETS: usize>(
-        name: &'static [u8],
-        interface_mac_address: [u8; 6],
-        gadget_mac_address: [u8; 6],
+        config: GadgetConfig<'a>,
         storage: &'a mut GadgetStorage<'a, U, SOCKETS>,
-        usb_bus_allocator: UsbBusAllocator<U>,
-        seed: u64) -> Self {
-        
+        usb_bus_allocator: UsbBusAllocator<U>) -> Result<Self, ConfigError> {
+
+        config.validate()?;
+        let GadgetConfig {
+            name,
+            identity,
+            link,
+            addressing,
+            interface_mac_address,
+            gadget_mac_address,
+            seed,
+        } = config;
         storage.set_name(name);
         storage.usb_bus_allocator.replace(usb_bus_allocator);
         let usb_bus_allocator = storage.usb_bus_allocator.as_ref().unwrap();
         let mut ethernet = usb_ethernet(
             interface_mac_address,
+            link.max_packet_size,
             usb_bus_allocator, 
             &mut storage.in_buffer, 
             &mut storage.out_buffer);
-        let interface = Self::interface(gadget_mac_address, &mut ethernet, seed);
-        let mut dhcp_socket = dhcpv4::Socket::new();
-        dhcp_socket.set_outgoing_options(storage.dhcp_options.as_ref());
+        let mut device = LimitMtu { device: &mut ethernet, mtu: link.mtu };
+        let interface = Self::interface(gadget_mac_address, &mut device, seed);
         let mut sockets = SocketSet::new(storage.socket_storage.as_mut_slice());
-        let dhcp = sockets.add(dhcp_socket);
-        Gadget::<'a,CLOCK,U> {
+        let dhcp = match addressing {
+            Addressing::Static { .. } => None,
+            #[cfg(feature = "dhcp-server")]
+            Addressing::DhcpServer { .. } => None,
+            Addressing::Dhcp | Addressing::DhcpWithLinkLocal { .. } => {
+                let mut dhcp_socket = dhcpv4::Socket::new();
+                dhcp_socket.set_outgoing_options(storage.dhcp_options.as_ref());
+                Some(sockets.add(dhcp_socket))
+            }
+        };
+        #[cfg(feature = "dhcp-server")]
+        let dhcp_server = match addressing {
+            Addressing::DhcpServer { address } =>
+                Some(DhcpServer::new(address, &mut sockets, &mut storage.dhcp_server)?),
+            _ => None,
+        };
+        #[cfg(feature = "mdns")]
+        let mdns = Mdns::new(name, &mut sockets, &mut storage.mdns);
+        // Its interfaces come after the Ethernet ones
+        #[cfg(feature = "serial")]
+        let (serial, serial_endpoint) = Serial::new(usb_bus_allocator, &mut storage.serial);
+        let mut gadget = Gadget::<'a,CLOCK,U> {
             ethernet,
             interface,
             sockets,
+            addressing,
             dhcp,
-            usb_device: Self::usb_device(usb_bus_allocator),
+            link_local: LinkLocal::new(EthernetAddress(gadget_mac_address)),
+            link_local_at: None,
+            #[cfg(feature = "ipv6")]
+            slaac: Slaac::new(EthernetAddress(gadget_mac_address)),
+            router: None,
+            dns_server: None,
+            clients: Vec::new(),
+            local_port: Connector::first_port(seed),
+            #[cfg(feature = "dhcp-server")]
+            dhcp_server,
+            #[cfg(feature = "mdns")]
+            mdns,
+            #[cfg(feature = "serial")]
+            serial,
+            #[cfg(feature = "serial")]
+            serial_endpoint: Some(serial_endpoint),
+            usb_device: Self::usb_device(usb_bus_allocator, &identity),
+            link,
             state: IpState::Unconfigured,
             clock: PhantomData,
+        };
+
+        match addressing {
+            Addressing::Static { address, gateway } => {
+                gadget.configure(address, gateway);
+                gadget.state = IpState::Configured;
+            },
+            #[cfg(feature = "dhcp-server")]
+            Addressing::DhcpServer { address } => {
+                gadget.configure(address, None);
+                gadget.state = IpState::Configured;
+            },
+            Addressing::Dhcp | Addressing::DhcpWithLinkLocal { .. } => {}
         }
+        #[cfg(feature = "ipv6")]
+        gadget.configure_ipv6();
+        Ok(gadget)
     }
    
 
-    fn usb_device(usb_bus_allocator: &UsbBusAllocator<U>) -> UsbDevice<'_, U> {
-        UsbDeviceBuilder::new(
+    fn usb_device(usb_bus_allocator: &'a UsbBusAllocator<U>, identity: &UsbIdentity<'a>) -> UsbDevice<'a, U> {
+        let strings: Vec<_, MAX_LANGUAGES> = identity.strings.iter()
+            .map(|strings| StringDescriptors::new(strings.language)
+                .manufacturer(strings.manufacturer)
+                .product(strings.product)
+                .serial_number(identity.serial_number))
+            .collect();
+        let builder = UsbDeviceBuilder::new(
             usb_bus_allocator,
-            UsbVidPid(0x1209, 0x0004),
+            UsbVidPid(identity.vid, identity.pid),
         )
-        .strings(&[StringDescriptors::default()
-            .manufacturer("none")
-            .product("none")
-            .serial_number("aux")])
-        .unwrap()
-        .device_class(usbd_ethernet::USB_CLASS_CDC)
-        .max_packet_size_0(64)
-        .unwrap()
-        .build()
-    }
-
-    fn interface(mac_address: [u8; 6], ethernet: &mut Ethernet<'a, U>, seed: u64) -> Interface {
+        .strings(&strings)
+        .unwrap();
+        // Each function starts with an interface association descriptor, which the host only
+        // looks for in a composite device
+        #[cfg(feature = "serial")]
+        let builder = builder.composite_with_iads();
+        #[cfg(not(feature = "serial"))]
+        let builder = builder.device_class(usbd_ethernet::USB_CLASS_CDC);
+        builder
+            .device_release(identity.device_release)
+            .self_powered(identity.self_powered)
+            // `GadgetConfig::validate` checked it's no more than 500mA
+            .max_power(identity.max_power_ma)
+            .unwrap()
+            .max_packet_size_0(64)
+            .unwrap()
+            .build()
+    }
+
+    fn interface<D: Device>(mac_address: [u8; 6], device: &mut D, seed: u64) -> Interface {
         let mac_address = EthernetAddress(mac_address);
         let mut interface_config = iface::Config::new(mac_address.into());
         interface_config.random_seed = seed;
 
         let mut interface = Interface::new(
             interface_config,
-            ethernet,
+            device,
             Self::now());
 
+        #[cfg(feature = "mdns")]
+        interface.join_multicast_group(MDNS_GROUP).unwrap();
+
         interface.update_ip_addrs(|ip_addrs| {
             ip_addrs
                 .push(Ipv4Cidr::new(IP_ADDRESS, 0).into())
@@ -344,8 +851,7 @@ impl <'a, CLOCK: Clock, U: UsbBus> Gadget<'a, CLOCK, U> {
     pub fn connect(&mut self)  {
         if self.ethernet.state() == DeviceState::Disconnected {
             if self.ethernet.connection_speed().is_none() {
-                // 1000 Kps upload and download
-                match self.ethernet.set_connection_speed(1_000_000, 1_000_000) {
+                match self.ethernet.set_connection_speed(self.link.download_speed, self.link.upload_speed) {
                     Ok(()) | Err(UsbError::WouldBlock) => {}
                     Err(e) => error!("Failed to set connection speed: {}", e),
                 }
@@ -363,35 +869,58 @@ impl <'a, CLOCK: Clock, U: UsbBus> Gadget<'a, CLOCK, U> {
     }
     
     pub fn configured(& self) -> bool {
+        self.state != IpState::Unconfigured
+    }
+
+    /// Configured, and not waiting for a better address: a link-local address is only a
+    /// fallback while DHCP keeps trying.
+    fn settled(&self) -> bool {
         self.state == IpState::Configured
     }
 
-    pub fn poll<const N: usize>(&mut self, send: &mut [SendChannel<N>], recv: &mut [RecvChannel<N>]) {
-        if (self.usb_device.poll(&mut [&mut self.ethernet]) && self.recv_channels(recv))
+    pub fn poll<S: NetworkSend, R: NetworkRecv>(&mut self, send: &mut [S], recv: &mut [R]) {
+        let connecting = self.clients_poll();
+        if (self.usb_poll() && self.recv_channels(recv))
             || self.send_channels(send) 
-            || !self.configured() {
+            || connecting
+            || !self.settled() {
                 self.usb_send();
         }
     }
 
-    pub fn try_send<const N: usize>(&mut self, channels: &mut [SendChannel<N>]) {
+    pub fn try_send<S: NetworkSend>(&mut self, channels: &mut [S]) {
         debug!("sending");
-        self.usb_device.poll(&mut [&mut self.ethernet]);
+        self.usb_poll();
 
-        if self.send_channels(channels) || !self.configured() {
+        let connecting = self.clients_poll();
+        if self.send_channels(channels) || connecting || !self.settled() {
             self.usb_send();
         }
     }
 
+    /// Poll the USB device. Returns true if there was a USB event.
+    #[cfg(not(feature = "serial"))]
+    fn usb_poll(&mut self) -> bool {
+        self.usb_device.poll(&mut [&mut self.ethernet])
+    }
+
+    #[cfg(feature = "serial")]
+    fn usb_poll(&mut self) -> bool {
+        let event = self.usb_device.poll(&mut [&mut self.ethernet, &mut self.serial]);
+        self.serial.pump();
+        event
+    }
+
     fn usb_send(&mut self) {
         debug!("data available, sending");
+        self.link_local_poll();
         self.interface.poll_egress(
             Self::now(), 
             &mut self.ethernet, 
             &mut self.sockets);
     }
     
-    fn send_channels<const N: usize>(&mut self, channels: &mut [SendChannel<'_, N>]) -> bool {
+    fn send_channels<S: NetworkSend>(&mut self, channels: &mut [S]) -> bool {
         let mut data = false;
         if self.connected() {
             debug!("connected");
@@ -410,9 +939,9 @@ impl <'a, CLOCK: Clock, U: UsbBus> Gadget<'a, CLOCK, U> {
         data
     }
     
-    pub fn try_recv<const N: usize>(&mut self, channels: &mut [RecvChannel<N>]) {
+    pub fn try_recv<R: NetworkRecv>(&mut self, channels: &mut [R]) {
         info!("receiving");
-        if !self.usb_device.poll(&mut [&mut self.ethernet]) {
+        if !self.usb_poll() {
             debug!("nothing to do");
             return;
         }
@@ -422,8 +951,112 @@ impl <'a, CLOCK: Clock, U: UsbBus> Gadget<'a, CLOCK, U> {
         }
     }
 
-    fn recv_channels<const N: usize>(&mut self, channels: &mut [RecvChannel<'_, N>]) -> bool {
-        let data = match self.interface.poll(Self::now(), &mut self.ethernet, &mut self.sockets) {
+    /// Drive the gadget from its own task, instead of calling `poll`. Between polls it waits
+    /// for the USB interrupt, for the application to send or read on a channel, or for the
+    /// next timer, like a TCP retransmission or DHCP renewal, so the MCU can sleep.
+    pub async fn run<S: NetworkSend, R: NetworkRecv, I: UsbInterrupt, D: DelayNs>(
+        &mut self,
+        interrupt: &mut I,
+        delay: &mut D,
+        send: &mut [S],
+        recv: &mut [R]) -> ! {
+
+        loop {
+            // Whatever wakes the task ends the wait. The channels register its waker before
+            // they're polled, so nothing the application does in between is missed.
+            let waker = poll_fn(|cx| Poll::Ready(cx.waker().clone())).await;
+            let mut polled = false;
+            for channel in send.iter() {
+                polled |= !channel.register(&waker);
+            }
+            for channel in recv.iter() {
+                polled |= !channel.register(&waker);
+            }
+            #[cfg(feature = "serial")]
+            self.serial.register(&waker);
+
+            self.usb_poll();
+            self.clients_poll();
+            self.recv_channels(recv);
+            // The application may have made room for what a socket is holding
+            for channel in recv.iter_mut() {
+                channel.try_recv(&mut self.sockets);
+            }
+            self.send_channels(send);
+            self.usb_send();
+
+            let timeout = match self.poll_delay() {
+                Some(timeout) if polled => Some(timeout.min(CHANNEL_POLL_INTERVAL)),
+                None if polled => Some(CHANNEL_POLL_INTERVAL),
+                timeout => timeout,
+            };
+            let timer = async {
+                match timeout {
+                    Some(timeout) => delay.delay_us(timeout.total_micros().min(u32::MAX as u64) as u32).await,
+                    None => pending().await,
+                }
+            };
+            let mut woken = false;
+            let channels = poll_fn(|_| match core::mem::replace(&mut woken, true) {
+                true => Poll::Ready(()),
+                false => Poll::Pending,
+            });
+            select(pin!(interrupt.wait()), select(pin!(channels), pin!(timer))).await;
+        }
+    }
+
+    /// How long until the interface, or anything the gadget does itself, next needs polling.
+    fn poll_delay(&mut self) -> Option<smoltcp::time::Duration> {
+        let now = Self::now();
+        let mut at = self.interface.poll_at(now, &self.sockets);
+        let mut earliest = |next: Option<smoltcp::time::Instant>| {
+            if let Some(next) = next {
+                at = Some(at.map_or(next, |at| at.min(next)));
+            }
+        };
+        for client in self.clients.iter() {
+            earliest(client.poll_at());
+        }
+        earliest(self.link_local.poll_at());
+        // Only while it's due, or a timeout that's passed would have `run` spin
+        if self.waiting_for_dhcp() {
+            earliest(self.link_local_at);
+        }
+        #[cfg(feature = "ipv6")]
+        earliest(self.slaac.poll_at());
+        #[cfg(feature = "mdns")]
+        earliest(self.mdns.poll_at());
+        at.map(|at| if at > now { at - now } else { smoltcp::time::Duration::ZERO })
+    }
+
+    /// Start connecting any client channels that are due to. Returns true if there's a SYN
+    /// to send.
+    fn clients_poll(&mut self) -> bool {
+        if !self.connected() || !self.configured() {
+            return false;
+        }
+
+        let now = Self::now();
+        let mut connecting = false;
+        for client in self.clients.iter_mut() {
+            connecting |= client.poll(
+                now,
+                self.interface.context(),
+                &mut self.sockets,
+                self.router,
+                self.dns_server,
+                &mut self.local_port);
+        }
+        connecting
+    }
+
+    fn recv_channels<R: NetworkRecv>(&mut self, channels: &mut [R]) -> bool {
+        #[cfg(not(feature = "ipv6"))]
+        let observer = &mut self.link_local;
+        #[cfg(feature = "ipv6")]
+        let observer = &mut (&mut self.link_local, &mut self.slaac);
+        let mut device = Snoop::new(&mut self.ethernet, observer);
+        let data = match self.interface.poll(Self::now(), &mut device, &mut self.sockets) {
             iface::PollResult::SocketStateChanged => true,
             iface::PollResult::None => false
         };
@@ -431,6 +1064,20 @@ impl <'a, CLOCK: Clock, U: UsbBus> Gadget<'a, CLOCK, U> {
         self.dhcp_poll();
     
         let mut ack = false;
+        #[cfg(feature = "ipv6")]
+        {
+            ack |= self.slaac_poll();
+        }
+        #[cfg(feature = "dhcp-server")]
+        if let Some(server) = self.dhcp_server.as_mut() {
+            ack |= server.poll(&mut self.sockets);
+        }
+        #[cfg(feature = "mdns")]
+        {
+            let address = self.configured().then(|| self.interface.ipv4_addr()).flatten();
+            ack |= self.mdns.poll(Self::now(), &mut self.sockets, address);
+        }
+
         if data {
             for channel in channels {
                 ack |= channel.try_recv(&mut self.sockets);
@@ -440,65 +1087,321 @@ impl <'a, CLOCK: Clock, U: UsbBus> Gadget<'a, CLOCK, U> {
     }
     
     fn dhcp_poll(&mut self) {
-        let event = self.sockets.get_mut::<dhcpv4::Socket>(self.dhcp).poll();
+        let Some(dhcp) = self.dhcp else { return };
+        let event = self.sockets.get_mut::<dhcpv4::Socket>(dhcp).poll();
         match event {
             None => {}
             Some(dhcpv4::Event::Configured(config)) => {
                 debug!("DHCP config acquired!");
+                self.link_local.stop();
+                self.link_local_at = None;
 
                 info!("IP address:      {}", config.address);
-                self.interface.update_ip_addrs(|addrs| {
-                    addrs.clear();
-                    addrs.push(IpCidr::Ipv4(config.address)).unwrap();
-                });
-
-                if let Some(router) = config.router {
-                    debug!("Default gateway: {}", router);
-                    self.interface.routes_mut().add_default_ipv4_route(router).unwrap();
-                } else {
-                    debug!("Default gateway: None");
-                    self.interface.routes_mut().remove_default_ipv4_route();
-                }
-
                 for (i, s) in config.dns_servers.iter().enumerate() {
                     debug!("DNS server {}:    {}", i, s);
                 }
 
+                let (address, router) = (config.address, config.router);
+                self.dns_server = config.dns_servers.first().copied();
+                self.configure(address, router);
                 self.state = IpState::Configured;
             }
             Some(dhcpv4::Event::Deconfigured) => {
                 debug!("DHCP lost config!");
-                self.interface.update_ip_addrs(|addrs| addrs.clear());
-                self.interface.routes_mut().remove_default_ipv4_route();
-                self.state = IpState::Unconfigured;
+                self.deconfigure();
             }
         }
 
     }
 
-    pub fn channel<const N:usize>(&mut self, port: u16, storage: &'a mut NetworkChannelStorage<N>) -> NetworkChannel<'a, N> {
-        let rx_buffer = tcp::SocketBuffer::new(&mut storage.rx_storage[..]);
-        let tx_buffer = tcp::SocketBuffer::new(&mut storage.tx_storage[..]);
+    fn link_local_poll(&mut self) {
+        let Addressing::DhcpWithLinkLocal { timeout } = self.addressing else { return };
+        let now = Self::now();
 
-        let socket = tcp::Socket::new(rx_buffer, tx_buffer);
-        let handle = self.sockets.add(socket);
-    
-        let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+        // The timeout runs from when the host brings the link up, not from power on, so it
+        // starts again each time the link does.
+        if !self.connected() {
+            self.link_local_at = None;
+        } else if self.waiting_for_dhcp() {
+            match self.link_local_at {
+                None => self.link_local_at = Some(now + timeout),
+                Some(at) if now >= at => {
+                    info!("no DHCP configuration, falling back to link-local");
+                    self.link_local.start(now);
+                },
+                Some(_) => {}
+            }
+        }
+
+        match self.link_local.poll(now, &mut self.ethernet) {
+            None => {}
+            Some(LinkLocalEvent::Claimed(address)) => {
+                info!("IP address:      {}", address);
+                self.configure(address, None);
+                self.state = IpState::LinkLocal;
+            }
+            Some(LinkLocalEvent::Lost) => {
+                self.deconfigure();
+            }
+        }
+    }
+
+    /// Unconfigured with the link up, so a link-local address may be needed.
+    fn waiting_for_dhcp(&self) -> bool {
+        self.state == IpState::Unconfigured && self.link_local.is_idle() && self.connected()
+    }
+
+    fn configure(&mut self, address: Ipv4Cidr, router: Option<Ipv4Address>) {
+        self.router = router;
+        self.interface.update_ip_addrs(|addrs| {
+            addrs.retain(|cidr| cidr.address().version() != IpVersion::Ipv4);
+            addrs.push(IpCidr::Ipv4(address)).unwrap();
+        });
+
+        if let Some(router) = router {
+            debug!("Default gateway: {}", router);
+            self.interface.routes_mut().add_default_ipv4_route(router).unwrap();
+        } else {
+            debug!("Default gateway: None");
+            self.interface.routes_mut().remove_default_ipv4_route();
+        }
+    }
+
+    fn deconfigure(&mut self) {
+        self.interface.update_ip_addrs(|addrs| addrs.retain(|cidr| cidr.address().version() != IpVersion::Ipv4));
+        self.interface.routes_mut().remove_default_ipv4_route();
+        self.router = None;
+        self.dns_server = None;
+        self.state = IpState::Unconfigured;
+    }
+
+    /// Solicit a router advertisement when the link comes up, and keep the interface's IPv6
+    /// addresses and default route up to date. Returns true if there's something to send.
+    #[cfg(feature = "ipv6")]
+    fn slaac_poll(&mut self) -> bool {
+        let now = Self::now();
+        self.slaac.link(self.connected(), now);
+        if self.slaac.poll(now, &mut self.ethernet) {
+            self.configure_ipv6();
+            return true;
+        }
+        false
+    }
+
+    #[cfg(feature = "ipv6")]
+    fn configure_ipv6(&mut self) {
+        let slaac = &self.slaac;
+        self.interface.update_ip_addrs(|addrs| {
+            addrs.retain(|cidr| cidr.address().version() != IpVersion::Ipv6);
+            for address in slaac.addresses() {
+                if addrs.push(IpCidr::Ipv6(address)).is_err() {
+                    warn!("no room for IPv6 address {}", address);
+                }
+            }
+        });
+
+        if let Some(router) = self.slaac.router() {
+            debug!("IPv6 default gateway: {}", router);
+            self.interface.routes_mut().add_default_ipv6_route(router).unwrap();
+        } else {
+            self.interface.routes_mut().remove_default_ipv6_route();
+        }
+    }
+
+    /// Advertise a service, usually a port passed to `channel`, over DNS-SD. Fails if there
+    /// are already `MDNS_SERVICES` services.
+    #[cfg(feature = "mdns")]
+    pub fn advertise(&mut self, service: Service) -> Result<(), Service> {
+        self.mdns.advertise(service)
+    }
+
+    /// The application's end of the serial port. Panics if it's been taken already.
+    #[cfg(feature = "serial")]
+    pub fn serial(&mut self) -> SerialEndpoint<'a, SERIAL_BUFFER> {
+        self.serial_endpoint.take().expect("the serial endpoint has already been taken")
+    }
+
+    pub fn channel<const N:usize>(&mut self, port: u16, storage: &'a mut NetworkChannelStorage<N>) -> NetworkChannel<'a, N> {
+        let channel = self.tcp_channel(port, true, storage);
+        let socket = self.sockets.get_mut::<tcp::Socket>(channel.handle());
         socket.listen(port).ok();
-      
-        let (net_send, app_recv) = storage.receiver.split();
-        let (app_send, net_recv) = storage.sender.split();
+        channel
+    }
 
-        NetworkChannel {
-            net: NetworkEndpoint { 
-                send: SendChannel { handle, receiver: net_recv },
-                recv: RecvChannel { port, handle, sender: net_send, state: RecvChannelState::Listening },
-            },
-            app: ApplicationEndpoint { send: app_send, recv: app_recv }
+    /// A channel that connects out to `remote`, rather than waiting for the host to connect,
+    /// and reconnects with backoff when that fails or the connection drops. Anything the
+    /// application sends while there's no connection is dropped. Fails, handing back the
+    /// storage, if there are already `MAX_CLIENTS` client channels.
+    pub fn client_channel<const N:usize>(
+        &mut self,
+        remote: Remote,
+        storage: &'a mut NetworkChannelStorage<N>) -> Result<NetworkChannel<'a, N>, &'a mut NetworkChannelStorage<N>> {
+
+        if self.clients.is_full() {
+            return Err(storage);
         }
+        let port = match remote {
+            Remote::Endpoint(endpoint) => endpoint.port,
+            Remote::Router(port) | Remote::DnsServer(port) => port,
+        };
+        let channel = self.tcp_channel(port, false, storage);
+        self.clients.push(Connector::new(channel.net.send.handle, remote)).ok();
+        Ok(channel)
+    }
+
+    fn tcp_channel<const N:usize>(&mut self, port: u16, listen: bool, storage: &'a mut NetworkChannelStorage<N>) -> NetworkChannel<'a, N> {
+        NetworkChannel::new(&mut self.sockets, port, listen, storage)
+    }
+
+    /// Accept up to `K` concurrent connections on `port`. Each takes a socket, so
+    /// `GadgetStorage` needs room for `K` of them.
+    pub fn listener<const N: usize, const K: usize>(
+        &mut self,
+        port: u16,
+        storage: &'a mut ListenerStorage<N, K>) -> Listener<'a, N, K> {
+
+        Listener::new(&mut self.sockets, port, storage)
+    }
+
+    /// A channel where each datagram is one encoded message, so there's no framing. With
+    /// `UdpPeer::LastSender` replies go to whoever sent the last datagram.
+    #[cfg(feature = "udp")]
+    pub fn udp_channel<const N: usize, const P: usize>(
+        &mut self,
+        port: u16,
+        peer: UdpPeer,
+        storage: &'a mut UdpChannelStorage<N, P>) -> UdpChannel<'a, N, P> {
+
+        UdpChannel::new(port, peer, &mut self.sockets, storage)
     }
 
     fn now() -> smoltcp::time::Instant {
         CLOCK::now().into_instant()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+
+    use smoltcp::phy::{ Loopback, Medium };
+
+    use super::*;
+    use crate::testing::{ FakeBus, TestClock };
+
+    fn config() -> GadgetConfig<'static> {
+        GadgetConfig {
+            name: b"gadget",
+            identity: UsbIdentity::TEST,
+            link: LinkConfig::DEFAULT,
+            addressing: Addressing::Dhcp,
+            interface_mac_address: [0x02, 0, 0, 0, 0, 1],
+            gadget_mac_address: [0x02, 0, 0, 0, 0, 2],
+            seed: 0,
+        }
+    }
+
+    #[test]
+    fn validate_identity() {
+        assert_eq!(config().validate(), Ok(()));
+
+        let mut config = config();
+        config.identity.max_power_ma = 500;
+        assert_eq!(config.validate(), Ok(()));
+        config.identity.max_power_ma = 510;
+        assert_eq!(config.validate(), Err(ConfigError::MaxPower));
+
+        let strings = [UsbIdentity::TEST.strings[0]; MAX_LANGUAGES + 1];
+        config.identity = UsbIdentity { strings: &strings[..MAX_LANGUAGES], ..UsbIdentity::TEST };
+        assert_eq!(config.validate(), Ok(()));
+        config.identity.strings = &strings;
+        assert_eq!(config.validate(), Err(ConfigError::Languages));
+    }
+
+    #[test]
+    fn validate_link() {
+        let min_mtu = if cfg!(feature = "ipv6") { 1280 } else { 576 };
+        let mut link = LinkConfig::DEFAULT;
+        for max_packet_size in [8, 16, 32, 64, 512] {
+            link.max_packet_size = max_packet_size;
+            assert_eq!(link.validate(), Ok(()));
+        }
+        for max_packet_size in [0, 63, 128, 1024] {
+            link.max_packet_size = max_packet_size;
+            assert_eq!(link.validate(), Err(ConfigError::MaxPacketSize));
+        }
+
+        let mut link = LinkConfig::DEFAULT;
+        for mtu in [min_mtu, MAX_MTU] {
+            link.mtu = mtu;
+            assert_eq!(link.validate(), Ok(()));
+        }
+        for mtu in [0, min_mtu - 1, MAX_MTU + 1] {
+            link.mtu = mtu;
+            assert_eq!(link.validate(), Err(ConfigError::Mtu));
+        }
+
+        let mut link = LinkConfig::DEFAULT;
+        link.download_speed = 0;
+        assert_eq!(link.validate(), Err(ConfigError::LinkSpeed));
+        link.download_speed = 1;
+        link.upload_speed = 0;
+        assert_eq!(link.validate(), Err(ConfigError::LinkSpeed));
+
+        // It goes into the gadget's
+        let mut config = config();
+        config.link = link;
+        assert_eq!(config.validate(), Err(ConfigError::LinkSpeed));
+    }
+
+    #[test]
+    fn limit_mtu() {
+        let mut loopback = Loopback::new(Medium::Ethernet);
+        let mtu = loopback.capabilities().ip_mtu();
+        assert!(mtu > MAX_MTU);
+
+        // What the interface is made with
+        let device = LimitMtu { device: &mut loopback, mtu: 576 };
+        assert_eq!(device.capabilities().ip_mtu(), 576);
+        // Only ever lowers it
+        let device = LimitMtu { device: &mut loopback, mtu: mtu + 1 };
+        assert_eq!(device.capabilities().ip_mtu(), mtu);
+    }
+
+    fn gadget(addressing: Addressing) -> Gadget<'static, TestClock, FakeBus> {
+        let storage = Box::leak(Box::new(GadgetStorage::<FakeBus, 8>::new()));
+        let config = GadgetConfig { addressing, ..config() };
+        Gadget::new(config, storage, UsbBusAllocator::new(FakeBus::default())).unwrap()
+    }
+
+    #[test]
+    fn link_local_timeout_only_counts_while_waiting() {
+        let mut gadget = gadget(Addressing::Static {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 7, 2), 24),
+            gateway: None,
+        });
+        let delay = gadget.poll_delay();
+        #[cfg(not(any(feature = "ipv6", feature = "mdns")))]
+        assert_eq!(delay, None);
+
+        // A timeout that passed, and the link's gone down
+        gadget.link_local_at = Some(TestClock::now().into_instant());
+        TestClock::advance(fugit::MicrosDurationU64::secs(10));
+        gadget.state = IpState::Unconfigured;
+        assert!(!gadget.connected());
+        assert_eq!(gadget.poll_delay(), delay);
+
+        // Or the gadget has an address
+        gadget.state = IpState::LinkLocal;
+        assert_eq!(gadget.poll_delay(), delay);
+    }
+
+    #[test]
+    fn link_local_timeout_restarts_with_the_link() {
+        let mut gadget = gadget(Addressing::DhcpWithLinkLocal { timeout: smoltcp::time::Duration::from_secs(5) });
+        gadget.link_local_at = Some(TestClock::now().into_instant());
+        assert!(!gadget.connected());
+        gadget.link_local_poll();
+        assert_eq!(gadget.link_local_at, None);
+    }
+}
@@ -0,0 +1,278 @@
+
+//! `SendChannel::send` and `RecvChannel::recv` over smoltcp's loopback device, with a waker
+//! that counts how often it's woken.
+
+mod common;
+
+use std::{
+    future::Future,
+    pin::pin,
+    sync::{ atomic::{ AtomicUsize, Ordering }, Arc },
+    task::{ Context, Poll, Wake, Waker },
+};
+
+use rtic2_usb_gadget::usb::{ ConnectionEvent, NetworkChannel, NetworkChannelStorage };
+use smoltcp::{
+    iface::{ Config, Interface, SocketHandle, SocketSet },
+    phy::{ Loopback, Medium },
+    socket::tcp,
+    time::{ Duration, Instant },
+    wire::{ EthernetAddress, IpAddress, IpCidr },
+};
+
+const PORT: u16 = 1234;
+const ADDRESS: IpAddress = IpAddress::v4(127, 0, 0, 1);
+
+#[derive(Default)]
+struct CountingWaker(AtomicUsize);
+
+impl CountingWaker {
+    fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn poll_once<F: Future>(future: F, waker: &Waker) -> Poll<F::Output> {
+    pin!(future).poll(&mut Context::from_waker(waker))
+}
+
+/// A channel's socket and a client socket on the same interface.
+struct Network<const N: usize> {
+    interface: Interface,
+    device: Loopback,
+    sockets: SocketSet<'static>,
+    now: Instant,
+    channel: NetworkChannel<'static, N>,
+    client: SocketHandle,
+}
+
+impl <const N: usize> Network<N> {
+    fn listening() -> Self {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let config = Config::new(EthernetAddress([0x02, 0, 0, 0, 0, 1]).into());
+        let mut interface = Interface::new(config, &mut device, Instant::ZERO);
+        interface.update_ip_addrs(|addrs| addrs.push(IpCidr::new(ADDRESS, 8)).unwrap());
+
+        let mut sockets = SocketSet::new(vec![]);
+        let storage = Box::leak(Box::new(NetworkChannelStorage::<N>::new()));
+        let channel = NetworkChannel::new(&mut sockets, PORT, true, storage);
+        sockets.get_mut::<tcp::Socket>(channel.handle()).listen(PORT).unwrap();
+        let client = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 1024]), tcp::SocketBuffer::new(vec![0; 1024]));
+        let client = sockets.add(client);
+
+        Network { interface, device, sockets, now: Instant::ZERO, channel, client }
+    }
+
+    fn connect(&mut self) {
+        let context = self.interface.context();
+        self.sockets.get_mut::<tcp::Socket>(self.client).connect(context, (ADDRESS, PORT), 49152).unwrap();
+        self.poll();
+        assert_eq!(self.client().state(), tcp::State::Established);
+    }
+
+    fn connected() -> Self {
+        let mut network = Self::listening();
+        network.connect();
+        network
+    }
+
+    fn poll(&mut self) {
+        for _ in 0..10 {
+            self.now += Duration::from_millis(50);
+            self.interface.poll(self.now, &mut self.device, &mut self.sockets);
+        }
+    }
+
+    fn client(&mut self) -> &mut tcp::Socket<'static> {
+        self.sockets.get_mut(self.client)
+    }
+
+    fn poll_send(&mut self, waker: &Waker) -> Poll<bool> {
+        poll_once(self.channel.net.send.send(&mut self.sockets), waker).map(Result::unwrap)
+    }
+
+    fn poll_recv(&mut self, waker: &Waker) -> Poll<bool> {
+        poll_once(self.channel.net.recv.recv(&mut self.sockets), waker)
+    }
+}
+
+#[test]
+fn send_wakes_when_the_application_sends() {
+    let mut network = Network::<256>::connected();
+    let counter = Arc::new(CountingWaker::default());
+    let waker = Waker::from(counter.clone());
+
+    assert_eq!(network.poll_send(&waker), Poll::Pending);
+    assert_eq!(counter.count(), 0);
+    network.channel.app.send.try_write(b"hello");
+    assert!(counter.count() > 0);
+    assert_eq!(network.poll_send(&waker), Poll::Ready(true));
+
+    network.poll();
+    let mut received = [0; 16];
+    let count = network.client().recv_slice(&mut received).unwrap();
+    assert_eq!(&received[..count], b"hello");
+}
+
+#[test]
+fn send_wakes_when_the_socket_has_room() {
+    let mut network = Network::<256>::connected();
+    let counter = Arc::new(CountingWaker::default());
+    let waker = Waker::from(counter.clone());
+
+    // Fill the socket's send buffer, then the ring behind it
+    assert_eq!(network.channel.app.send.try_write(&[1; 256]), 256);
+    assert_eq!(network.poll_send(&waker), Poll::Ready(true));
+    assert_eq!(network.channel.app.send.try_write(&[2; 256]), 256);
+    assert_eq!(network.poll_send(&waker), Poll::Pending);
+
+    // The client acknowledges what it's received, which makes room
+    let woken = counter.count();
+    network.poll();
+    assert!(counter.count() > woken);
+    assert_eq!(network.poll_send(&waker), Poll::Ready(true));
+}
+
+#[test]
+fn recv_wakes_when_data_arrives() {
+    let mut network = Network::<256>::connected();
+    let counter = Arc::new(CountingWaker::default());
+    let waker = Waker::from(counter.clone());
+
+    assert_eq!(network.poll_recv(&waker), Poll::Pending);
+    let woken = counter.count();
+    network.client().send_slice(b"ping").unwrap();
+    network.poll();
+    assert!(counter.count() > woken);
+    assert_eq!(network.poll_recv(&waker), Poll::Ready(true));
+
+    let mut received = [0; 16];
+    let count = network.channel.app.recv.try_read(&mut received);
+    assert_eq!(&received[..count], b"ping");
+}
+
+#[test]
+fn recv_wakes_when_the_application_reads() {
+    let mut network = Network::<64>::connected();
+    let counter = Arc::new(CountingWaker::default());
+    let waker = Waker::from(counter.clone());
+
+    // Fill the ring, and leave more waiting in the socket
+    network.client().send_slice(&[1; 64]).unwrap();
+    network.poll();
+    assert_eq!(network.poll_recv(&waker), Poll::Ready(true));
+    network.client().send_slice(&[2; 10]).unwrap();
+    network.poll();
+    assert_eq!(network.poll_recv(&waker), Poll::Pending);
+
+    let woken = counter.count();
+    let mut received = [0; 64];
+    assert_eq!(network.channel.app.recv.try_read(&mut received), 64);
+    assert!(counter.count() > woken);
+    assert_eq!(network.poll_recv(&waker), Poll::Ready(true));
+    assert_eq!(network.channel.app.recv.try_read(&mut received), 10);
+    assert_eq!(received[..10], [2; 10]);
+}
+
+#[test]
+fn written_after_connected_is_sent() {
+    let mut network = Network::<256>::connected();
+    let waker = Waker::from(Arc::new(CountingWaker::default()));
+
+    assert_eq!(network.poll_recv(&waker), Poll::Pending);
+    assert!(matches!(network.channel.app.events.try_recv(), Ok(ConnectionEvent::Connected { .. })));
+    assert_eq!(network.channel.app.send.try_write(b"hello"), 5);
+    assert_eq!(network.poll_send(&waker), Poll::Ready(true));
+
+    network.poll();
+    let mut received = [0; 16];
+    let count = network.client().recv_slice(&mut received).unwrap();
+    assert_eq!(&received[..count], b"hello");
+}
+
+#[test]
+fn written_before_connected_is_discarded() {
+    let mut network = Network::<256>::listening();
+    let counter = Arc::new(CountingWaker::default());
+    let waker = Waker::from(counter.clone());
+    assert_eq!(network.channel.app.send.try_write(b"stale"), 5);
+    network.connect();
+
+    assert_eq!(network.poll_recv(&waker), Poll::Pending);
+    assert!(matches!(network.channel.app.events.try_recv(), Ok(ConnectionEvent::Connected { .. })));
+    // The stale bytes are fenced off, so a write waits until they've been thrown away
+    {
+        let Network { channel, sockets, .. } = &mut network;
+        let mut write = pin!(channel.app.send.write_all(b"hello"));
+        assert_eq!(write.as_mut().poll(&mut Context::from_waker(&waker)), Poll::Pending);
+        let woken = counter.count();
+        assert_eq!(poll_once(channel.net.send.send(sockets), &waker), Poll::Pending);
+        assert!(counter.count() > woken);
+        assert_eq!(write.as_mut().poll(&mut Context::from_waker(&waker)), Poll::Ready(Ok(())));
+        assert_eq!(poll_once(channel.net.send.send(sockets), &waker), Poll::Ready(Ok(true)));
+    }
+
+    network.poll();
+    let mut received = [0; 16];
+    let count = network.client().recv_slice(&mut received).unwrap();
+    assert_eq!(&received[..count], b"hello");
+}
+
+#[test]
+fn a_reset_is_a_session_boundary() {
+    let mut network = Network::<256>::connected();
+    let waker = Waker::from(Arc::new(CountingWaker::default()));
+    network.client().send_slice(b"unread").unwrap();
+    network.poll();
+    assert_eq!(network.poll_recv(&waker), Poll::Ready(true));
+    assert!(!network.channel.app.recv.take_boundary());
+
+    network.client().abort();
+    network.poll();
+    assert_eq!(network.poll_recv(&waker), Poll::Pending);
+    assert!(matches!(network.channel.app.events.try_recv(), Ok(ConnectionEvent::Connected { .. })));
+    assert!(matches!(network.channel.app.events.try_recv(), Ok(ConnectionEvent::Reset)));
+    // Still there until the next connection
+    assert!(!network.channel.app.recv.is_empty());
+    assert!(!network.channel.app.recv.take_boundary());
+
+    // Listening again
+    assert_eq!(network.poll_recv(&waker), Poll::Pending);
+    network.connect();
+    assert_eq!(network.poll_recv(&waker), Poll::Pending);
+    assert!(matches!(network.channel.app.events.try_recv(), Ok(ConnectionEvent::Connected { .. })));
+    assert_eq!(network.channel.app.recv.try_read(&mut [0; 16]), 0);
+    assert!(network.channel.app.recv.take_boundary());
+    assert!(!network.channel.app.recv.take_boundary());
+}
+
+#[test]
+fn what_was_sent_before_a_close_can_be_read() {
+    let mut network = Network::<256>::connected();
+    let waker = Waker::from(Arc::new(CountingWaker::default()));
+
+    // The client's last request, and then it closes its end
+    network.client().send_slice(b"last").unwrap();
+    network.client().close();
+    network.poll();
+    assert_eq!(network.poll_recv(&waker), Poll::Ready(true));
+    network.poll();
+    assert_eq!(network.poll_recv(&waker), Poll::Pending);
+    assert!(matches!(network.channel.app.events.try_recv(), Ok(ConnectionEvent::Connected { .. })));
+    assert!(matches!(network.channel.app.events.try_recv(), Ok(ConnectionEvent::Disconnected)));
+
+    let mut received = [0; 16];
+    let count = network.channel.app.recv.try_read(&mut received);
+    assert_eq!(&received[..count], b"last");
+    assert!(!network.channel.app.recv.take_boundary());
+}
@@ -0,0 +1,96 @@
+
+//! NOR flash in memory, and a swap that records what it was asked to do, for
+//! `update::FirmwareUpdate`. Both are shared, so a test can look at them after the update
+//! has taken them.
+
+use std::{ cell::{ Cell, RefCell }, rc::Rc };
+
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+use rtic2_usb_gadget::update::SwapOnReboot;
+
+/// Erasing sets bytes to 0xff, and writing a byte that isn't erased fails.
+#[derive(Clone)]
+pub struct MemoryFlash {
+    pub bytes: Rc<RefCell<Vec<u8>>>,
+    pub erases: Rc<Cell<usize>>,
+    /// A byte whose lowest bit is stuck at 1.
+    pub stuck: Option<usize>,
+}
+
+impl MemoryFlash {
+    pub const CAPACITY: usize = 8192;
+
+    pub fn new() -> Self {
+        MemoryFlash {
+            bytes: Rc::new(RefCell::new(vec![0; Self::CAPACITY])),
+            erases: Rc::new(Cell::new(0)),
+            stuck: None,
+        }
+    }
+}
+
+impl ErrorType for MemoryFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MemoryFlash {
+    const READ_SIZE: usize = 4;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.bytes.borrow()[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        Self::CAPACITY
+    }
+}
+
+impl NorFlash for MemoryFlash {
+    const WRITE_SIZE: usize = 8;
+    const ERASE_SIZE: usize = 1024;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.bytes.borrow_mut()[from as usize..to as usize].fill(0xff);
+        self.erases.set(self.erases.get() + 1);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        let mut flash = self.bytes.borrow_mut();
+        let target = &mut flash[offset as usize..offset as usize + bytes.len()];
+        if target.iter().any(|byte| *byte != 0xff) {
+            return Err(NorFlashErrorKind::Other);
+        }
+        target.copy_from_slice(bytes);
+        if let Some(stuck) = self.stuck {
+            flash[stuck] |= 1;
+        }
+        Ok(())
+    }
+}
+
+/// Records the size it was asked to swap in.
+#[derive(Clone, Default)]
+pub struct Swap {
+    pub swapped: Rc<Cell<Option<u32>>>,
+    pub fail: bool,
+}
+
+impl SwapOnReboot for Swap {
+    type Error = ();
+
+    fn swap(&mut self, size: u32) -> Result<(), ()> {
+        if self.fail {
+            return Err(());
+        }
+        self.swapped.set(Some(size));
+        Ok(())
+    }
+}
@@ -0,0 +1,12 @@
+
+//! Fixtures shared by the integration tests.
+
+#![allow(dead_code)]
+
+#[cfg(feature = "update")]
+pub mod flash;
+#[cfg(not(feature = "net-logger"))]
+mod logger;
+pub mod ping;
+
+defmt::timestamp!("");
@@ -0,0 +1,296 @@
+
+//! `codec::Decoder` driven from memory with oversized, truncated and garbage input, decoding
+//! in place, and a checked round trip from an `Encoder`.
+
+mod common;
+
+use std::{
+    cell::RefCell, collections::VecDeque, convert::Infallible, future::Future, pin::pin, rc::Rc,
+    task::{ Context, Poll },
+};
+
+use futures::{ executor::block_on, task::noop_waker };
+use micropb::MessageEncode;
+use rtic2_usb_gadget::{
+    codec::{ CodecError, Decoder, Encoder },
+    stream::{ ring::Ring, ByteSink, ByteStream, Sink, Stream },
+};
+use common::ping::Ping;
+use pbstream_framing::{ BorrowedMessage, Cobs, Crc16, FieldError, Fields, Sequenced };
+
+/// A `Ping` that borrows its text from the decoder.
+#[derive(Debug, PartialEq)]
+struct PingRef<'a> {
+    seq: u32,
+    text: &'a str,
+}
+
+impl <'a> BorrowedMessage<'a> for PingRef<'a> {
+    fn decode(payload: &'a [u8]) -> Result<Self, FieldError> {
+        let mut ping = PingRef { seq: 0, text: "" };
+        for field in Fields::new(payload) {
+            let field = field?;
+            match field.number {
+                1 => ping.seq = field.value.as_u32().ok_or(FieldError::UnknownWireType)?,
+                2 => ping.text = field.value.as_str().ok_or(FieldError::Utf8)?,
+                _ => {},
+            }
+        }
+        Ok(ping)
+    }
+}
+
+/// Bytes from memory, a few at a time, so frames straddle reads. Clones share the bytes, so
+/// a test can add more after the decoder has taken its input.
+#[derive(Clone)]
+struct Input {
+    bytes: Rc<RefCell<VecDeque<u8>>>,
+}
+
+impl Input {
+    const CHUNK: usize = 5;
+
+    fn new(bytes: Vec<u8>) -> Self {
+        Input { bytes: Rc::new(RefCell::new(bytes.into())) }
+    }
+
+    fn extend(&self, bytes: Vec<u8>) {
+        self.bytes.borrow_mut().extend(bytes);
+    }
+}
+
+impl Stream for Input {
+    type Item = u8;
+
+    async fn next(&mut self) -> Option<u8> {
+        self.bytes.borrow_mut().pop_front()
+    }
+}
+
+impl ByteStream for Input {
+    async fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut bytes = self.bytes.borrow_mut();
+        let count = buf.len().min(Self::CHUNK).min(bytes.len());
+        for (slot, byte) in buf.iter_mut().zip(bytes.drain(..count)) {
+            *slot = byte;
+        }
+        count
+    }
+}
+
+impl Sink for Input {
+    type Item = u8;
+    type Error = Infallible;
+
+    async fn send(&mut self, byte: u8) -> Result<(), Infallible> {
+        self.bytes.borrow_mut().push_back(byte);
+        Ok(())
+    }
+}
+
+impl ByteSink for Input {}
+
+/// Decodes frames of up to 32 bytes.
+type SmallDecoder = Decoder<Input, Ping, 32>;
+
+fn frame(ping: &Ping) -> Vec<u8> {
+    let mut buffer = vec![0; pbstream_framing::max_frame_size(ping.compute_size())];
+    let size = pbstream_framing::encode(ping, &mut buffer).unwrap();
+    buffer.truncate(size);
+    buffer
+}
+
+fn ping(seq: u32, text: &str) -> Ping {
+    Ping { seq, text: text.into() }
+}
+
+fn decode_all(decoder: &mut SmallDecoder) -> Vec<Result<Ping, CodecError>> {
+    block_on(async {
+        let mut results = Vec::new();
+        while let Some(result) = decoder.try_next().await {
+            results.push(result);
+        }
+        results
+    })
+}
+
+#[test]
+fn frames_across_reads() {
+    let pings = [ping(1, "one"), Ping::default(), ping(300, "three hundred")];
+    let mut decoder = SmallDecoder::new(Input::new(pings.iter().flat_map(frame).collect()));
+
+    let results = decode_all(&mut decoder);
+    assert_eq!(results, pings.into_iter().map(Ok).collect::<Vec<_>>());
+    assert_eq!(decoder.counters().messages, 3);
+    assert_eq!(decoder.counters().errors(), 0);
+}
+
+#[test]
+fn oversized_frame_is_skipped_to_its_delimiter() {
+    // The tail of the oversized frame is a complete frame of its own, starting just after
+    // the byte that overflows the buffer. Decoding it would deliver a ping that was never sent.
+    let smuggled = frame(&ping(99, "evil"));
+    let smuggled = String::from_utf8(smuggled[..smuggled.len() - 1].to_vec()).unwrap();
+    let oversized = ping(1, &("x".repeat(29) + &smuggled));
+    let mut input = frame(&oversized);
+    input.extend(frame(&ping(2, "good")));
+    let mut decoder = SmallDecoder::new(Input::new(input));
+
+    let results = decode_all(&mut decoder);
+    assert_eq!(results, [Err(CodecError::FrameTooLong), Ok(ping(2, "good"))]);
+    assert_eq!(decoder.counters().frame_too_long, 1);
+    assert_eq!(decoder.counters().discarded(), 1);
+}
+
+#[test]
+fn oversized_frames_in_a_row() {
+    let long = ping(1, &"y".repeat(300));
+    let mut input = frame(&long);
+    input.extend(frame(&long));
+    input.extend(frame(&ping(2, "good")));
+    let mut decoder = SmallDecoder::new(Input::new(input));
+
+    let results = decode_all(&mut decoder);
+    assert_eq!(results, [Err(CodecError::FrameTooLong), Err(CodecError::FrameTooLong), Ok(ping(2, "good"))]);
+    assert_eq!(decoder.counters().discarded(), 2);
+}
+
+#[test]
+fn reset_stops_skipping() {
+    // A connection that drops part way through an oversized frame, so there's no delimiter
+    let long = frame(&ping(1, &"y".repeat(100)));
+    let input = Input::new(long[..50].to_vec());
+    let mut decoder = SmallDecoder::new(input.clone());
+    assert_eq!(decode_all(&mut decoder), [Err(CodecError::FrameTooLong)]);
+
+    decoder.reset();
+    input.extend(frame(&ping(2, "good")));
+    assert_eq!(decode_all(&mut decoder), [Ok(ping(2, "good"))]);
+}
+
+#[test]
+fn fence_drops_a_partial_frame() {
+    let mut ring = Ring::<64>::new();
+    let (mut producer, consumer) = ring.split();
+    let mut decoder = Decoder::<_, Ping, 32>::new(consumer);
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    // A connection that drops half way through a frame, then a new one
+    let cut = frame(&ping(1, "cut short"));
+    producer.try_write(&cut[..cut.len() / 2]);
+    {
+        let mut next = pin!(decoder.try_next());
+        assert!(next.as_mut().poll(&mut cx).is_pending());
+        producer.fence();
+        assert!(next.as_mut().poll(&mut cx).is_pending());
+        producer.try_write(&frame(&ping(2, "good")));
+        assert_eq!(next.as_mut().poll(&mut cx), Poll::Ready(Some(Ok(ping(2, "good")))));
+    }
+    assert_eq!(decoder.counters().errors(), 0);
+}
+
+#[test]
+fn truncated_frame() {
+    let cut = frame(&ping(1, "cut short"));
+    let mut input = cut[..cut.len() / 2].to_vec();
+    input.push(pbstream_framing::DELIMITER);
+    input.extend(frame(&ping(2, "good")));
+    let mut decoder = SmallDecoder::new(Input::new(input));
+
+    let results = decode_all(&mut decoder);
+    assert_eq!(results, [Err(CodecError::Cobs), Ok(ping(2, "good"))]);
+    assert_eq!(decoder.counters().cobs, 1);
+}
+
+#[test]
+fn truncated_at_end_of_input() {
+    let cut = frame(&ping(1, "cut short"));
+    let mut decoder = SmallDecoder::new(Input::new(cut[..cut.len() - 1].to_vec()));
+
+    assert_eq!(decode_all(&mut decoder), []);
+    assert_eq!(decoder.counters().messages, 0);
+}
+
+#[test]
+fn garbage() {
+    let mut not_a_message = vec![0; 8];
+    let size = pbstream_framing::frame(&[0xff, 0xff, 0xff], &mut not_a_message).unwrap();
+    let mut input = vec![0x05, 0x01, 0x00];
+    input.extend(&not_a_message[..size]);
+    input.extend([0x00, 0x00]);
+    input.extend(frame(&ping(2, "good")));
+    let mut decoder = SmallDecoder::new(Input::new(input));
+
+    let results = decode_all(&mut decoder);
+    assert_eq!(results, [Err(CodecError::Cobs), Err(CodecError::Decode), Ok(ping(2, "good"))]);
+    assert_eq!(decoder.counters().discarded(), 2);
+}
+
+#[test]
+fn stream_skips_errors() {
+    let cut = frame(&ping(1, "cut short"));
+    let mut input = cut[..4].to_vec();
+    input.push(pbstream_framing::DELIMITER);
+    input.extend(frame(&ping(1, &"z".repeat(64))));
+    input.extend(frame(&ping(2, "good")));
+    let mut decoder = SmallDecoder::new(Input::new(input));
+
+    assert_eq!(block_on(decoder.next()), Some(ping(2, "good")));
+    assert_eq!(block_on(decoder.next()), None);
+    assert_eq!(decoder.counters().discarded(), 2);
+}
+
+#[test]
+fn borrowed_frames_and_messages() {
+    let mut input = frame(&ping(1, "borrowed"));
+    input.extend(frame(&ping(2, "")));
+    input.extend([0x02, 0x08, 0x00]);
+    input.extend(frame(&ping(3, "raw")));
+    let mut decoder = SmallDecoder::new(Input::new(input));
+
+    block_on(async {
+        let message = decoder.next_borrowed::<PingRef>().await.unwrap().unwrap();
+        assert_eq!(message, PingRef { seq: 1, text: "borrowed" });
+        let message = decoder.next_borrowed::<PingRef>().await.unwrap().unwrap();
+        assert_eq!(message, PingRef { seq: 2, text: "" });
+        // A tag with no value
+        assert_eq!(decoder.next_borrowed::<PingRef>().await.unwrap(), Err(CodecError::Decode));
+        let payload = decoder.next_frame().await.unwrap().unwrap();
+        assert_eq!(payload, [0x08, 0x03, 0x12, 0x03, b'r', b'a', b'w']);
+        assert!(decoder.next_frame().await.is_none());
+    });
+    assert_eq!(decoder.counters().messages, 3);
+    assert_eq!(decoder.counters().decode, 1);
+}
+
+#[test]
+fn sequenced_crc16_round_trip() {
+    let pings = [ping(1, "one"), Ping::default(), ping(3, "longer than the encoder's buffer")];
+    let wire = Input::new(Vec::new());
+    let mut encoder = Encoder::<Ping, _, 16, Cobs, Sequenced<Crc16>>::new(wire.clone());
+    let mut decoder = Decoder::<_, Ping, 64, Cobs, Sequenced<Crc16>>::new(wire.clone());
+
+    block_on(async {
+        for ping in pings.iter() {
+            encoder.send(ping.clone()).await.unwrap();
+        }
+        for ping in pings.iter() {
+            assert_eq!(decoder.try_next().await, Some(Ok(ping.clone())));
+        }
+
+        // Lose a frame, and corrupt the one after
+        encoder.send(ping(4, "lost")).await.unwrap();
+        wire.bytes.borrow_mut().clear();
+        encoder.send(ping(5, "corrupt")).await.unwrap();
+        wire.bytes.borrow_mut()[3] ^= 0x10;
+        encoder.send(ping(6, "six")).await.unwrap();
+        assert_eq!(decoder.try_next().await, Some(Err(CodecError::Check)));
+        assert_eq!(decoder.try_next().await, Some(Ok(ping(6, "six"))));
+        assert_eq!(decoder.try_next().await, None);
+    });
+    assert_eq!(encoder.counters().messages, 6);
+    assert_eq!(decoder.counters().messages, 4);
+    assert_eq!(decoder.counters().check, 1);
+    assert_eq!(decoder.counters().dropped, 2);
+}
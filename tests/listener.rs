@@ -0,0 +1,149 @@
+
+//! A `Listener` over smoltcp's loopback device, with more clients than it has sockets.
+
+mod common;
+
+use rtic2_usb_gadget::usb::{ ConnectionEvent, Listener, ListenerStorage };
+use smoltcp::{
+    iface::{ Config, Interface, SocketHandle, SocketSet },
+    phy::{ Loopback, Medium },
+    socket::tcp,
+    time::{ Duration, Instant },
+    wire::{ EthernetAddress, IpAddress, IpCidr },
+};
+
+const PORT: u16 = 1234;
+const ADDRESS: IpAddress = IpAddress::v4(127, 0, 0, 1);
+const K: usize = 2;
+
+/// A listener's sockets, and `K + 1` client sockets, on the same interface.
+struct Network {
+    interface: Interface,
+    device: Loopback,
+    sockets: SocketSet<'static>,
+    now: Instant,
+    listener: Listener<'static, 256, K>,
+    clients: [SocketHandle; K + 1],
+}
+
+impl Network {
+    fn new() -> Self {
+        let mut device = Loopback::new(Medium::Ethernet);
+        let config = Config::new(EthernetAddress([0x02, 0, 0, 0, 0, 1]).into());
+        let mut interface = Interface::new(config, &mut device, Instant::ZERO);
+        interface.update_ip_addrs(|addrs| addrs.push(IpCidr::new(ADDRESS, 8)).unwrap());
+
+        let mut sockets = SocketSet::new(vec![]);
+        let storage = Box::leak(Box::new(ListenerStorage::<256, K>::new()));
+        let listener = Listener::new(&mut sockets, PORT, storage);
+        let clients = core::array::from_fn(|_| {
+            let client = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 1024]), tcp::SocketBuffer::new(vec![0; 1024]));
+            sockets.add(client)
+        });
+
+        Network { interface, device, sockets, now: Instant::ZERO, listener, clients }
+    }
+
+    fn connect(&mut self, client: usize) {
+        let context = self.interface.context();
+        let port = 49152 + client as u16;
+        self.sockets.get_mut::<tcp::Socket>(self.clients[client]).connect(context, (ADDRESS, PORT), port).unwrap();
+        self.poll();
+    }
+
+    /// Run the interface for a while, with the listener's channels polled as `Gadget::poll`
+    /// would.
+    fn poll(&mut self) {
+        for _ in 0..10 {
+            self.now += Duration::from_millis(50);
+            self.interface.poll(self.now, &mut self.device, &mut self.sockets);
+            for recv in self.listener.net.recv.iter_mut() {
+                recv.try_recv(&mut self.sockets);
+            }
+            for send in self.listener.net.send.iter_mut() {
+                send.try_send(&mut self.sockets).unwrap();
+            }
+        }
+    }
+
+    fn client(&mut self, client: usize) -> &mut tcp::Socket<'static> {
+        self.sockets.get_mut(self.clients[client])
+    }
+
+    /// Which of the listener's endpoints has a new connection.
+    fn accepted(&mut self) -> Option<usize> {
+        self.listener.app.iter_mut()
+            .position(|app| matches!(app.events.try_recv(), Ok(ConnectionEvent::Connected { .. })))
+    }
+
+    /// Send `request` from the client, and answer it from whichever endpoint it arrives on.
+    fn serve(&mut self, client: usize, request: &[u8]) -> usize {
+        self.client(client).send_slice(request).unwrap();
+        let mut received = [0; 16];
+        // A fenced ring takes nothing more until the application has read up to the fence
+        let (endpoint, count) = (0..2)
+            .find_map(|_| {
+                self.poll();
+                self.listener.app.iter_mut()
+                    .map(|app| app.recv.try_read(&mut received))
+                    .enumerate()
+                    .find(|(_, count)| *count > 0)
+            })
+            .unwrap();
+        assert_eq!(&received[..count], request);
+
+        self.listener.app[endpoint].send.try_write(&received[..count].to_ascii_uppercase());
+        self.poll();
+        let count = self.client(client).recv_slice(&mut received).unwrap();
+        assert_eq!(&received[..count], request.to_ascii_uppercase());
+        endpoint
+    }
+}
+
+#[test]
+fn serves_k_clients_at_once() {
+    let mut network = Network::new();
+    for client in 0..K {
+        network.connect(client);
+        assert_eq!(network.client(client).state(), tcp::State::Established);
+    }
+    let mut accepted: Vec<_> = (0..K).map(|_| network.accepted().unwrap()).collect();
+    accepted.sort();
+    assert_eq!(accepted, (0..K).collect::<Vec<_>>());
+
+    // Each on its own endpoint
+    assert_ne!(network.serve(0, b"zero"), network.serve(1, b"one"));
+    assert_ne!(network.serve(1, b"again"), network.serve(0, b"and again"));
+}
+
+#[test]
+fn refuses_a_client_too_many() {
+    let mut network = Network::new();
+    for client in 0..K {
+        network.connect(client);
+    }
+    network.connect(K);
+    assert_eq!(network.client(K).state(), tcp::State::Closed);
+
+    // The others carry on
+    network.serve(0, b"still here");
+}
+
+#[test]
+fn listens_again_after_a_client_disconnects() {
+    let mut network = Network::new();
+    for client in 0..K {
+        network.connect(client);
+    }
+    while network.accepted().is_some() {}
+    let endpoint = network.serve(0, b"first");
+
+    network.client(0).close();
+    network.poll();
+    assert!(matches!(network.listener.app[endpoint].events.try_recv(), Ok(ConnectionEvent::Disconnected)));
+
+    network.connect(K);
+    assert_eq!(network.client(K).state(), tcp::State::Established);
+    assert_eq!(network.accepted(), Some(endpoint));
+    assert_eq!(network.serve(K, b"next"), endpoint);
+}
@@ -0,0 +1,162 @@
+
+//! `update::FirmwareUpdate` writing to flash in memory.
+
+#![cfg(feature = "update")]
+
+mod common;
+
+use common::flash::{ MemoryFlash, Swap };
+use futures::executor::block_on;
+use micropb::heapless::Vec;
+use rtic2_usb_gadget::{
+    rpc::Service,
+    update::{ Begin, Body, Chunk, Commit, FirmwareUpdate, Reply, Status, Verify, UPDATE_CHUNK },
+};
+use sha2::{ Digest, Sha256 };
+
+type Update = FirmwareUpdate<MemoryFlash, Swap>;
+
+fn update() -> Update {
+    FirmwareUpdate::new(MemoryFlash::new(), Swap::default())
+}
+
+fn image(len: usize) -> std::vec::Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+fn call(update: &Update, body: Body) -> Reply {
+    block_on(update.call(body))
+}
+
+fn reply(status: Status, written: u32) -> Reply {
+    Reply { status, written }
+}
+
+fn begin(size: usize) -> Body {
+    Body::Begin(Begin { size: size as u32 })
+}
+
+fn chunk(offset: usize, data: &[u8]) -> Body {
+    Body::Chunk(Chunk { offset: offset as u32, data: Vec::from_slice(data).unwrap() })
+}
+
+fn verify(image: &[u8]) -> Body {
+    Body::Verify(Verify { sha256: Vec::from_slice(&Sha256::digest(image)).unwrap() })
+}
+
+/// Send the whole image in order.
+fn send(update: &Update, image: &[u8]) {
+    assert_eq!(call(update, begin(image.len())), reply(Status::OK, 0));
+    for (index, data) in image.chunks(UPDATE_CHUNK).enumerate() {
+        let end = index * UPDATE_CHUNK + data.len();
+        assert_eq!(call(update, chunk(index * UPDATE_CHUNK, data)), reply(Status::OK, end as u32));
+    }
+}
+
+#[test]
+fn update_and_commit() {
+    // Not a whole number of write blocks, so the last one is padded
+    let image = image(2500 + 2);
+    let update = update();
+    send(&update, &image);
+    assert_eq!(call(&update, verify(&image)), reply(Status::OK, image.len() as u32));
+    assert_eq!(call(&update, Body::Commit(Commit {})), reply(Status::OK, image.len() as u32));
+
+    let (flash, swap) = update.into_inner();
+    assert_eq!(swap.swapped.get(), Some(image.len() as u32));
+    let bytes = flash.bytes.borrow();
+    assert_eq!(&bytes[..image.len()], &image[..]);
+    assert_eq!(bytes[image.len()..image.len() + 6], [0xff; 6]);
+    // Only the sectors the image reaches
+    assert!(bytes[3072..].iter().all(|byte| *byte == 0));
+}
+
+#[test]
+fn bad_digest() {
+    let image = image(600);
+    let update = update();
+    send(&update, &image);
+    let mut wrong = image.clone();
+    wrong[300] ^= 1;
+    assert_eq!(call(&update, verify(&wrong)), reply(Status::BAD_DIGEST, 0));
+    assert_eq!(call(&update, Body::Commit(Commit {})), reply(Status::BAD_STATE, 0));
+    assert_eq!(update.into_inner().1.swapped.get(), None);
+}
+
+#[test]
+fn verify_reads_back() {
+    let image = image(600);
+    assert_eq!(image[100] & 1, 0);
+    let mut flash = MemoryFlash::new();
+    flash.stuck = Some(100);
+    let update = FirmwareUpdate::new(flash, Swap::default());
+    send(&update, &image);
+    assert_eq!(call(&update, verify(&image)), reply(Status::BAD_DIGEST, 0));
+}
+
+#[test]
+fn resume_after_a_lost_chunk() {
+    let image = image(1000);
+    let update = update();
+    assert_eq!(call(&update, begin(image.len())), reply(Status::OK, 0));
+    assert_eq!(call(&update, chunk(0, &image[..256])), reply(Status::OK, 256));
+    // The next chunk was lost, so the host sends the one after it
+    assert_eq!(call(&update, chunk(512, &image[512..768])), reply(Status::BAD_OFFSET, 256));
+    // and a resent chunk is refused too, telling the host where to carry on from
+    assert_eq!(call(&update, chunk(0, &image[..256])), reply(Status::BAD_OFFSET, 256));
+
+    for offset in (256..image.len()).step_by(256) {
+        let end = (offset + 256).min(image.len());
+        assert_eq!(call(&update, chunk(offset, &image[offset..end])), reply(Status::OK, end as u32));
+    }
+    assert_eq!(call(&update, verify(&image)), reply(Status::OK, 1000));
+}
+
+#[test]
+fn too_large() {
+    let update = update();
+    assert_eq!(call(&update, begin(MemoryFlash::CAPACITY + 1)), reply(Status::TOO_LARGE, 0));
+    // Rounded up to a whole erase block, it wouldn't fit in a u32
+    assert_eq!(call(&update, Body::Begin(Begin { size: u32::MAX })), reply(Status::TOO_LARGE, 0));
+    assert_eq!(call(&update, chunk(0, &[1, 2, 3, 4])), reply(Status::BAD_STATE, 0));
+
+    assert_eq!(call(&update, begin(6)), reply(Status::OK, 0));
+    assert_eq!(call(&update, chunk(0, &[1, 2, 3, 4, 5, 6, 7, 8])), reply(Status::TOO_LARGE, 0));
+}
+
+#[test]
+fn partial_write_blocks() {
+    let update = update();
+    assert_eq!(call(&update, begin(100)), reply(Status::OK, 0));
+    assert_eq!(call(&update, chunk(0, &[0; 10])), reply(Status::BAD_LENGTH, 0));
+    assert_eq!(call(&update, begin(0)), reply(Status::BAD_LENGTH, 0));
+}
+
+#[test]
+fn verify_before_the_end() {
+    let image = image(600);
+    let update = update();
+    assert_eq!(call(&update, begin(image.len())), reply(Status::OK, 0));
+    assert_eq!(call(&update, chunk(0, &image[..256])), reply(Status::OK, 256));
+    assert_eq!(call(&update, verify(&image)), reply(Status::BAD_STATE, 256));
+}
+
+#[test]
+fn begin_again() {
+    let update = update();
+    send(&update, &image(600));
+    // A second update over the first, which has to erase again
+    let image = image(300).into_iter().map(|byte| !byte).collect::<std::vec::Vec<_>>();
+    send(&update, &image);
+    assert_eq!(call(&update, verify(&image)), reply(Status::OK, 300));
+    assert_eq!(update.into_inner().0.erases.get(), 2);
+}
+
+#[test]
+fn swap_failed() {
+    let image = image(64);
+    let update = FirmwareUpdate::new(MemoryFlash::new(), Swap { fail: true, ..Swap::default() });
+    send(&update, &image);
+    assert_eq!(call(&update, verify(&image)), reply(Status::OK, 64));
+    assert_eq!(call(&update, Body::Commit(Commit {})), reply(Status::SWAP_FAILED, 64));
+}